@@ -0,0 +1,88 @@
+// Old per-cell loop vs the row-parallel pipeline, on a 4096x4096 input with 8px dice
+// (262k cells). Run with `cargo bench`, and `cargo bench --no-default-features`
+// to see the pipeline without rayon.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use dice::dicelib::{map_intensity_to_dice_side, Dice, DiceSides, IntensityPreset, Thresholds};
+use dice::integral::IntegralImage;
+use dice::layout::{Layout, LayoutKind};
+use dice::render::{assign_faces, render_mosaic, TileSet};
+use image::{imageops, GrayImage, RgbaImage};
+
+const INPUT_SIZE: u32 = 4096;
+const DICE_SIZE: u32 = 8;
+
+fn test_input() -> GrayImage {
+    GrayImage::from_fn(INPUT_SIZE, INPUT_SIZE, |x, y| {
+        image::Luma([((x ^ y) % 256) as u8])
+    })
+}
+
+fn test_dice() -> [Dice; 6] {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/dice");
+    core::array::from_fn(|i| {
+        let path = format!("{}/{}side.png", dir, i + 1);
+        let image = image::open(&path)
+            .unwrap_or_else(|_| panic!("Couldn't load {}", path))
+            .resize_exact(DICE_SIZE, DICE_SIZE, imageops::FilterType::Lanczos3);
+        Dice { side: DiceSides::ALL[i], image }
+    })
+}
+
+// What main used to do for every cell: copy the block, sum it, convert the die, overlay.
+fn naive(input: &GrayImage, dice: &[Dice; 6], preset: &IntensityPreset) -> RgbaImage {
+    let nx = input.width() / DICE_SIZE;
+    let ny = input.height() / DICE_SIZE;
+    let mut out = RgbaImage::new(nx * DICE_SIZE, ny * DICE_SIZE);
+    for gy in 0..ny {
+        for gx in 0..nx {
+            let (x, y) = (gx * DICE_SIZE, gy * DICE_SIZE);
+            let block = imageops::crop_imm(input, x, y, DICE_SIZE, DICE_SIZE).to_image();
+            let total: u64 = block.pixels().map(|p| p[0] as u64).sum();
+            let avg = (total / (DICE_SIZE * DICE_SIZE) as u64) as u8;
+            let side = map_intensity_to_dice_side(avg, preset);
+            let die = dice.iter().find(|d| d.side == side).unwrap();
+            imageops::overlay(&mut out, &die.image.to_rgba8(), x as i64, y as i64);
+        }
+    }
+    out
+}
+
+fn bench_render(c: &mut Criterion) {
+    let input = test_input();
+    let dice = test_dice();
+    let preset = IntensityPreset::Default;
+
+    let mut group = c.benchmark_group("render_4096_8px");
+    group.sample_size(10);
+
+    group.bench_function("naive", |b| b.iter(|| naive(&input, &dice, &preset)));
+
+    group.bench_function("pipeline", |b| {
+        b.iter(|| {
+            let layout = Layout::new(LayoutKind::Grid, input.dimensions(), (DICE_SIZE, DICE_SIZE));
+            let tiles = TileSet::new(&dice);
+            let integral = IntegralImage::new(&input);
+            let placements = assign_faces(&integral, &layout.cells(), &Thresholds::from_preset(&preset));
+            render_mosaic(&placements, &tiles, layout.canvas_size())
+        })
+    });
+
+    // Same input, every preset: the table is built once and each pass is just lookups
+    let integral = IntegralImage::new(&input);
+    let cells = Layout::new(LayoutKind::Grid, input.dimensions(), (DICE_SIZE, DICE_SIZE)).cells();
+    let presets = IntensityPreset::ALL.map(|p| Thresholds::from_preset(&p));
+    group.bench_function("assign_all_presets", |b| {
+        b.iter(|| {
+            presets
+                .iter()
+                .map(|p| assign_faces(&integral, &cells, p).len())
+                .sum::<usize>()
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_render);
+criterion_main!(benches);
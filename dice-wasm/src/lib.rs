@@ -1,9 +1,18 @@
 use wasm_bindgen::prelude::*;
 use js_sys::Uint8Array;
 
+use dice::alpha::Alpha;
+use dice::anneal::Anneal;
+use dice::dicelib::{decode_input, split_input, Dice, DiceSides, IntensityPreset as CorePreset};
+use dice::grayscale::Grayscale;
+use dice::inventory::{Inventory, Solver};
+use dice::mask::{Key, Subject};
+use dice::pipeline::{self, Settings};
+use dice::preprocess::Op;
+use dice::rulers::RulerOptions;
 use ab_glyph::{FontVec, PxScale};
-use image::{imageops, DynamicImage, GrayImage, ImageBuffer, Rgba, RgbaImage};
 use image::codecs::png::PngEncoder;
+use image::{imageops, DynamicImage, GrayImage, ImageReader, Rgba, RgbaImage};
 use image::{ColorType, ImageEncoder};
 use imageproc::drawing::{draw_filled_rect_mut, draw_text_mut};
 use imageproc::rect::Rect;
@@ -19,59 +28,15 @@ pub enum IntensityPreset {
     Dark,
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub enum DiceSides {
-    One, Two, Three, Four, Five, Six,
-}
-
-#[derive(Clone)]
-struct Dice {
-    side: DiceSides,
-    image: DynamicImage,
-}
-
-fn map_intensity_to_dice_side(avg: u8, preset: IntensityPreset) -> DiceSides {
-    match preset {
-        IntensityPreset::Default => match avg {
-            0..=50 => DiceSides::One,
-            51..=100 => DiceSides::Two,
-            101..=150 => DiceSides::Three,
-            151..=200 => DiceSides::Four,
-            201..=230 => DiceSides::Five,
-            _ => DiceSides::Six,
-        },
-        IntensityPreset::HighContrast => match avg {
-            0..=42 => DiceSides::One,
-            43..=85 => DiceSides::Two,
-            86..=128 => DiceSides::Three,
-            129..=171 => DiceSides::Four,
-            172..=214 => DiceSides::Five,
-            _ => DiceSides::Six,
-        },
-        IntensityPreset::LowContrast => match avg {
-            0..=60 => DiceSides::One,
-            61..=120 => DiceSides::Two,
-            121..=180 => DiceSides::Three,
-            181..=210 => DiceSides::Four,
-            211..=240 => DiceSides::Five,
-            _ => DiceSides::Six,
-        },
-        IntensityPreset::Bright => match avg {
-            0..=30 => DiceSides::One,
-            31..=80 => DiceSides::Two,
-            81..=130 => DiceSides::Three,
-            131..=180 => DiceSides::Four,
-            181..=220 => DiceSides::Five,
-            _ => DiceSides::Six,
-        },
-        IntensityPreset::Dark => match avg {
-            0..=70 => DiceSides::One,
-            71..=120 => DiceSides::Two,
-            121..=160 => DiceSides::Three,
-            161..=200 => DiceSides::Four,
-            201..=240 => DiceSides::Five,
-            _ => DiceSides::Six,
-        },
+impl From<IntensityPreset> for CorePreset {
+    fn from(preset: IntensityPreset) -> CorePreset {
+        match preset {
+            IntensityPreset::Default => CorePreset::Default,
+            IntensityPreset::HighContrast => CorePreset::HighContrast,
+            IntensityPreset::LowContrast => CorePreset::LowContrast,
+            IntensityPreset::Bright => CorePreset::Bright,
+            IntensityPreset::Dark => CorePreset::Dark,
+        }
     }
 }
 
@@ -99,9 +64,10 @@ fn add_reference_text(
     draw_text_mut(image, Rgba([255, 255, 255, 255]), 6, 4, scale, &font, &text);
 }
 
-fn load_from_bytes_gray(bytes: &[u8]) -> Result<GrayImage, String> {
-    let img = image::load_from_memory(bytes).map_err(|e| e.to_string())?;
-    Ok(img.into_luma8())
+fn load_from_bytes_gray(bytes: &[u8], settings: &Settings) -> Result<(GrayImage, Option<GrayImage>), String> {
+    let reader = ImageReader::new(Cursor::new(bytes)).with_guessed_format().map_err(|e| e.to_string())?;
+    let img = decode_input(reader, settings.auto_orient)?;
+    split_input(&img, settings)
 }
 fn load_from_bytes_rgba(bytes: &[u8]) -> Result<DynamicImage, String> {
     let img = image::load_from_memory(bytes).map_err(|e| e.to_string())?;
@@ -117,6 +83,26 @@ pub struct DiceOptions {
     pub output_width: Option<u32>,
     pub output_height: Option<u32>,
     pub add_debug: bool,
+    /// Row/column numbers in a margin around the mosaic. Off by default; set from JS.
+    pub rulers: bool,
+    /// Section line every this many dice when `rulers` is on, 0 for none.
+    pub ruler_every: u32,
+    /// Average cells in linear light instead of on the encoded values.
+    pub linear_light: bool,
+    /// Turn the input upright per its EXIF orientation. On by default.
+    pub auto_orient: bool,
+    /// Tone steps for the input, set with `set_preprocess`.
+    preprocess: Vec<Op>,
+    /// Color to gray conversion, set with `set_grayscale`.
+    grayscale: Grayscale,
+    /// What transparent parts of the input become, set with `set_alpha`.
+    alpha: Alpha,
+    /// Dice on the subject only, set with `set_mask`.
+    mask: Option<Subject>,
+    /// Dice on hand, set with `set_inventory`.
+    inventory: Option<Inventory>,
+    /// Whole-mosaic refinement, set with `set_anneal`.
+    anneal: Option<Anneal>,
 }
 
 #[wasm_bindgen]
@@ -139,6 +125,118 @@ impl DiceOptions {
             output_width,
             output_height,
             add_debug,
+            rulers: false,
+            ruler_every: RulerOptions::default().every,
+            linear_light: false,
+            auto_orient: true,
+            preprocess: Vec::new(),
+            grayscale: Grayscale::default(),
+            alpha: Alpha::default(),
+            mask: None,
+            inventory: None,
+            anneal: None,
+        }
+    }
+
+    /// Refines the faces by simulated annealing, like the CLI's `--anneal`: `iterations`
+    /// moves (500 per die when unset), `seed` for repeatable results and `blur` for the
+    /// viewing distance in dice (1 is close). There's no time budget here, so keep the
+    /// moves in check for big mosaics. `on = false` turns it off.
+    pub fn set_anneal(&mut self, on: bool, iterations: Option<u32>, seed: u32, blur: f32) -> Result<(), JsValue> {
+        let anneal = Anneal {
+            iterations: iterations.map(u64::from),
+            seed: seed as u64,
+            seconds: None,
+            blur,
+        };
+        if on {
+            anneal.check().map_err(|e| JsValue::from(js_sys::Error::new(&e)))?;
+        }
+        self.anneal = on.then_some(anneal);
+        Ok(())
+    }
+
+    /// Dice on hand, like the CLI's `--inventory`: a total or six face counts
+    /// ("500,480,520,500,500,500"), met by `solver` "exact" or "diffuse". An empty
+    /// count lifts the limit. Renders fail when there aren't enough dice.
+    pub fn set_inventory(&mut self, counts: &str, solver: &str) -> Result<(), JsValue> {
+        let error = |e: &str| JsValue::from(js_sys::Error::new(e));
+        if counts.trim().is_empty() {
+            self.inventory = None;
+            return Ok(());
+        }
+        let solver = Solver::parse(solver).ok_or_else(|| error("The solver is exact or diffuse"))?;
+        self.inventory = Some(Inventory {
+            solver,
+            ..Inventory::parse(counts).map_err(|e| error(&e))?
+        });
+        Ok(())
+    }
+
+    /// Keys the backdrop out, like the CLI's `--mask`: "luma=VALUE[,TOL]",
+    /// "chroma=#RRGGBB[,TOL]" or "flood[=TOL]". Backdrop cells are left empty, or get
+    /// `fill` (1-6) pips. An empty key turns the mask off.
+    pub fn set_mask(&mut self, key: &str, fill: Option<u8>) -> Result<(), JsValue> {
+        let error = |e: &str| JsValue::from(js_sys::Error::new(e));
+        if key.trim().is_empty() {
+            self.mask = None;
+            return Ok(());
+        }
+        let key = Key::parse(key).map_err(|e| error(&e))?;
+        if let Key::Image { .. } = key {
+            return Err(error("Mask images aren't supported here"));
+        }
+        if fill.is_some_and(|f| !(1..=6).contains(&f)) {
+            return Err(error("The fill face goes from 1 to 6"));
+        }
+        self.mask = Some(Subject { fill, ..Subject::new(key) });
+        Ok(())
+    }
+
+    /// Same as the CLI's `--alpha`: "ignore" (default), "composite" or "composite=#RRGGBB"
+    /// onto a background, "empty" or "empty=0.5" to leave mostly transparent cells without
+    /// a die (transparent in the PNG).
+    pub fn set_alpha(&mut self, mode: &str) -> Result<(), JsValue> {
+        self.alpha = Alpha::parse(mode).map_err(|e| JsValue::from(js_sys::Error::new(&e)))?;
+        Ok(())
+    }
+
+    /// How a color input becomes gray, same names as the CLI's `--grayscale`: "rec709"
+    /// (default), "rec601", "linear", "lab", "red", "green", "blue" or "R,G,B" weights.
+    pub fn set_grayscale(&mut self, mode: &str) -> Result<(), JsValue> {
+        self.grayscale = Grayscale::parse(mode).map_err(|e| JsValue::from(js_sys::Error::new(&e)))?;
+        Ok(())
+    }
+
+    /// Tone steps run on the input in order, same syntax as the CLI's `--preprocess`,
+    /// e.g. "levels=20,235; gamma=1.2; curve=0:0,96:70,255:255". Empty clears them.
+    pub fn set_preprocess(&mut self, steps: &str) -> Result<(), JsValue> {
+        self.preprocess = Op::parse_list(steps).map_err(|e| JsValue::from(js_sys::Error::new(&e)))?;
+        Ok(())
+    }
+}
+
+impl DiceOptions {
+    fn settings(&self) -> Settings {
+        Settings {
+            dice_size: self.dice_size,
+            preset: self.preset.into(),
+            invert_dice: self.invert_dice,
+            // The banner is drawn here, in this build's own style
+            add_debug: false,
+            linear_light: self.linear_light,
+            auto_orient: self.auto_orient,
+            grayscale: self.grayscale,
+            alpha: self.alpha,
+            mask: self.mask.clone(),
+            inventory: self.inventory.clone(),
+            anneal: self.anneal.clone(),
+            preprocess: self.preprocess.clone(),
+            rulers: self.rulers.then(|| RulerOptions {
+                every: self.ruler_every,
+                ..RulerOptions::default()
+            }),
+            ..Settings::default()
         }
     }
 }
@@ -152,11 +250,12 @@ pub fn process_dice_image(
     opts: DiceOptions,
 ) -> Result<Uint8Array, JsValue> {
     console_error_panic_hook::set_once();
+    let settings = opts.settings();
 
     // 1) Input
     let mut input_vec = vec![0u8; input_bytes.length() as usize];
     input_bytes.copy_to(&mut input_vec[..]);
-    let mut input = load_from_bytes_gray(&input_vec)
+    let (mut input, mut alpha) = load_from_bytes_gray(&input_vec, &settings)
         .map_err(|e| JsValue::from(js_sys::Error::new(&e)))?;
     if opts.invert_input {
         imageops::invert(&mut input);
@@ -167,88 +266,37 @@ pub fn process_dice_image(
         return Err(js_sys::Error::new("dice_pngs must contain exactly 6 images").into());
     }
     let mut dice_vec: Vec<Dice> = Vec::with_capacity(6);
-    for (i, val) in dice_pngs.iter().enumerate() {
+    for (val, side) in dice_pngs.iter().zip(DiceSides::ALL) {
         let u8arr = js_sys::Uint8Array::new(&val);
         let mut buf = vec![0u8; u8arr.length() as usize];
         u8arr.copy_to(&mut buf[..]);
 
-        let mut img = load_from_bytes_rgba(&buf)
+        let img = load_from_bytes_rgba(&buf)
             .map_err(|e| JsValue::from(js_sys::Error::new(&e)))?;
-        if opts.invert_dice { img.invert(); }
-
         let resized = img.resize_exact(opts.dice_size, opts.dice_size, imageops::FilterType::Lanczos3);
-        let side = match i {
-            0 => DiceSides::One,
-            1 => DiceSides::Two,
-            2 => DiceSides::Three,
-            3 => DiceSides::Four,
-            4 => DiceSides::Five,
-            5 => DiceSides::Six,
-            _ => unreachable!(),
-        };
         dice_vec.push(Dice { side, image: resized });
     }
+    let dice: [Dice; 6] = dice_vec.try_into().expect("checked for 6 above");
+    let tiles = pipeline::tile_set(dice, &settings);
 
-    // 3) Optional output canvas sizing (center-fit)
-    let (out_w, out_h) = match (opts.output_width, opts.output_height) {
-        (Some(w), Some(h)) if w > 0 && h > 0 => (w, h),
-        _ => input.dimensions(),
-    };
-    let (in_w, in_h) = input.dimensions();
-    if (out_w, out_h) != (in_w, in_h) {
-        let aspect = in_w as f32 / in_h as f32;
-        let (nw, nh) = if (out_w as f32 / out_h as f32) > aspect {
-            (((out_h as f32) * aspect).round() as u32, out_h)
-        } else {
-            (out_w, ((out_w as f32) / aspect).round() as u32)
-        };
-        let mut canvas = GrayImage::new(out_w, out_h);
-        let scaled = imageops::resize(&input, nw, nh, imageops::FilterType::Lanczos3);
-        let off_x = ((out_w - nw) / 2) as i64;
-        let off_y = ((out_h - nh) / 2) as i64;
-        imageops::overlay(&mut canvas, &scaled, off_x, off_y);
-        input = canvas;
-    }
-
-    // 4) Crop square
-    let (w, h) = input.dimensions();
-    let sq = w.min(h);
-    let input_sq = imageops::crop_imm(&input, 0, 0, sq, sq).to_image();
-
-    // 5) Grid → dice render
-    let dw = opts.dice_size;
-    let dh = opts.dice_size;
-    let num_x = sq / dw;
-    let num_y = sq / dh;
-
-    let mut out: RgbaImage = ImageBuffer::new(num_x * dw, num_y * dh);
-
-    for gy in 0..num_y {
-        for gx in 0..num_x {
-            let sx = gx * dw;
-            let sy = gy * dh;
-            let block = imageops::crop_imm(&input_sq, sx, sy, dw, dh).to_image();
-
-            let mut total: u64 = 0;
-            let count = (dw * dh) as u64;
-            for p in block.pixels() { total += p[0] as u64; }
-            let avg = if count > 0 { (total / count) as u8 } else { 0 };
-
-            let target = map_intensity_to_dice_side(avg, opts.preset);
-            let dice = dice_vec.iter().find(|d| d.side == target).expect("missing dice face");
-            let dice_rgba = dice.image.to_rgba8();
-            imageops::overlay(&mut out, &dice_rgba, sx as i64, sy as i64);
+    // 3) Optional output canvas sizing (center-fit), before the square crop
+    if let (Some(w), Some(h)) = (opts.output_width, opts.output_height) {
+        if w > 0 && h > 0 && (w, h) != input.dimensions() {
+            input = pipeline::fit_into(&input, w, h);
+            alpha = alpha.map(|a| pipeline::fit_into(&a, w, h));
         }
     }
 
+    // 4) Crop square, grid → dice render and rulers
+    let (mosaic, mut out) = pipeline::run(input, alpha, &tiles, &settings, None)
+        .map_err(|e| JsValue::from(js_sys::Error::new(&e)))?;
+
     if opts.add_debug {
-        let total_dice = num_x * num_y;
-        // Avoid simultaneous mutable & immutable borrows:
         let dims = out.dimensions();
-        add_reference_text(&mut out, (dw, dh), total_dice, dims);
+        add_reference_text(&mut out, (opts.dice_size, opts.dice_size), mosaic.total_dice(), dims);
     }
 
-    // 6) Encode RGBA to PNG
+    // 5) Encode RGBA to PNG
     let width = out.width();
     let height = out.height();
     let mut bytes = Vec::<u8>::new();
@@ -0,0 +1,166 @@
+// What to do with a transparent input. Converting to gray drops alpha, so whatever
+// color sits under the transparent pixels (often black) decides those dice. Either
+// flatten the image onto a background first, or leave mostly transparent cells
+// without a die, which makes a logo into a cut-out dice shape.
+
+use image::{DynamicImage, GrayImage, ImageBuffer, Rgb};
+use serde::{Deserialize, Serialize};
+
+use crate::grayscale::Grayscale;
+use crate::palette::Color;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Alpha {
+    /// Alpha is dropped and the colors under it are used as they are.
+    #[default]
+    Ignore,
+    /// Flattened onto `background` before going gray.
+    Composite {
+        #[serde(default = "white")]
+        background: Color,
+    },
+    /// Cells less than `coverage` (0 to 1) opaque get no die: transparent in the
+    /// image outputs, blank in the text ones, and left out of the bill of materials.
+    Empty {
+        #[serde(default = "half")]
+        coverage: f32,
+    },
+}
+
+fn white() -> Color {
+    Color([255, 255, 255])
+}
+
+fn half() -> f32 {
+    0.5
+}
+
+impl Alpha {
+    /// `ignore`, `composite[=COLOR]` (white by default) or `empty[=COVERAGE]` (0.5).
+    pub fn parse(s: &str) -> Result<Alpha, String> {
+        let (mode, value) = s.trim().split_once('=').map(|(m, v)| (m, Some(v.trim()))).unwrap_or((s.trim(), None));
+        match (mode.to_ascii_lowercase().as_str(), value) {
+            ("ignore", None) => Ok(Alpha::Ignore),
+            ("composite", None) => Ok(Alpha::Composite { background: white() }),
+            ("composite", Some(color)) => Ok(Alpha::Composite {
+                background: Color::parse(color)?,
+            }),
+            ("empty", None) => Ok(Alpha::Empty { coverage: half() }),
+            ("empty", Some(coverage)) => match coverage.parse::<f32>() {
+                Ok(coverage) => {
+                    let alpha = Alpha::Empty { coverage };
+                    alpha.check()?;
+                    Ok(alpha)
+                }
+                _ => Err(format!("Coverage goes from 0 to 1, got {:?}", coverage)),
+            },
+            _ => Err(format!("Unknown alpha mode {:?}, expected ignore, composite[=COLOR] or empty[=COVERAGE]", s)),
+        }
+    }
+
+    /// A coverage outside 0 to 1 would keep every cell or none, e.g. from a job file.
+    pub fn check(&self) -> Result<(), String> {
+        match self.coverage() {
+            Some(coverage) if !(0.0..=1.0).contains(&coverage) => Err(format!("Coverage goes from 0 to 1, got {}", coverage)),
+            _ => Ok(()),
+        }
+    }
+
+    /// The input's luma, plus its alpha channel when cells are cut out by it.
+    /// Images without alpha come back without a mask in every mode.
+    pub fn split(&self, image: &DynamicImage, grayscale: Grayscale) -> (GrayImage, Option<GrayImage>) {
+        if !image.color().has_alpha() {
+            return (grayscale.convert(image), None);
+        }
+        match *self {
+            Alpha::Ignore => (grayscale.convert(image), None),
+            Alpha::Empty { .. } => {
+                let alpha = GrayImage::from_raw(image.width(), image.height(), image.to_luma_alpha8().pixels().map(|p| p.0[1]).collect())
+                    .expect("one alpha value per pixel");
+                (grayscale.convert(image), Some(alpha))
+            }
+            Alpha::Composite { background } => {
+                // Blended at 16 bits so the edges keep their gradient
+                let rgba = image.to_rgba16();
+                let bg = background.0.map(|c| c as u32 * 257);
+                let flat = ImageBuffer::from_fn(rgba.width(), rgba.height(), |x, y| {
+                    let [r, g, b, a] = rgba.get_pixel(x, y).0.map(u32::from);
+                    let mix = |c: u32, bg: u32| ((c * a + bg * (65535 - a)) / 65535) as u16;
+                    Rgb([mix(r, bg[0]), mix(g, bg[1]), mix(b, bg[2])])
+                });
+                (grayscale.convert(&DynamicImage::ImageRgb16(flat)), None)
+            }
+        }
+    }
+
+    /// Smallest share of opaque pixels a cell needs to get a die, if cells are cut out.
+    pub fn coverage(&self) -> Option<f32> {
+        match self {
+            Alpha::Empty { coverage } => Some(*coverage),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_wants_coverage_from_0_to_1() {
+        assert!(Alpha::Empty { coverage: 1.0 }.check().is_ok());
+        assert!(Alpha::Empty { coverage: 1.5 }.check().is_err());
+        assert!(Alpha::Empty { coverage: -0.1 }.check().is_err());
+        assert!(Alpha::Empty { coverage: f32::NAN }.check().is_err());
+        assert!(Alpha::parse("empty=NaN").is_err());
+    }
+
+    #[test]
+    fn parse_reads_every_mode() {
+        assert_eq!(Alpha::parse("ignore").unwrap(), Alpha::Ignore);
+        assert_eq!(Alpha::parse("composite").unwrap(), Alpha::Composite { background: white() });
+        assert_eq!(
+            Alpha::parse(" Composite=#f80 ").unwrap(),
+            Alpha::Composite {
+                background: Color([255, 136, 0])
+            }
+        );
+        assert_eq!(Alpha::parse("empty").unwrap(), Alpha::Empty { coverage: 0.5 });
+        assert_eq!(Alpha::parse("empty=0.25").unwrap(), Alpha::Empty { coverage: 0.25 });
+        assert!(Alpha::parse("empty=half").is_err());
+        assert!(Alpha::parse("ignore=1").is_err());
+        assert!(Alpha::parse("composite=#zzz").is_err());
+        assert!(Alpha::parse("cutout").is_err());
+    }
+
+    /// One opaque black pixel and one fully transparent black one.
+    fn half_transparent() -> DynamicImage {
+        DynamicImage::ImageRgba8(image::RgbaImage::from_fn(2, 1, |x, _| image::Rgba([0, 0, 0, if x == 0 { 255 } else { 0 }])))
+    }
+
+    #[test]
+    fn composite_flattens_onto_the_background() {
+        let (luma, alpha) = Alpha::parse("composite").unwrap().split(&half_transparent(), Grayscale::Rec709);
+        assert_eq!(luma.as_raw(), &[0, 255]);
+        assert!(alpha.is_none());
+        let (luma, _) = Alpha::parse("composite=#000").unwrap().split(&half_transparent(), Grayscale::Rec709);
+        assert_eq!(luma.as_raw(), &[0, 0]);
+    }
+
+    #[test]
+    fn empty_keeps_the_alpha_as_the_mask() {
+        let (luma, alpha) = Alpha::parse("empty").unwrap().split(&half_transparent(), Grayscale::Rec709);
+        assert_eq!(luma.as_raw(), &[0, 0]);
+        assert_eq!(alpha.unwrap().as_raw(), &[255, 0]);
+    }
+
+    #[test]
+    fn ignore_and_opaque_inputs_have_no_mask() {
+        let (luma, alpha) = Alpha::Ignore.split(&half_transparent(), Grayscale::Rec709);
+        assert_eq!(luma.as_raw(), &[0, 0]);
+        assert!(alpha.is_none());
+        let opaque = DynamicImage::ImageRgb8(image::RgbImage::new(2, 1));
+        assert!(Alpha::parse("empty").unwrap().split(&opaque, Grayscale::Rec709).1.is_none());
+    }
+}
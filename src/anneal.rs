@@ -0,0 +1,383 @@
+// Refining the whole mosaic at once. Thresholding (and dithering) decide each die from
+// its own cell, but from across the room neighbouring dice blur together. This starts
+// from those faces and anneals: try changing a die, keep the change if the blurred
+// mosaic gets closer to the blurred source, and early on sometimes keep it anyway so it
+// can get out of local minima.
+//
+// The model works on the cell grid: every die is the tone its face stands for, every
+// cell of the source is its level, and the eye is a Gaussian `blur` (in dice) over both.
+// The error is linear in the faces, so the blurred difference is kept up to date and a
+// move only costs a look at the dice within the blur's reach.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::dicelib::{DiceSides, Thresholds};
+use crate::layout::Cell;
+use crate::render::Placement;
+
+/// The `anneal` setting.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Anneal {
+    /// Moves to try. Unset is 500 per die.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iterations: Option<u64>,
+    /// Same seed, same mosaic.
+    pub seed: u64,
+    /// Stop after this many seconds even if there are iterations left.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seconds: Option<f64>,
+    /// Standard deviation of the viewing blur in (smallest) dice. Bigger is farther away.
+    pub blur: f32,
+}
+
+impl Default for Anneal {
+    fn default() -> Anneal {
+        Anneal {
+            iterations: None,
+            seed: 0,
+            seconds: None,
+            blur: 1.0,
+        }
+    }
+}
+
+/// Widest viewing blur, in dice. The footprint of every die grows with its square.
+const MAX_BLUR: f32 = 100.0;
+
+/// Longest time budget, a day. Longer runs can count iterations instead.
+const MAX_SECONDS: f64 = 86_400.0;
+
+impl Anneal {
+    /// Catches settings `refine` can't work with, e.g. from a job file.
+    pub fn check(&self) -> Result<(), String> {
+        if !(self.blur > 0.0 && self.blur <= MAX_BLUR) {
+            return Err(format!("The annealing blur goes from above 0 to {} dice, got {}", MAX_BLUR, self.blur));
+        }
+        if let Some(seconds) = self.seconds
+            && !(0.0..=MAX_SECONDS).contains(&seconds)
+        {
+            return Err(format!("The annealing time budget goes from 0 to {} seconds, got {}", MAX_SECONDS, seconds));
+        }
+        Ok(())
+    }
+
+    /// Refines `placements` (one per cell, in the same order as `levels`) in place. With
+    /// `keep_counts` dice only swap faces, so per-face counts stay as they are.
+    pub fn refine(&self, placements: &mut [Placement], levels: &[f32], thresholds: &Thresholds, keep_counts: bool) {
+        let n = placements.len();
+        if n < 2 || self.blur <= 0.0 {
+            return;
+        }
+        let tone = |side: DiceSides| thresholds.level(side) as f32;
+        let mut model = Model::new(placements, levels, tone, self.blur);
+        let mut error = model.error();
+
+        let mut rng = XorShift::new(self.seed);
+        let iterations = self.iterations.unwrap_or(500 * n as u64);
+        // A budget too big for a Duration is no budget at all
+        let budget = self
+            .seconds
+            .and_then(|s| Duration::try_from_secs_f64(s.max(0.0)).ok())
+            .map(|limit| (Instant::now(), limit));
+
+        // A move: two (cell, new face) pairs, the same one twice for a step
+        let propose = |rng: &mut XorShift, sides: &[usize]| -> Option<[(usize, usize); 2]> {
+            let a = rng.below(n);
+            if keep_counts {
+                let b = rng.below(n);
+                (sides[a] != sides[b]).then_some([(a, sides[b]), (b, sides[a])])
+            } else {
+                let step = if rng.below(4) == 0 { 2 } else { 1 };
+                let face = match rng.below(2) {
+                    0 => sides[a].checked_sub(step)?,
+                    _ => Some(sides[a] + step).filter(|&f| f < 6)?,
+                };
+                Some([(a, face), (a, face)])
+            }
+        };
+
+        let mut sides: Vec<usize> = placements.iter().map(|p| p.side.index()).collect();
+        // Best seen so far, looked at now and then since copying it isn't free
+        let (mut best, mut best_error) = (sides.clone(), error);
+        let check_every = n as u64;
+        // Starting temperature, a fraction of the average uphill move. Most tries are
+        // wild, and a start from a good (dithered) mosaic is easily undone when it's hotter.
+        let mut uphill = Vec::new();
+        for _ in 0..200 {
+            if let Some(moves) = propose(&mut rng, &sides) {
+                let delta = model.try_moves(&moves, &sides);
+                model.undo();
+                if delta > 0.0 {
+                    uphill.push(delta);
+                }
+            }
+        }
+        if uphill.is_empty() {
+            return;
+        }
+        let hot = uphill.iter().sum::<f64>() / uphill.len() as f64 * 0.03;
+        let cold = hot * 1e-4;
+
+        for i in 0..iterations {
+            if i % check_every == 0 && error < best_error {
+                best.copy_from_slice(&sides);
+                best_error = error;
+            }
+            let mut progress = i as f64 / iterations as f64;
+            if let Some((began, limit)) = budget {
+                let elapsed = began.elapsed();
+                if elapsed >= limit {
+                    break;
+                }
+                progress = progress.max(elapsed.as_secs_f64() / limit.as_secs_f64().max(1e-9));
+            }
+            let temperature = hot * (cold / hot).powf(progress);
+            let Some(moves) = propose(&mut rng, &sides) else {
+                continue;
+            };
+            let delta = model.try_moves(&moves, &sides);
+            if delta <= 0.0 || rng.unit() < (-delta / temperature).exp() {
+                for (cell, face) in moves {
+                    sides[cell] = face;
+                }
+                error += delta;
+                model.keep();
+            } else {
+                model.undo();
+            }
+        }
+
+        if error < best_error {
+            best = sides;
+        }
+        for (p, &face) in placements.iter_mut().zip(&best) {
+            p.side = DiceSides::ALL[face];
+        }
+    }
+}
+
+/// The blurred difference between the mosaic and the source, on a grid of the smallest
+/// die. Big quadtree dice cover several grid spots.
+struct Model {
+    width: usize,
+    height: usize,
+    /// Blurred (mosaic - source), one value per grid spot.
+    blurred: Vec<f64>,
+    /// Each cell's spot on the grid and its footprint's key.
+    spots: Vec<(usize, usize, (usize, usize))>,
+    /// The blur of a die covering w x h spots, `radius` spots of spill all round.
+    footprints: HashMap<(usize, usize), Vec<f64>>,
+    radius: usize,
+    /// Tone for every face.
+    tones: [f64; 6],
+    /// Changes made by the last `try_moves`, to take back.
+    pending: Vec<(usize, f64)>,
+}
+
+impl Model {
+    fn new(placements: &[Placement], levels: &[f32], tone: impl Fn(DiceSides) -> f32, blur: f32) -> Model {
+        let cells: Vec<Cell> = placements.iter().map(|p| p.cell).collect();
+        let unit = cells.iter().map(|c| c.w).min().unwrap_or(1).max(1);
+        let spots: Vec<(usize, usize, (usize, usize))> = cells
+            .iter()
+            .map(|c| {
+                let size = ((c.w / unit).max(1) as usize, (c.h / unit).max(1) as usize);
+                (c.col as usize, c.row as usize, size)
+            })
+            .collect();
+        let width = spots.iter().map(|&(x, _, (w, _))| x + w).max().unwrap_or(0);
+        let height = spots.iter().map(|&(_, y, (_, h))| y + h).max().unwrap_or(0);
+
+        let radius = (blur * 3.0).ceil().max(1.0) as usize;
+        let kernel: Vec<f64> = (0..=2 * radius)
+            .map(|i| {
+                let d = i as f64 - radius as f64;
+                (-d * d / (2.0 * blur as f64 * blur as f64)).exp()
+            })
+            .collect();
+        let sum: f64 = kernel.iter().sum();
+        let kernel: Vec<f64> = kernel.iter().map(|k| k / sum).collect();
+
+        let mut footprints = HashMap::new();
+        for &(_, _, (w, h)) in &spots {
+            footprints.entry((w, h)).or_insert_with(|| {
+                // Separable: a box blurred along x times a box blurred along y
+                let spread = |len: usize| -> Vec<f64> {
+                    (0..len + 2 * radius)
+                        .map(|i| (0..len).filter_map(|s| kernel.get(i.wrapping_sub(s))).sum())
+                        .collect()
+                };
+                let (xs, ys) = (spread(w), spread(h));
+                ys.iter().flat_map(|y| xs.iter().map(move |x| x * y)).collect()
+            });
+        }
+
+        let tones = DiceSides::ALL.map(|s| tone(s) as f64);
+        let mut model = Model {
+            width,
+            height,
+            blurred: vec![0.0; width * height],
+            spots,
+            footprints,
+            radius,
+            tones,
+            pending: Vec::new(),
+        };
+        for (i, (p, &level)) in placements.iter().zip(levels).enumerate() {
+            model.add(i, model.tones[p.side.index()] - level.clamp(0.0, 255.0) as f64);
+        }
+        model.pending.clear();
+        model
+    }
+
+    fn error(&self) -> f64 {
+        self.blurred.iter().map(|b| b * b).sum()
+    }
+
+    /// Adds `amount` times cell `i`'s footprint, noting what changed. Returns the change
+    /// in error.
+    fn add(&mut self, i: usize, amount: f64) -> f64 {
+        let (x, y, size) = self.spots[i];
+        let footprint = &self.footprints[&size];
+        let fw = size.0 + 2 * self.radius;
+        let mut delta = 0.0;
+        for (r, row) in footprint.chunks(fw).enumerate() {
+            let Some(gy) = (y + r).checked_sub(self.radius).filter(|&gy| gy < self.height) else {
+                continue;
+            };
+            for (c, &k) in row.iter().enumerate() {
+                let Some(gx) = (x + c).checked_sub(self.radius).filter(|&gx| gx < self.width) else {
+                    continue;
+                };
+                let at = gy * self.width + gx;
+                let change = amount * k;
+                let old = self.blurred[at];
+                delta += change * (2.0 * old + change);
+                self.blurred[at] = old + change;
+                self.pending.push((at, change));
+            }
+        }
+        delta
+    }
+
+    /// Applies `moves` and returns the change in error. `keep()` or `undo()` after.
+    fn try_moves(&mut self, moves: &[(usize, usize); 2], sides: &[usize]) -> f64 {
+        let swap = moves[0].0 != moves[1].0;
+        let mut delta = 0.0;
+        for &(cell, face) in &moves[..if swap { 2 } else { 1 }] {
+            delta += self.add(cell, self.tones[face] - self.tones[sides[cell]]);
+        }
+        delta
+    }
+
+    fn keep(&mut self) {
+        self.pending.clear();
+    }
+
+    fn undo(&mut self) {
+        for (at, change) in self.pending.drain(..).rev() {
+            self.blurred[at] -= change;
+        }
+    }
+}
+
+/// Small, fast and seedable. The quality is plenty for picking moves.
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> XorShift {
+        // splitmix64 so nearby seeds (and 0) start far apart
+        let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        XorShift((z ^ (z >> 31)).max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    /// Uniform in [0, 1).
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dither::cell_levels;
+    use crate::integral::IntegralImage;
+    use crate::pipeline::{plan, Settings};
+    use image::GrayImage;
+
+    fn faces(seed: u64, keep_counts: bool) -> (Vec<DiceSides>, Vec<DiceSides>) {
+        let input = GrayImage::from_fn(96, 64, |x, y| image::Luma([((x * 7 + y * 13 + x * y) % 256) as u8]));
+        let settings = Settings {
+            dice_size: 4,
+            ..Settings::default()
+        };
+        let mut placements = plan(&input, &settings).placements;
+        let before: Vec<DiceSides> = placements.iter().map(|p| p.side).collect();
+        let cells: Vec<_> = placements.iter().map(|p| p.cell).collect();
+        let levels = cell_levels(&IntegralImage::new(&input), &cells);
+        let anneal = Anneal {
+            iterations: Some(20_000),
+            seed,
+            seconds: None,
+            blur: 1.0,
+        };
+        anneal.refine(&mut placements, &levels, &settings.thresholds(), keep_counts);
+        (before, placements.iter().map(|p| p.side).collect())
+    }
+
+    fn counts(sides: &[DiceSides]) -> [usize; 6] {
+        let mut counts = [0; 6];
+        for side in sides {
+            counts[side.index()] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_mosaic() {
+        let (before, first) = faces(7, false);
+        assert_ne!(first, before, "annealing should move some faces");
+        assert_eq!(faces(7, false).1, first);
+        assert_ne!(faces(8, false).1, first);
+    }
+
+    #[test]
+    fn keeping_counts_only_swaps_faces() {
+        let (before, after) = faces(7, true);
+        assert_ne!(after, before);
+        assert_eq!(counts(&after), counts(&before));
+    }
+
+    #[test]
+    fn check_rejects_unusable_budgets_and_blurs() {
+        assert!(Anneal::default().check().is_ok());
+        for anneal in [
+            Anneal { blur: 0.0, ..Anneal::default() },
+            Anneal { blur: f32::NAN, ..Anneal::default() },
+            Anneal { seconds: Some(-1.0), ..Anneal::default() },
+            Anneal { seconds: Some(f64::NAN), ..Anneal::default() },
+            Anneal { seconds: Some(1e30), ..Anneal::default() },
+        ] {
+            assert!(anneal.check().is_err(), "{:?}", anneal);
+        }
+    }
+}
@@ -0,0 +1,225 @@
+// Batch mode: many inputs, one tile set. Inputs can be files, directories (every image
+// directly inside) or glob patterns. Jobs run on a handful of worker threads that all
+// share the same loaded dice, and failures are collected instead of aborting the run.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::dicelib::try_load_input;
+use crate::export::OutputFormat;
+use crate::pipeline::{prepare_alpha, prepare_input, run, Settings};
+use crate::render::TileSet;
+use crate::text::{write_html, write_text, TextArt};
+
+/// Extensions picked up when a directory is given as input.
+pub const IMAGE_EXTENSIONS: [&str; 13] = [
+    "png", "jpg", "jpeg", "gif", "bmp", "tif", "tiff", "webp", "ico", "pgm", "ppm", "pnm", "tga",
+];
+
+pub const DEFAULT_TEMPLATE: &str = "output/{stem}_dice.png";
+
+pub struct BatchOptions {
+    /// Worker threads.
+    pub jobs: usize,
+    /// Keep going after a failure instead of stopping new jobs.
+    pub continue_on_error: bool,
+}
+
+pub struct BatchResult {
+    pub input: PathBuf,
+    pub output: PathBuf,
+    pub elapsed: Duration,
+    /// Number of dice on success, the reason on failure.
+    pub outcome: Result<u32, String>,
+}
+
+/// Turns files, directories and glob patterns into a sorted, de-duplicated list of images.
+pub fn expand_inputs(patterns: &[String]) -> Result<Vec<PathBuf>, String> {
+    let mut inputs = Vec::new();
+    for pattern in patterns {
+        let path = Path::new(pattern);
+        if path.is_dir() {
+            let mut found: Vec<PathBuf> = std::fs::read_dir(path)
+                .map_err(|e| format!("Couldn't read directory {:?}: {}", path, e))?
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|p| p.is_file() && is_image(p))
+                .collect();
+            found.sort();
+            inputs.extend(found);
+        } else if path.is_file() {
+            inputs.push(path.to_path_buf());
+        } else {
+            let matches = glob::glob(pattern).map_err(|e| format!("Bad pattern {:?}: {}", pattern, e))?;
+            let found: Vec<PathBuf> = matches.filter_map(|m| m.ok()).filter(|p| p.is_file()).collect();
+            if found.is_empty() {
+                return Err(format!("Nothing matches {:?}", pattern));
+            }
+            inputs.extend(found);
+        }
+    }
+
+    let mut seen = std::collections::HashSet::new();
+    inputs.retain(|p| seen.insert(p.clone()));
+    Ok(inputs)
+}
+
+fn is_image(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| IMAGE_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+/// Fills in the output template for one input. Placeholders:
+/// `{stem}` file name without extension, `{name}` full file name, `{ext}` extension,
+/// `{dir}` the input's directory, `{index}` position in the batch (1-based),
+/// `{size}` dice size, `{preset}` and `{layout}`.
+pub fn output_path(template: &str, input: &Path, index: usize, settings: &Settings) -> PathBuf {
+    let text = |s: Option<&std::ffi::OsStr>| s.map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    let dir = input
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .map(|p| p.to_string_lossy().into_owned())
+        .unwrap_or_else(|| ".".to_string());
+    let layout = if settings.quadtree.is_some() { "quadtree" } else { settings.layout.name() };
+
+    let filled = template
+        .replace("{stem}", &text(input.file_stem()))
+        .replace("{name}", &text(input.file_name()))
+        .replace("{ext}", &text(input.extension()))
+        .replace("{dir}", &dir)
+        .replace("{index}", &index.to_string())
+        .replace("{size}", &settings.tile_size().to_string())
+        .replace("{preset}", settings.preset.name())
+        .replace("{layout}", layout);
+    PathBuf::from(filled)
+}
+
+/// The output for every input, worked out before anything renders. Two inputs landing
+/// on the same file (`a/x.jpg` and `b/x.jpg`, or `x.png` and `x.jpg` with `{stem}`)
+/// would overwrite each other, so that's an error naming them.
+pub fn output_paths(template: &str, inputs: &[PathBuf], settings: &Settings) -> Result<Vec<PathBuf>, String> {
+    let outputs: Vec<PathBuf> = inputs
+        .iter()
+        .enumerate()
+        .map(|(index, input)| output_path(template, input, index + 1, settings))
+        .collect();
+
+    let mut claimed: HashMap<&Path, Vec<&Path>> = HashMap::new();
+    for (input, output) in inputs.iter().zip(&outputs) {
+        claimed.entry(output.as_path()).or_default().push(input);
+    }
+    let mut clashes: Vec<String> = outputs
+        .iter()
+        .filter_map(|output| claimed.remove(output.as_path()).filter(|ins| ins.len() > 1).map(|ins| (output, ins)))
+        .map(|(output, ins)| {
+            let ins: Vec<String> = ins.iter().map(|i| i.display().to_string()).collect();
+            format!("  {} <- {}", output.display(), ins.join(", "))
+        })
+        .collect();
+    if clashes.is_empty() {
+        return Ok(outputs);
+    }
+    clashes.insert(
+        0,
+        "Some inputs would be written to the same file. Put {dir}, {ext} or {index} in the output template:".to_string(),
+    );
+    Err(clashes.join("\n"))
+}
+
+/// Renders every input to its output (see `output_paths`) with the same tiles and settings.
+/// Results come back in input order; inputs skipped after a failure (without
+/// `continue_on_error`) are left out.
+pub fn run_batch(
+    inputs: &[PathBuf],
+    outputs: &[PathBuf],
+    tiles: &TileSet,
+    settings: &Settings,
+    opts: &BatchOptions,
+) -> Vec<BatchResult> {
+    let next = AtomicUsize::new(0);
+    let stop = AtomicBool::new(false);
+    let results = Mutex::new(Vec::with_capacity(inputs.len()));
+
+    std::thread::scope(|scope| {
+        for _ in 0..opts.jobs.clamp(1, inputs.len().max(1)) {
+            scope.spawn(|| {
+                loop {
+                    if stop.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let (Some(input), Some(output)) = (inputs.get(index), outputs.get(index)) else { break };
+
+                    let started = Instant::now();
+                    let outcome = render_one(input, output, tiles, settings);
+                    if outcome.is_err() && !opts.continue_on_error {
+                        stop.store(true, Ordering::Relaxed);
+                    }
+
+                    let result = BatchResult {
+                        input: input.clone(),
+                        output: output.clone(),
+                        elapsed: started.elapsed(),
+                        outcome,
+                    };
+                    results.lock().unwrap().push((index, result));
+                }
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+fn render_one(input: &Path, output: &Path, tiles: &TileSet, settings: &Settings) -> Result<u32, String> {
+    let (image, alpha) = try_load_input(&input.to_string_lossy(), settings)?;
+    let name = input.file_name().map(|n| n.to_string_lossy().into_owned());
+    if let Some(format) = OutputFormat::from_path(output).filter(OutputFormat::is_text) {
+        let input = prepare_input(image, settings);
+        let alpha = alpha.map(|a| prepare_alpha(a, settings));
+        let art = TextArt::new(&input, alpha.as_ref(), settings);
+        match format {
+            OutputFormat::Txt => write_text(output, &art)?,
+            _ => write_html(output, &art, settings)?,
+        }
+        return Ok(art.cells);
+    }
+    let (mosaic, out) = run(image, alpha, tiles, settings, name.as_deref())?;
+
+    if let Some(parent_dir) = output.parent() {
+        std::fs::create_dir_all(parent_dir).map_err(|e| format!("Failed to create output directory: {}", e))?;
+    }
+    out.save(output).map_err(|e| format!("Error saving {:?}: {}", output, e))?;
+    Ok(mosaic.total_dice())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(names: &[&str]) -> Vec<PathBuf> {
+        names.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn output_paths_fill_in_the_template() {
+        let inputs = paths(&["shots/a.jpg", "b.png"]);
+        let outputs = output_paths("out/{stem}-{index}-{size}.{ext}", &inputs, &Settings::default()).unwrap();
+        assert_eq!(outputs, paths(&["out/a-1-32.jpg", "out/b-2-32.png"]));
+    }
+
+    #[test]
+    fn output_paths_refuse_inputs_landing_on_one_file() {
+        let inputs = paths(&["a/x.jpg", "b/x.jpg", "x.png", "y.png"]);
+        let err = output_paths(DEFAULT_TEMPLATE, &inputs, &Settings::default()).unwrap_err();
+        assert!(err.contains("output/x_dice.png <- a/x.jpg, b/x.jpg, x.png"), "{}", err);
+        assert!(!err.contains("y.png"), "{}", err);
+        assert!(output_paths("{dir}/{stem}.{ext}.png", &inputs, &Settings::default()).is_ok());
+    }
+}
@@ -0,0 +1,261 @@
+// On-disk cache of resized dice. Decoding and Lanczos-resizing hundreds of tiles on
+// every run adds up, so each resized tile is stored as a PNG keyed by what went into it:
+//
+//     <path hash>-<content hash>-<w>x<h>-<filter>.png
+//
+// Changing the source file changes the content hash, so stale tiles are never read.
+// When a new entry is written, older entries for the same file, size and filter are
+// deleted so the cache doesn't pile up. `dice cache clear` wipes the lot.
+
+use std::path::{Path, PathBuf};
+
+use image::{imageops::FilterType, DynamicImage};
+
+// Bump when the stored format or resize pipeline changes
+const CACHE_VERSION: u64 = 1;
+
+pub struct TileCache {
+    dir: PathBuf,
+}
+
+impl TileCache {
+    pub fn new(dir: impl Into<PathBuf>) -> TileCache {
+        TileCache { dir: dir.into() }
+    }
+
+    /// `$DICE_CACHE_DIR`, else `$XDG_CACHE_HOME/dice`, else `~/.cache/dice`,
+    /// else `.dice-cache` in the working directory.
+    pub fn default_dir() -> PathBuf {
+        if let Some(dir) = std::env::var_os("DICE_CACHE_DIR") {
+            return PathBuf::from(dir);
+        }
+        if let Some(dir) = std::env::var_os("XDG_CACHE_HOME") {
+            return PathBuf::from(dir).join("dice");
+        }
+        if let Some(home) = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE")) {
+            return PathBuf::from(home).join(".cache").join("dice");
+        }
+        PathBuf::from(".dice-cache")
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Loads the tile at `path` resized to `w`x`h`, from the cache if it's there,
+    /// otherwise resizes it and stores the result. A cache that can't be written
+    /// just means we resize every time, so that only warns.
+    pub fn load_resized(&self, path: &Path, w: u32, h: u32, filter: FilterType) -> Result<DynamicImage, String> {
+        let bytes = std::fs::read(path).map_err(|e| format!("Couldn't read dice image at {:?}: {}", path, e))?;
+        let prefix = entry_prefix(path, w, h, filter);
+        let entry = self.dir.join(format!(
+            "{}-{:016x}-{}.png",
+            prefix.0,
+            fnv1a(&bytes, CACHE_VERSION),
+            prefix.1
+        ));
+
+        if let Ok(cached) = image::open(&entry) {
+            return Ok(cached);
+        }
+
+        let image = image::load_from_memory(&bytes)
+            .map_err(|e| format!("Couldn't load dice image {:?}: {}", path, e))?
+            .resize_exact(w, h, filter);
+        let resized = DynamicImage::ImageRgba8(image.to_rgba8());
+
+        if let Err(err) = self.store(&entry, &prefix, &resized) {
+            eprintln!("Warning: couldn't write tile cache {:?}: {}", entry, err);
+        }
+        Ok(resized)
+    }
+
+    fn store(&self, entry: &Path, prefix: &(String, String), image: &DynamicImage) -> Result<(), String> {
+        std::fs::create_dir_all(&self.dir).map_err(|e| e.to_string())?;
+
+        // Same file, size and filter but different content: that's an old version
+        for stale in self.entries()? {
+            let name = stale.file_name().and_then(|n| n.to_str()).unwrap_or("");
+            if name.starts_with(&prefix.0) && name.ends_with(&format!("-{}.png", prefix.1)) {
+                let _ = std::fs::remove_file(&stale);
+            }
+        }
+
+        image.save(entry).map_err(|e| e.to_string())
+    }
+
+    /// Files in the cache directory named like our entries. Anything else there (the
+    /// cache can share a directory, even the dice folder) is left alone.
+    fn entries(&self) -> Result<Vec<PathBuf>, String> {
+        match std::fs::read_dir(&self.dir) {
+            Ok(dir) => Ok(dir
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| path.file_name().and_then(|n| n.to_str()).is_some_and(is_entry))
+                .collect()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    /// Number of cached tiles and their total size in bytes.
+    pub fn stats(&self) -> Result<(usize, u64), String> {
+        let entries = self.entries()?;
+        let bytes = entries
+            .iter()
+            .filter_map(|path| std::fs::metadata(path).ok())
+            .map(|meta| meta.len())
+            .sum();
+        Ok((entries.len(), bytes))
+    }
+
+    /// Deletes every cached tile. Returns how many were removed.
+    pub fn clear(&self) -> Result<usize, String> {
+        let entries = self.entries()?;
+        for entry in &entries {
+            std::fs::remove_file(entry).map_err(|e| format!("Couldn't remove {:?}: {}", entry, e))?;
+        }
+        Ok(entries.len())
+    }
+}
+
+// (path hash, "<w>x<h>-<filter>")
+fn entry_prefix(path: &Path, w: u32, h: u32, filter: FilterType) -> (String, String) {
+    let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let path_hash = fnv1a(canonical.to_string_lossy().as_bytes(), CACHE_VERSION);
+    (format!("{:016x}", path_hash), format!("{}x{}-{}", w, h, filter_name(filter)))
+}
+
+// "<16 hex>-<16 hex>-<w>x<h>-<filter>.png"
+fn is_entry(name: &str) -> bool {
+    let Some(stem) = name.strip_suffix(".png") else {
+        return false;
+    };
+    let hex = |s: &str| s.len() == 16 && s.bytes().all(|b| b.is_ascii_hexdigit());
+    let number = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    match stem.splitn(4, '-').collect::<Vec<_>>()[..] {
+        [path, content, size, filter] => {
+            hex(path)
+                && hex(content)
+                && size.split_once('x').is_some_and(|(w, h)| number(w) && number(h))
+                && FILTERS.iter().any(|&f| filter_name(f) == filter)
+        }
+        _ => false,
+    }
+}
+
+const FILTERS: [FilterType; 5] = [
+    FilterType::Nearest,
+    FilterType::Triangle,
+    FilterType::CatmullRom,
+    FilterType::Gaussian,
+    FilterType::Lanczos3,
+];
+
+pub fn filter_name(filter: FilterType) -> &'static str {
+    match filter {
+        FilterType::Nearest => "nearest",
+        FilterType::Triangle => "triangle",
+        FilterType::CatmullRom => "catmullrom",
+        FilterType::Gaussian => "gaussian",
+        FilterType::Lanczos3 => "lanczos3",
+    }
+}
+
+// FNV-1a, 64 bit. Stable across Rust versions and platforms, unlike DefaultHasher.
+fn fnv1a(bytes: &[u8], seed: u64) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64 ^ seed;
+    for &b in bytes {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dice-cache-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_tile(path: &Path, shade: u8) {
+        image::GrayImage::from_pixel(4, 4, image::Luma([shade])).save(path).unwrap();
+    }
+
+    fn names(cache: &TileCache) -> Vec<String> {
+        let mut names: Vec<String> = cache
+            .entries()
+            .unwrap()
+            .iter()
+            .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn entries_are_recognised_by_name() {
+        assert!(is_entry("0123456789abcdef-fedcba9876543210-32x32-lanczos3.png"));
+        assert!(is_entry("0123456789abcdef-fedcba9876543210-8x16-nearest.png"));
+        assert!(!is_entry("1.png"));
+        assert!(!is_entry("0123456789abcdef-fedcba9876543210-32x32-lanczos3.jpg"));
+        assert!(!is_entry("0123456789abcdef-fedcba9876543210-32x32-bicubic.png"));
+        assert!(!is_entry("0123456789abcdef-fedcba98765432-32x32-lanczos3.png"));
+        assert!(!is_entry("0123456789abcdeg-fedcba9876543210-32x32-lanczos3.png"));
+        assert!(!is_entry("0123456789abcdef-fedcba9876543210-32-lanczos3.png"));
+        assert!(!is_entry("0123456789abcdef-fedcba9876543210-32xx-lanczos3.png"));
+    }
+
+    #[test]
+    fn key_follows_size_filter_and_content() {
+        let dir = scratch("key");
+        let tile = dir.join("1.png");
+        write_tile(&tile, 10);
+        let cache = TileCache::new(dir.join("cache"));
+
+        cache.load_resized(&tile, 2, 2, FilterType::Lanczos3).unwrap();
+        cache.load_resized(&tile, 3, 3, FilterType::Lanczos3).unwrap();
+        cache.load_resized(&tile, 2, 2, FilterType::Nearest).unwrap();
+        let first = names(&cache);
+        assert_eq!(first.len(), 3);
+        assert!(first.iter().all(|n| is_entry(n)));
+        assert!(first.iter().any(|n| n.ends_with("-3x3-lanczos3.png")));
+        assert!(first.iter().any(|n| n.ends_with("-2x2-nearest.png")));
+
+        // Same request again is a hit, not a new entry
+        cache.load_resized(&tile, 2, 2, FilterType::Lanczos3).unwrap();
+        assert_eq!(names(&cache), first);
+
+        // New content, new key, and the stale entry for that size and filter goes
+        write_tile(&tile, 200);
+        let resized = cache.load_resized(&tile, 2, 2, FilterType::Lanczos3).unwrap();
+        assert_eq!(resized.to_rgba8().get_pixel(0, 0).0[0], 200);
+        let second = names(&cache);
+        assert_eq!(second.len(), 3);
+        assert_ne!(second, first);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn clear_leaves_other_files_alone() {
+        let dir = scratch("clear");
+        for pips in 1..=6 {
+            write_tile(&dir.join(format!("{}.png", pips)), 40 * pips);
+        }
+        // The cache shares the dice folder
+        let cache = TileCache::new(&dir);
+        for pips in 1..=6 {
+            cache.load_resized(&dir.join(format!("{}.png", pips)), 2, 2, FilterType::Triangle).unwrap();
+        }
+        assert_eq!(cache.stats().unwrap().0, 6);
+        assert_eq!(cache.clear().unwrap(), 6);
+        assert_eq!(cache.stats().unwrap().0, 0);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 6);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,147 @@
+// Contact sheet: the same input rendered with every combination of preset, dither mode
+// and dice size, shrunk to thumbnails and laid out in one labeled image. One column per
+// preset, one row per dice size and dither mode, so picking a look is a single glance.
+
+use image::{imageops, GrayImage, Rgba, RgbaImage};
+use imageproc::drawing::{draw_text_mut, text_size};
+
+use crate::cache::TileCache;
+use crate::dicelib::{font, IntensityPreset};
+use crate::dither::Dither;
+use crate::pipeline::{self, Settings};
+
+const BACKGROUND: Rgba<u8> = Rgba([32, 32, 32, 255]);
+const CAPTION: Rgba<u8> = Rgba([255, 255, 255, 255]);
+
+pub struct CompareOptions {
+    pub presets: Vec<IntensityPreset>,
+    pub dithers: Vec<Dither>,
+    pub sizes: Vec<u32>,
+    /// Width of each thumbnail in pixels.
+    pub thumb_width: u32,
+}
+
+impl Default for CompareOptions {
+    fn default() -> CompareOptions {
+        CompareOptions {
+            presets: IntensityPreset::ALL.to_vec(),
+            dithers: Dither::ALL.to_vec(),
+            sizes: vec![16, 32],
+            thumb_width: 320,
+        }
+    }
+}
+
+/// One cell of the sheet.
+#[derive(Debug, Clone, Copy)]
+pub struct Variant {
+    pub preset: IntensityPreset,
+    pub dither: Dither,
+    pub dice_size: u32,
+}
+
+impl Variant {
+    pub fn caption(&self) -> String {
+        format!("{}, {}, {}px", self.preset.name(), self.dither.name(), self.dice_size)
+    }
+
+    /// `base` with this variant's preset, dither and size.
+    pub fn settings(&self, base: &Settings) -> Settings {
+        Settings {
+            dice_size: self.dice_size,
+            preset: self.preset,
+            thresholds: None,
+            dither: self.dither,
+            quadtree: None,
+            add_debug: false,
+            ..base.clone()
+        }
+    }
+}
+
+impl CompareOptions {
+    /// Every variant in sheet order: row by row (size, then dither), presets across.
+    pub fn variants(&self) -> Vec<Variant> {
+        let mut variants = Vec::new();
+        for &dice_size in &self.sizes {
+            for &dither in &self.dithers {
+                for &preset in &self.presets {
+                    variants.push(Variant { preset, dither, dice_size });
+                }
+            }
+        }
+        variants
+    }
+}
+
+/// Renders every variant of `input` and lays the thumbnails out with captions.
+/// The dice are loaded once per size; everything else in `base` applies to all cells.
+/// `alpha` is the input's alpha when transparent cells are left empty.
+pub fn contact_sheet(
+    input: &GrayImage,
+    alpha: Option<&GrayImage>,
+    dice_dir: &str,
+    base: &Settings,
+    opts: &CompareOptions,
+    cache: Option<&TileCache>,
+) -> Result<RgbaImage, String> {
+    let variants = opts.variants();
+    if variants.is_empty() {
+        return Err("Nothing to compare: pick at least one preset, dither mode and dice size".to_string());
+    }
+    let columns = opts.presets.len() as u32;
+    let rows = variants.len() as u32 / columns;
+
+    // All thumbnails share the prepared input's aspect ratio
+    let prepared = pipeline::prepare_input(input.clone(), base);
+    let alpha = alpha.map(|a| pipeline::prepare_alpha(a.clone(), base));
+    let (pw, ph) = prepared.dimensions();
+    let thumb_w = opts.thumb_width.max(16);
+    let thumb_h = ((thumb_w as f64 * ph as f64 / pw.max(1) as f64).round() as u32).max(1);
+
+    let font = font();
+    let scale = (thumb_w as f32 / 18.0).clamp(12.0, 28.0);
+    let caption_h = (scale * 1.6).ceil() as u32;
+    let gap = (thumb_w / 32).max(4);
+    let cell_w = thumb_w + gap;
+    let cell_h = thumb_h + caption_h + gap;
+    let mut sheet = RgbaImage::from_pixel(columns * cell_w + gap, rows * cell_h + gap, BACKGROUND);
+
+    let mut tiles = None;
+    for (i, variant) in variants.iter().enumerate() {
+        let settings = variant.settings(base);
+        // Variants come grouped by size, so reload only when it changes
+        if tiles.as_ref().is_none_or(|(size, _)| *size != variant.dice_size) {
+            tiles = Some((variant.dice_size, pipeline::load_tiles(dice_dir, &settings, cache)?));
+        }
+        let (_, tile_set) = tiles.as_ref().expect("loaded above");
+
+        let mosaic = pipeline::plan_masked(&prepared, alpha.as_ref(), &settings);
+        let (col, row) = (i as u32 % columns, i as u32 / columns);
+        let (x, y) = (gap + col * cell_w, gap + row * cell_h);
+        if !mosaic.placements.is_empty() {
+            let full = pipeline::render(&mosaic, tile_set, &settings);
+            let thumb = imageops::resize(&full, thumb_w, thumb_h, imageops::FilterType::Triangle);
+            imageops::overlay(&mut sheet, &thumb, x as i64, y as i64);
+        }
+
+        // Caption centered under the thumbnail, shortened until it fits
+        let mut caption = variant.caption();
+        if mosaic.placements.is_empty() {
+            caption = format!("{} (too big)", caption);
+        } else if mosaic.short > 0 {
+            caption = format!("{} ({} dice short)", caption, mosaic.short);
+        }
+        if text_size(scale, &font, &caption).0 > thumb_w {
+            while text_size(scale, &font, &format!("{}…", caption)).0 > thumb_w && caption.len() > 1 {
+                caption.pop();
+            }
+            caption.push('…');
+        }
+        let (text_w, _) = text_size(scale, &font, &caption);
+        let text_x = x + (thumb_w - text_w.min(thumb_w)) / 2;
+        let text_y = y + thumb_h + (caption_h - scale as u32) / 2;
+        draw_text_mut(&mut sheet, CAPTION, text_x as i32, text_y as i32, scale, &font, &caption);
+    }
+    Ok(sheet)
+}
@@ -1,7 +1,15 @@
-use ab_glyph::{FontVec, PxScale};
-use image::{imageops, open, DynamicImage, GrayImage, Rgba, RgbaImage};
-use imageproc::drawing::{draw_filled_rect_mut, draw_text_mut};
-use imageproc::rect::Rect;
+use std::io::{BufRead, Seek};
+use std::path::{Path, PathBuf};
+
+use ab_glyph::FontVec;
+use image::metadata::Orientation;
+use image::{imageops, DynamicImage, GrayImage, ImageDecoder, ImageReader, RgbaImage};
+use moxcms::{ColorProfile, DataColorSpace, Layout, TransformOptions};
+use serde::{Deserialize, Serialize};
+
+use crate::cache::TileCache;
+use crate::overlay::{Overlay, OverlayPosition};
+use crate::pipeline::Settings;
 
 
 
@@ -15,50 +23,215 @@ pub enum DiceSides {
     Six,
 }
 
+impl DiceSides {
+    /// Every face, darkest to brightest.
+    pub const ALL: [DiceSides; 6] = [
+        DiceSides::One,
+        DiceSides::Two,
+        DiceSides::Three,
+        DiceSides::Four,
+        DiceSides::Five,
+        DiceSides::Six,
+    ];
+
+    /// Position in `ALL`, 0 to 5.
+    pub fn index(&self) -> usize {
+        self.pips() as usize - 1
+    }
+
+    /// Number of pips on the face, 1 to 6.
+    pub fn pips(&self) -> u8 {
+        match self {
+            DiceSides::One => 1,
+            DiceSides::Two => 2,
+            DiceSides::Three => 3,
+            DiceSides::Four => 4,
+            DiceSides::Five => 5,
+            DiceSides::Six => 6,
+        }
+    }
+}
+
 #[derive(Debug, Clone)] // Added Clone
 pub struct Dice {
     pub side: DiceSides, // Uses the simplified enum
     pub image: DynamicImage,
 }
 
+/// The font baked into the binary, for anything that writes text on an image.
+pub const FONT_DATA: &[u8] = include_bytes!("../DejaVuSans-Bold.ttf");
+
+pub fn font() -> FontVec {
+    FontVec::try_from_vec(FONT_DATA.to_vec()).expect("Failed to load font")
+}
+
 pub fn add_reference_text(
     image: &mut RgbaImage,
     dice_size: (u32, u32),
     total_dice: u32,
     full_image_size: (u32, u32),
 ) {
-    let font_data = include_bytes!("../DejaVuSans-Bold.ttf");
-    let font = FontVec::try_from_vec(font_data.to_vec()).expect("Failed to load font");
-    let scale = PxScale::from(20.0); // Font size
-
     let text = format!(
         "Dice size: {}x{}, Total dice: {}, Image size: {}x{}",
         dice_size.0, dice_size.1, total_dice, full_image_size.0, full_image_size.1
     );
 
-    // Calculate text dimensions
-    let text_width = text.len() as u32 * 12; // Approximate width per character
-    let text_height = 24; // Approximate height of the text
-
-    // Draw black background rectangle
-    let rect = Rect::at(0, 0).of_size(text_width, text_height);
-    draw_filled_rect_mut(image, rect, Rgba([0, 0, 0, 255])); // Black background
-
-    // Draw the text
-    draw_text_mut(
-        image,
-        Rgba([255, 255, 255, 255]), // White text
-        5,                          // X offset
-        5,                          // Y offset
-        scale,
-        &font,
-        &text,
-    );
+    // White text on a black box that fits it, top-left corner
+    let overlay = Overlay::new(vec![text], image.dimensions(), OverlayPosition::TopLeft, Some(20.0));
+    overlay.draw(image, 0);
+}
+
+
+/// Loads the six dice images from `dice_dir` (sorted by file name, so 1 to 6)
+/// resized to `size`x`size`. Goes through the tile cache when one is given.
+pub fn load_dice_dir(dice_dir: &str, size: u32, cache: Option<&TileCache>) -> Result<[Dice; 6], String> {
+    // Read the dice directory and grab all the files
+    let mut dice_image_paths: Vec<PathBuf> = std::fs::read_dir(dice_dir)
+        .map_err(|_| format!("Couldn't find the dice directory {:?}. Double-check your path!", dice_dir))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .collect();
+
+    // Make sure we have exactly 6 dice images. No more, no less.
+    if dice_image_paths.len() != 6 {
+        return Err(format!(
+            "You need exactly 6 dice images in {:?}, found {}. No shortcuts allowed!",
+            dice_dir,
+            dice_image_paths.len()
+        ));
+    }
+
+    // Sort the paths to keep things consistent (alphabetical order is nice)
+    dice_image_paths.sort();
+
+    let mut dice = Vec::with_capacity(6);
+    for (image_path, side) in dice_image_paths.iter().zip(DiceSides::ALL) {
+        let image = match cache {
+            Some(cache) => cache.load_resized(image_path, size, size, imageops::FilterType::Lanczos3)?,
+            None => load_resized(image_path, size)?,
+        };
+        dice.push(Dice { side, image });
+    }
+
+    Ok(dice.try_into().expect("Exactly six dice were loaded."))
+}
+
+fn load_resized(path: &Path, size: u32) -> Result<DynamicImage, String> {
+    let image_data = std::fs::read(path).map_err(|_| format!("Couldn't read dice image at {:?}", path))?;
+    let image = image::load_from_memory(&image_data).map_err(|_| format!("Couldn't load dice image {:?}", path))?;
+    Ok(image.resize_exact(size, size, imageops::FilterType::Lanczos3))
+}
+
+/// Like `load_image`, but hands back the error instead of giving up.
+pub fn try_load_image(input_path: &str, settings: &Settings) -> Result<GrayImage, String> {
+    try_load_input(input_path, settings).map(|(luma, _)| luma)
+}
+
+/// The input's luma and, when the `alpha` or `mask` settings cut out cells, the mask
+/// that does it. See `pipeline::plan_masked`.
+pub fn try_load_input(input_path: &str, settings: &Settings) -> Result<(GrayImage, Option<GrayImage>), String> {
+    let img = ImageReader::open(input_path)
+        .map_err(|e| e.to_string())
+        .and_then(|reader| decode_input(reader, settings.auto_orient))
+        .map_err(|e| format!("Failed to load input image {}: {}", input_path, e))?;
+    split_input(&img, settings)
+}
+
+/// A decoded input's luma, and its alpha and subject masks combined (the lower of
+/// the two wins) when either is in use.
+pub fn split_input(img: &DynamicImage, settings: &Settings) -> Result<(GrayImage, Option<GrayImage>), String> {
+    let (luma, alpha) = settings.alpha.split(img, settings.grayscale);
+    let Some(subject) = &settings.mask else {
+        return Ok((luma, alpha));
+    };
+    let mut mask = subject.mask(img, &luma)?;
+    if let Some(alpha) = alpha {
+        for (m, a) in mask.pixels_mut().zip(alpha.pixels()) {
+            m.0[0] = m.0[0].min(a.0[0]);
+        }
+    }
+    Ok((luma, Some(mask)))
+}
+
+/// Decodes an input the way it was meant to be seen: in sRGB if it carries an ICC
+/// profile, and turned upright per its EXIF orientation unless `auto_orient` is off.
+pub fn decode_input<R: BufRead + Seek>(reader: ImageReader<R>, auto_orient: bool) -> Result<DynamicImage, String> {
+    let mut decoder = reader.into_decoder().map_err(|e| e.to_string())?;
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    let icc = decoder.icc_profile().ok().flatten();
+    let mut image = DynamicImage::from_decoder(decoder).map_err(|e| e.to_string())?;
+    if let Some(icc) = icc {
+        image = to_srgb(image, &icc);
+    }
+    if auto_orient {
+        image.apply_orientation(orientation);
+    }
+    Ok(image)
 }
 
+/// Converts from the embedded profile to sRGB, keeping alpha and 16-bit depth.
+/// Profiles we can't read or use (CMYK, Lab...) leave the image as decoded.
+fn to_srgb(image: DynamicImage, icc: &[u8]) -> DynamicImage {
+    let Ok(profile) = ColorProfile::new_from_slice(icc) else {
+        return image;
+    };
+    let srgb = ColorProfile::new_srgb();
+    let alpha = image.color().has_alpha();
+    let deep = image.color().bytes_per_pixel() / image.color().channel_count() > 1;
+    let from = match (profile.color_space, alpha) {
+        (DataColorSpace::Rgb, false) => Layout::Rgb,
+        (DataColorSpace::Rgb, true) => Layout::Rgba,
+        (DataColorSpace::Gray, false) => Layout::Gray,
+        (DataColorSpace::Gray, true) => Layout::GrayAlpha,
+        _ => return image,
+    };
+    let to = if alpha { Layout::Rgba } else { Layout::Rgb };
+    let (w, h) = (image.width(), image.height());
+    let channels = |layout: Layout| match layout {
+        Layout::Gray => 1,
+        Layout::GrayAlpha => 2,
+        Layout::Rgb => 3,
+        _ => 4,
+    };
+    let out_len = (w * h) as usize * channels(to);
+
+    let converted = if deep {
+        let Ok(transform) = profile.create_transform_16bit(from, &srgb, to, TransformOptions::default()) else {
+            return image;
+        };
+        let src = match from {
+            Layout::Gray => image.to_luma16().into_raw(),
+            Layout::GrayAlpha => image.to_luma_alpha16().into_raw(),
+            Layout::Rgb => image.to_rgb16().into_raw(),
+            _ => image.to_rgba16().into_raw(),
+        };
+        let mut dst = vec![0u16; out_len];
+        transform.transform(&src, &mut dst).ok().and_then(|_| match to {
+            Layout::Rgb => image::ImageBuffer::from_raw(w, h, dst).map(DynamicImage::ImageRgb16),
+            _ => image::ImageBuffer::from_raw(w, h, dst).map(DynamicImage::ImageRgba16),
+        })
+    } else {
+        let Ok(transform) = profile.create_transform_8bit(from, &srgb, to, TransformOptions::default()) else {
+            return image;
+        };
+        let src = match from {
+            Layout::Gray => image.to_luma8().into_raw(),
+            Layout::GrayAlpha => image.to_luma_alpha8().into_raw(),
+            Layout::Rgb => image.to_rgb8().into_raw(),
+            _ => image.to_rgba8().into_raw(),
+        };
+        let mut dst = vec![0u8; out_len];
+        transform.transform(&src, &mut dst).ok().and_then(|_| match to {
+            Layout::Rgb => image::ImageBuffer::from_raw(w, h, dst).map(DynamicImage::ImageRgb8),
+            _ => image::ImageBuffer::from_raw(w, h, dst).map(DynamicImage::ImageRgba8),
+        })
+    };
+    converted.unwrap_or(image)
+}
 
 /// Loads and returns a GrayImage
-pub fn load_image(input_path: &str) -> GrayImage {
+pub fn load_image(input_path: &str, settings: &Settings) -> GrayImage {
     // Path is currently hardcoded inside, consider passing _input_path through
 
    // let img = ImageReader::open("images/flag.jpeg").unwrap()
@@ -66,9 +239,8 @@ pub fn load_image(input_path: &str) -> GrayImage {
    //     .expect("Failed to decode image")
    //     .into_luma8();
 
-   let img = open(input_path) // Use _input_path here if needed
-       .expect("Failed to load input image")
-       .into_luma8();
+   let img = try_load_image(input_path, settings) // Use _input_path here if needed
+       .unwrap_or_else(|e| panic!("{}", e));
 
    // // conditional resize here later
 //    let dynamic_image = DynamicImage::ImageLuma8(img);
@@ -79,6 +251,8 @@ pub fn load_image(input_path: &str) -> GrayImage {
 }
 
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum IntensityPreset {
     Default,
     HighContrast,
@@ -87,47 +261,166 @@ pub enum IntensityPreset {
     Dark
 }
 
+impl IntensityPreset {
+    pub const ALL: [IntensityPreset; 5] = [
+        IntensityPreset::Default,
+        IntensityPreset::HighContrast,
+        IntensityPreset::LowContrast,
+        IntensityPreset::Bright,
+        IntensityPreset::Dark,
+    ];
+
+    pub fn parse(s: &str) -> Option<IntensityPreset> {
+        match s.trim().to_ascii_lowercase().replace(['-', '_', ' '], "").as_str() {
+            "default" => Some(IntensityPreset::Default),
+            "highcontrast" | "high" => Some(IntensityPreset::HighContrast),
+            "lowcontrast" | "low" => Some(IntensityPreset::LowContrast),
+            "bright" => Some(IntensityPreset::Bright),
+            "dark" => Some(IntensityPreset::Dark),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            IntensityPreset::Default => "default",
+            IntensityPreset::HighContrast => "high-contrast",
+            IntensityPreset::LowContrast => "low-contrast",
+            IntensityPreset::Bright => "bright",
+            IntensityPreset::Dark => "dark",
+        }
+    }
+}
+
+/// Upper bounds (inclusive) of faces One to Five; anything brighter is a Six.
+/// The presets are just named sets of these, but any ascending set works.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "[u8; 5]", into = "[u8; 5]")]
+pub struct Thresholds(pub [u8; 5]);
+
+impl TryFrom<[u8; 5]> for Thresholds {
+    type Error = String;
+    fn try_from(bounds: [u8; 5]) -> Result<Thresholds, String> {
+        Thresholds::new(bounds)
+    }
+}
+
+impl From<Thresholds> for [u8; 5] {
+    fn from(t: Thresholds) -> [u8; 5] {
+        t.0
+    }
+}
+
+impl Thresholds {
+    /// Checks the bounds go up. Equal neighbours are fine, that face just never shows up.
+    pub fn new(bounds: [u8; 5]) -> Result<Thresholds, String> {
+        if bounds.windows(2).any(|w| w[0] > w[1]) {
+            return Err(format!("Thresholds have to go up from dark to bright, got {:?}", bounds));
+        }
+        Ok(Thresholds(bounds))
+    }
+
+    /// Five comma-separated bounds, e.g. "40,90,140,190,230".
+    pub fn parse(s: &str) -> Result<Thresholds, String> {
+        let values: Vec<u8> = s
+            .split(',')
+            .map(|v| v.trim().parse().map_err(|_| format!("Bad threshold {:?}, expected 0 to 255", v.trim())))
+            .collect::<Result<_, _>>()?;
+        let bounds: [u8; 5] = values
+            .try_into()
+            .map_err(|v: Vec<u8>| format!("Expected 5 thresholds, got {}", v.len()))?;
+        Thresholds::new(bounds)
+    }
+
+    pub fn from_preset(preset: &IntensityPreset) -> Thresholds {
+        Thresholds(match preset {
+            IntensityPreset::Default => [50, 100, 150, 200, 230],
+            IntensityPreset::HighContrast => [42, 85, 128, 171, 214],
+            IntensityPreset::LowContrast => [60, 120, 180, 210, 240],
+            IntensityPreset::Bright => [30, 80, 130, 180, 220],
+            IntensityPreset::Dark => [70, 120, 160, 200, 240],
+        })
+    }
+
+    pub fn side_for(&self, avg_intensity: u8) -> DiceSides {
+        let i = self.0.iter().position(|&bound| avg_intensity <= bound).unwrap_or(5);
+        DiceSides::ALL[i]
+    }
+
+    /// The intensity a face stands for: the middle of its band, except the two ends
+    /// which stand for pure black and pure white so flat areas don't drift when dithering.
+    pub fn level(&self, side: DiceSides) -> u8 {
+        match side.index() {
+            0 => 0,
+            5 => 255,
+            i => ((self.0[i - 1] as u16 + 1 + self.0[i] as u16) / 2) as u8,
+        }
+    }
+}
+
 pub fn map_intensity_to_dice_side(avg_intensity: u8, preset: &IntensityPreset) -> DiceSides {
-    match preset {
-        IntensityPreset::Default => match avg_intensity {
-            0..=50 => DiceSides::One,
-            51..=100 => DiceSides::Two,
-            101..=150 => DiceSides::Three,
-            151..=200 => DiceSides::Four,
-            201..=230 => DiceSides::Five,
-            231..=255 => DiceSides::Six,
-        },
-        IntensityPreset::HighContrast => match avg_intensity {
-            0..=42 => DiceSides::One,
-            43..=85 => DiceSides::Two,
-            86..=128 => DiceSides::Three,
-            129..=171 => DiceSides::Four,
-            172..=214 => DiceSides::Five,
-            215..=255 => DiceSides::Six,
-        },
-        IntensityPreset::LowContrast => match avg_intensity {
-            0..=60 => DiceSides::One,
-            61..=120 => DiceSides::Two,
-            121..=180 => DiceSides::Three,
-            181..=210 => DiceSides::Four,
-            211..=240 => DiceSides::Five,
-            241..=255 => DiceSides::Six,
-        },
-        IntensityPreset::Bright => match avg_intensity {
-            0..=30 => DiceSides::One,
-            31..=80 => DiceSides::Two,
-            81..=130 => DiceSides::Three,
-            131..=180 => DiceSides::Four,
-            181..=220 => DiceSides::Five,
-            221..=255 => DiceSides::Six,
-        },
-        IntensityPreset::Dark => match avg_intensity {
-            0..=70 => DiceSides::One,
-            71..=120 => DiceSides::Two,
-            121..=160 => DiceSides::Three,
-            161..=200 => DiceSides::Four,
-            201..=240 => DiceSides::Five,
-            241..=255 => DiceSides::Six,
+    Thresholds::from_preset(preset).side_for(avg_intensity)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// A 3x2 grid of 8x8 blocks, 1 2 3 over 11 12 13 (times 18, so JPEG can't blur
+    /// them together), as a JPEG tagged with EXIF `orientation`.
+    fn tagged_jpeg(orientation: u16) -> Vec<u8> {
+        let image = GrayImage::from_fn(24, 16, |x, y| image::Luma([((10 * (y / 8) + x / 8 + 1) * 18) as u8]));
+        let mut jpeg = Vec::new();
+        image.write_to(&mut Cursor::new(&mut jpeg), image::ImageFormat::Jpeg).unwrap();
+
+        // APP1 "Exif", then a big-endian TIFF header with one IFD entry: tag 0x0112, SHORT, count 1
+        let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01".to_vec();
+        exif.extend_from_slice(&orientation.to_be_bytes());
+        exif.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+        let mut segment = vec![0xff, 0xe1];
+        segment.extend_from_slice(&(exif.len() as u16 + 2).to_be_bytes());
+        segment.extend_from_slice(&exif);
+
+        // Straight after the start-of-image marker
+        jpeg.splice(2..2, segment);
+        jpeg
+    }
+
+    /// The decoded blocks, read back from their centers.
+    fn decoded(orientation: u16, auto_orient: bool) -> Vec<Vec<u8>> {
+        let reader = ImageReader::with_format(Cursor::new(tagged_jpeg(orientation)), image::ImageFormat::Jpeg);
+        let image = decode_input(reader, auto_orient).unwrap().to_luma8();
+        (0..image.height() / 8)
+            .map(|by| {
+                (0..image.width() / 8)
+                    .map(|bx| (image.get_pixel(bx * 8 + 4, by * 8 + 4).0[0] as f32 / 18.0).round() as u8)
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn every_exif_orientation_comes_out_upright() {
+        let expected: [&[&[u8]]; 8] = [
+            &[&[1, 2, 3], &[11, 12, 13]],
+            &[&[3, 2, 1], &[13, 12, 11]],
+            &[&[13, 12, 11], &[3, 2, 1]],
+            &[&[11, 12, 13], &[1, 2, 3]],
+            &[&[1, 11], &[2, 12], &[3, 13]],
+            &[&[11, 1], &[12, 2], &[13, 3]],
+            &[&[13, 3], &[12, 2], &[11, 1]],
+            &[&[3, 13], &[2, 12], &[1, 11]],
+        ];
+        for (orientation, rows) in (1..=8).zip(expected) {
+            assert_eq!(decoded(orientation, true), rows, "orientation {}", orientation);
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn orientation_is_ignored_when_asked() {
+        for orientation in 1..=8 {
+            assert_eq!(decoded(orientation, false), [[1, 2, 3], [11, 12, 13]]);
+        }
+    }
+}
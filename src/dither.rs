@@ -0,0 +1,120 @@
+// Error diffusion over the dice grid. Six faces is a very coarse palette, so instead of
+// rounding every cell on its own we push each cell's rounding error onto the cells that
+// haven't been decided yet (Floyd-Steinberg weights). Smooth gradients stop banding.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::dicelib::Thresholds;
+use crate::integral::IntegralImage;
+use crate::layout::Cell;
+use crate::render::Placement;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Dither {
+    /// Plain thresholding, every cell on its own.
+    #[default]
+    None,
+    /// Floyd-Steinberg error diffusion across neighbouring dice.
+    FloydSteinberg,
+}
+
+impl Dither {
+    pub const ALL: [Dither; 2] = [Dither::None, Dither::FloydSteinberg];
+
+    pub fn parse(s: &str) -> Option<Dither> {
+        match s.trim().to_ascii_lowercase().replace(['-', '_', ' '], "").as_str() {
+            "none" | "off" => Some(Dither::None),
+            "floydsteinberg" | "fs" => Some(Dither::FloydSteinberg),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Dither::None => "none",
+            Dither::FloydSteinberg => "floyd-steinberg",
+        }
+    }
+}
+
+/// Cell averages as floats, ready to have error pushed around.
+pub fn cell_levels(integral: &IntegralImage, cells: &[Cell]) -> Vec<f32> {
+    cells
+        .iter()
+        .map(|c| integral.level(c.x, c.y, c.w, c.h))
+        .collect()
+}
+
+/// Floyd-Steinberg over the cells' `col`/`row` grid. Cells are visited in the order
+/// given (reading order for every layout), and error only flows to neighbours that
+/// exist, so offset rows and quadtree cells of mixed sizes just lose a bit at the seams.
+pub fn diffuse(levels: Vec<f32>, cells: &[Cell], thresholds: &Thresholds) -> Vec<Placement> {
+    let sides = diffuse_by(levels, cells, |level| {
+        let side = thresholds.side_for(level.round() as u8);
+        (side, thresholds.level(side) as f32)
+    });
+    cells
+        .iter()
+        .zip(sides)
+        .map(|(cell, side)| Placement { cell: *cell, side })
+        .collect()
+}
+
+/// The diffusion itself, for any set of output levels: `quantize` picks the value for a
+/// level and says which level that value stands for. Dice faces and text ramps share it.
+pub fn diffuse_by<T>(mut levels: Vec<f32>, cells: &[Cell], mut quantize: impl FnMut(f32) -> (T, f32)) -> Vec<T> {
+    let index: HashMap<(u32, u32), usize> = cells
+        .iter()
+        .enumerate()
+        .map(|(i, c)| ((c.col, c.row), i))
+        .collect();
+
+    let mut out = Vec::with_capacity(cells.len());
+    for (i, cell) in cells.iter().enumerate() {
+        let level = levels[i].clamp(0.0, 255.0);
+        let (value, stands_for) = quantize(level);
+        let error = level - stands_for;
+        out.push(value);
+
+        let (col, row) = (cell.col as i64, cell.row as i64);
+        for (dc, dr, weight) in [(1, 0, 7.0), (-1, 1, 3.0), (0, 1, 5.0), (1, 1, 1.0)] {
+            let (nc, nr) = (col + dc, row + dr);
+            if nc < 0 {
+                continue;
+            }
+            if let Some(&n) = index.get(&(nc as u32, nr as u32)) {
+                // Only cells we haven't decided yet
+                if n > i {
+                    levels[n] += error * weight / 16.0;
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Face assignment with the given dither mode.
+/// Same as `assign_dithered`, for levels already worked out (and maybe adjusted).
+pub fn assign_levels(levels: Vec<f32>, cells: &[Cell], thresholds: &Thresholds, dither: Dither) -> Vec<Placement> {
+    match dither {
+        Dither::None => cells
+            .iter()
+            .zip(levels)
+            .map(|(cell, level)| Placement {
+                cell: *cell,
+                side: thresholds.side_for(level.round().clamp(0.0, 255.0) as u8),
+            })
+            .collect(),
+        Dither::FloydSteinberg => diffuse(levels, cells, thresholds),
+    }
+}
+
+pub fn assign_dithered(integral: &IntegralImage, cells: &[Cell], thresholds: &Thresholds, dither: Dither) -> Vec<Placement> {
+    match dither {
+        Dither::None => crate::render::assign_faces(integral, cells, thresholds),
+        Dither::FloydSteinberg => diffuse(cell_levels(integral, cells), cells, thresholds),
+    }
+}
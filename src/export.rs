@@ -0,0 +1,424 @@
+// Writing a finished mosaic somewhere other than a PNG. The raster formats go through
+// the image crate, SVG and PDF redraw each die as vector shapes (so they print sharp at
+// any size), CSV is the build list (one line per die), and JSON carries the grid or the
+// bill of materials for other tools. Text and HTML live in `text`.
+
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::dicelib::DiceSides;
+use crate::layout::Cell;
+use crate::pipeline::{Mosaic, Settings};
+use crate::render::Placement;
+use crate::text::cell_grid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Png,
+    Tif,
+    Svg,
+    Pdf,
+    Csv,
+    Txt,
+    Html,
+    /// The grid as data, see `Grid`.
+    Json,
+    /// Dice to buy, see `Bom`. JSON too, so only picked by name.
+    Bom,
+}
+
+impl OutputFormat {
+    pub fn parse(s: &str) -> Option<OutputFormat> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "png" => Some(OutputFormat::Png),
+            "tif" | "tiff" => Some(OutputFormat::Tif),
+            "svg" => Some(OutputFormat::Svg),
+            "pdf" => Some(OutputFormat::Pdf),
+            "csv" => Some(OutputFormat::Csv),
+            "txt" | "text" => Some(OutputFormat::Txt),
+            "html" | "htm" => Some(OutputFormat::Html),
+            "json" => Some(OutputFormat::Json),
+            "bom" => Some(OutputFormat::Bom),
+            _ => None,
+        }
+    }
+
+    /// Guesses the format from the file extension.
+    pub fn from_path(path: &Path) -> Option<OutputFormat> {
+        path.extension().and_then(|ext| ext.to_str()).and_then(OutputFormat::parse)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Tif => "tif",
+            OutputFormat::Svg => "svg",
+            OutputFormat::Pdf => "pdf",
+            OutputFormat::Csv => "csv",
+            OutputFormat::Txt => "txt",
+            OutputFormat::Html => "html",
+            OutputFormat::Json => "json",
+            OutputFormat::Bom => "bom",
+        }
+    }
+
+    /// Needs the tile images painted, as opposed to just the face list.
+    pub fn is_raster(&self) -> bool {
+        matches!(self, OutputFormat::Png | OutputFormat::Tif)
+    }
+
+    /// Characters instead of dice, see `text`.
+    pub fn is_text(&self) -> bool {
+        matches!(self, OutputFormat::Txt | OutputFormat::Html)
+    }
+}
+
+/// Real-world size of the dice, for the vector outputs and the build summary.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Physical {
+    /// Edge length of one die (a `dice_size` die, the biggest one in quadtree mode) in mm.
+    pub die_mm: f64,
+}
+
+impl Physical {
+    /// Millimetres per output pixel.
+    pub fn mm_per_pixel(&self, settings: &Settings) -> f64 {
+        self.die_mm / settings.tile_size().max(1) as f64
+    }
+
+    /// Finished size of the piece in mm.
+    pub fn finished_mm(&self, mosaic: &Mosaic, settings: &Settings) -> (f64, f64) {
+        let scale = self.mm_per_pixel(settings);
+        (mosaic.size.0 as f64 * scale, mosaic.size.1 as f64 * scale)
+    }
+
+    /// One-line "how big is this thing" for the console.
+    pub fn summary(&self, mosaic: &Mosaic, settings: &Settings) -> String {
+        let (w, h) = self.finished_mm(mosaic, settings);
+        format!(
+            "Finished size: {:.0} x {:.0} mm ({:.1} x {:.1} in), {} dice at {} mm",
+            w,
+            h,
+            w / 25.4,
+            h / 25.4,
+            mosaic.total_dice(),
+            self.die_mm
+        )
+    }
+}
+
+/// Pip centers for each face on a unit die, top-left origin.
+fn pips(side: DiceSides) -> &'static [(f64, f64)] {
+    const L: f64 = 0.25;
+    const M: f64 = 0.5;
+    const R: f64 = 0.75;
+    match side {
+        DiceSides::One => &[(M, M)],
+        DiceSides::Two => &[(R, L), (L, R)],
+        DiceSides::Three => &[(R, L), (M, M), (L, R)],
+        DiceSides::Four => &[(L, L), (R, L), (L, R), (R, R)],
+        DiceSides::Five => &[(L, L), (R, L), (M, M), (L, R), (R, R)],
+        DiceSides::Six => &[(L, L), (R, L), (L, M), (R, M), (L, R), (R, R)],
+    }
+}
+
+const PIP_RADIUS: f64 = 0.09;
+
+/// Die body and pip colors for the vector outputs. Same as the stock tiles: without a
+/// palette the dice are white with black pips (black with white pips when inverted).
+pub(crate) fn vector_colors(settings: &Settings) -> ([u8; 3], [u8; 3]) {
+    let palette = settings.palette.unwrap_or_default();
+    let (body, pip) = (palette.at(255), palette.at(0));
+    if settings.invert_dice {
+        (pip, body)
+    } else {
+        (body, pip)
+    }
+}
+
+/// The list of placed dice as CSV: position, size, grid slot and face.
+pub fn csv(placed: &[Placement]) -> String {
+    let mut out = String::from("x,y,width,height,col,row,face\n");
+    for Placement { cell, side } in placed {
+        out.push_str(&format!(
+            "{},{},{},{},{},{},{}\n",
+            cell.x, cell.y, cell.w, cell.h, cell.col, cell.row, side.pips()
+        ));
+    }
+    out
+}
+
+pub fn write_csv(path: &Path, placed: &[Placement]) -> Result<(), String> {
+    write_file(path, csv(placed).as_bytes())
+}
+
+/// One die in the JSON grid.
+#[derive(Debug, Clone, Serialize)]
+pub struct PlacedDie {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub col: u32,
+    pub row: u32,
+    pub face: u8,
+}
+
+/// The grid as JSON for other tools: pips by row and column, and where every die goes.
+#[derive(Debug, Clone, Serialize)]
+pub struct Grid {
+    pub width: u32,
+    pub height: u32,
+    pub total_dice: u32,
+    /// Pips by row and column (quadtree mosaics on a grid of the smallest die), null
+    /// where there's no die.
+    pub faces: Vec<Vec<Option<u8>>>,
+    pub placements: Vec<PlacedDie>,
+}
+
+impl Grid {
+    pub fn new(mosaic: &Mosaic) -> Grid {
+        let cells: Vec<Cell> = mosaic.placements.iter().map(|p| p.cell).collect();
+        let pips: Vec<u8> = mosaic.placements.iter().map(|p| p.side.pips()).collect();
+        Grid {
+            width: mosaic.size.0,
+            height: mosaic.size.1,
+            total_dice: mosaic.total_dice(),
+            faces: cell_grid(&cells, &pips),
+            placements: mosaic
+                .placements
+                .iter()
+                .map(|Placement { cell, side }| PlacedDie {
+                    x: cell.x,
+                    y: cell.y,
+                    width: cell.w,
+                    height: cell.h,
+                    col: cell.col,
+                    row: cell.row,
+                    face: side.pips(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Bill of materials: how many dice of each size to get, and how many of them show
+/// each face (handy for checking progress while building).
+#[derive(Debug, Clone, Serialize)]
+pub struct Bom {
+    pub total_dice: u32,
+    pub dice: Vec<BomLine>,
+    /// Finished piece in mm, when the die size is known.
+    pub finished_mm: Option<(f64, f64)>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BomLine {
+    /// Die edge in output pixels.
+    pub size: u32,
+    pub die_mm: Option<f64>,
+    pub count: u32,
+    /// Dice showing 1 to 6 pips.
+    pub faces: [u32; 6],
+}
+
+impl Bom {
+    /// One line per die size, biggest first. Only quadtree mosaics have more than one.
+    pub fn new(mosaic: &Mosaic, settings: &Settings, physical: Option<&Physical>) -> Bom {
+        let mut dice: Vec<BomLine> = Vec::new();
+        for p in &mosaic.placements {
+            let size = p.cell.w.max(p.cell.h);
+            let line = match dice.iter_mut().find(|l| l.size == size) {
+                Some(line) => line,
+                None => {
+                    dice.push(BomLine {
+                        size,
+                        die_mm: physical.map(|ph| ph.mm_per_pixel(settings) * size as f64),
+                        count: 0,
+                        faces: [0; 6],
+                    });
+                    dice.last_mut().expect("just pushed")
+                }
+            };
+            line.count += 1;
+            line.faces[p.side.index()] += 1;
+        }
+        dice.sort_by_key(|line| std::cmp::Reverse(line.size));
+        Bom {
+            total_dice: mosaic.total_dice(),
+            dice,
+            finished_mm: physical.map(|ph| ph.finished_mm(mosaic, settings)),
+        }
+    }
+}
+
+pub fn write_json(path: &Path, value: &impl Serialize) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(value).map_err(|e| format!("Couldn't write JSON: {}", e))?;
+    write_file(path, json.as_bytes())
+}
+
+/// SVG with one `<symbol>` per face and a `<use>` per die. Coordinates are output
+/// pixels; with physical sizing the document is sized in mm so it prints to scale.
+pub fn svg(mosaic: &Mosaic, settings: &Settings, physical: Option<&Physical>) -> String {
+    let (w, h) = mosaic.size;
+    let (body, pip) = vector_colors(settings);
+    let hex = |c: [u8; 3]| format!("#{:02x}{:02x}{:02x}", c[0], c[1], c[2]);
+    let (doc_w, doc_h) = match physical {
+        Some(p) => {
+            let scale = p.mm_per_pixel(settings);
+            (format!("{:.2}mm", w as f64 * scale), format!("{:.2}mm", h as f64 * scale))
+        }
+        None => (w.to_string(), h.to_string()),
+    };
+
+    let mut svg = String::new();
+    svg.push_str(&format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" xmlns:xlink=\"http://www.w3.org/1999/xlink\" width=\"{}\" height=\"{}\" viewBox=\"0 0 {} {}\">\n",
+        doc_w, doc_h, w, h
+    ));
+    svg.push_str("<defs>\n");
+    // Square bodies, so neighbouring dice meet without gaps at the corners
+    for side in DiceSides::ALL {
+        svg.push_str(&format!(
+            "<symbol id=\"f{}\" viewBox=\"0 0 1 1\"><rect width=\"1\" height=\"1\" fill=\"{}\"/>",
+            side.pips(),
+            hex(body)
+        ));
+        for (x, y) in pips(side) {
+            svg.push_str(&format!("<circle cx=\"{}\" cy=\"{}\" r=\"{}\" fill=\"{}\"/>", x, y, PIP_RADIUS, hex(pip)));
+        }
+        svg.push_str("</symbol>\n");
+    }
+    svg.push_str("</defs>\n");
+    // No background: cells without a die stay transparent, like the raster outputs
+    for Placement { cell, side } in &mosaic.placements {
+        svg.push_str(&format!(
+            "<use xlink:href=\"#f{}\" x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\"/>\n",
+            side.pips(),
+            cell.x,
+            cell.y,
+            cell.w,
+            cell.h
+        ));
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+pub fn write_svg(path: &Path, mosaic: &Mosaic, settings: &Settings, physical: Option<&Physical>) -> Result<(), String> {
+    write_file(path, svg(mosaic, settings, physical).as_bytes())
+}
+
+/// Single-page PDF. Each face is a Form XObject drawn on a unit square, and every
+/// die is just "scale, move, draw face n". Without physical sizing one pixel is one point.
+pub fn pdf(mosaic: &Mosaic, settings: &Settings, physical: Option<&Physical>) -> Vec<u8> {
+    let (w, h) = mosaic.size;
+    let (body, pip) = vector_colors(settings);
+    let scale = physical.map_or(1.0, |p| p.mm_per_pixel(settings) * 72.0 / 25.4);
+    let rgb = |c: [u8; 3]| format!("{:.3} {:.3} {:.3}", c[0] as f64 / 255.0, c[1] as f64 / 255.0, c[2] as f64 / 255.0);
+
+    // Objects 1-3 are catalog, pages and page, 4 the page content, 5.. the faces
+    let mut objects: Vec<Vec<u8>> = Vec::new();
+    objects.push(b"<< /Type /Catalog /Pages 2 0 R >>".to_vec());
+    objects.push(b"<< /Type /Pages /Kids [3 0 R] /Count 1 >>".to_vec());
+    let faces: String = DiceSides::ALL
+        .iter()
+        .map(|side| format!("/F{} {} 0 R ", side.pips(), 5 + side.index()))
+        .collect();
+    objects.push(
+        format!(
+            "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {:.2} {:.2}] /Resources << /XObject << {}>> >> /Contents 4 0 R >>",
+            w as f64 * scale,
+            h as f64 * scale,
+            faces
+        )
+        .into_bytes(),
+    );
+
+    // PDF's origin is bottom-left, so rows are flipped
+    // No background: cells without a die stay blank, like the raster outputs
+    let mut content = format!("{:.5} 0 0 {:.5} 0 0 cm\n", scale, scale);
+    for Placement { cell, side } in &mosaic.placements {
+        content.push_str(&format!(
+            "q {} 0 0 {} {} {} cm /F{} Do Q\n",
+            cell.w,
+            cell.h,
+            cell.x,
+            h - cell.y - cell.h,
+            side.pips()
+        ));
+    }
+    objects.push(stream_object("", content.as_bytes()));
+
+    for side in DiceSides::ALL {
+        let mut face = format!("{} rg\n0 0 1 1 re f\n{} rg\n", rgb(body), rgb(pip));
+        for (x, y) in pips(side) {
+            face.push_str(&circle(*x, 1.0 - y, PIP_RADIUS));
+        }
+        face.push_str("f\n");
+        objects.push(stream_object("/Type /XObject /Subtype /Form /BBox [0 0 1 1] ", face.as_bytes()));
+    }
+
+    let mut pdf: Vec<u8> = b"%PDF-1.4\n".to_vec();
+    let mut offsets = Vec::with_capacity(objects.len());
+    for (i, object) in objects.iter().enumerate() {
+        offsets.push(pdf.len());
+        pdf.extend_from_slice(format!("{} 0 obj\n", i + 1).as_bytes());
+        pdf.extend_from_slice(object);
+        pdf.extend_from_slice(b"\nendobj\n");
+    }
+    let xref = pdf.len();
+    pdf.extend_from_slice(format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).as_bytes());
+    for offset in offsets {
+        pdf.extend_from_slice(format!("{:010} 00000 n \n", offset).as_bytes());
+    }
+    pdf.extend_from_slice(
+        format!("trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n", objects.len() + 1, xref).as_bytes(),
+    );
+    pdf
+}
+
+pub fn write_pdf(path: &Path, mosaic: &Mosaic, settings: &Settings, physical: Option<&Physical>) -> Result<(), String> {
+    write_file(path, &pdf(mosaic, settings, physical))
+}
+
+fn stream_object(dict: &str, data: &[u8]) -> Vec<u8> {
+    let mut object = format!("<< {}/Length {} >>\nstream\n", dict, data.len()).into_bytes();
+    object.extend_from_slice(data);
+    object.extend_from_slice(b"\nendstream");
+    object
+}
+
+// Bezier control distance for a quarter circle
+const KAPPA: f64 = 0.552_284_75;
+
+/// Path for a circle, four Bezier quarters.
+fn circle(cx: f64, cy: f64, r: f64) -> String {
+    let k = r * KAPPA;
+    format!(
+        "{:.4} {:.4} m {:.4} {:.4} {:.4} {:.4} {:.4} {:.4} c {:.4} {:.4} {:.4} {:.4} {:.4} {:.4} c \
+         {:.4} {:.4} {:.4} {:.4} {:.4} {:.4} c {:.4} {:.4} {:.4} {:.4} {:.4} {:.4} c h\n",
+        cx + r, cy,
+        cx + r, cy + k, cx + k, cy + r, cx, cy + r,
+        cx - k, cy + r, cx - r, cy + k, cx - r, cy,
+        cx - r, cy - k, cx - k, cy - r, cx, cy - r,
+        cx + k, cy - r, cx + r, cy - k, cx + r, cy
+    )
+}
+
+fn write_file(path: &Path, data: &[u8]) -> Result<(), String> {
+    create_parent(path)?;
+    std::fs::write(path, data).map_err(|e| format!("Error writing {:?}: {}", path, e))
+}
+
+pub(crate) fn create_parent(path: &Path) -> Result<(), String> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => {
+            std::fs::create_dir_all(dir).map_err(|e| format!("Failed to create output directory: {}", e))
+        }
+        _ => Ok(()),
+    }
+}
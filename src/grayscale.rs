@@ -0,0 +1,174 @@
+// How a color input becomes the luma the dice are picked from. The default is the
+// image crate's own conversion (Rec.709 weights on the sRGB values), which is what
+// every render used before there was a choice. Red-heavy subjects come out very
+// differently under the others, so it's worth trying a few.
+
+use image::{DynamicImage, GrayImage, Luma};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Grayscale {
+    /// Rec.709 weights straight on the sRGB values.
+    #[default]
+    Rec709,
+    /// Rec.601 weights (0.299, 0.587, 0.114), the old TV standard. Reds read lighter.
+    Rec601,
+    /// Rec.709 luminance worked out in linear light, then encoded back to sRGB.
+    Linear,
+    /// CIELAB L*, perceptual lightness.
+    Lab,
+    /// One channel on its own: 0 red, 1 green, 2 blue.
+    Channel(usize),
+    /// Channel mixer weights for red, green and blue, scaled to add up to 1.
+    Weights([f32; 3]),
+}
+
+impl Grayscale {
+    /// `rec709`, `rec601`, `linear`, `lab`, `red`, `green`, `blue`, or three weights
+    /// like `0.5,0.3,0.2`.
+    pub fn parse(s: &str) -> Result<Grayscale, String> {
+        let mode = match s.trim().to_ascii_lowercase().as_str() {
+            "rec709" | "709" | "default" => Grayscale::Rec709,
+            "rec601" | "601" => Grayscale::Rec601,
+            "linear" => Grayscale::Linear,
+            "lab" | "l*" => Grayscale::Lab,
+            "red" | "r" => Grayscale::Channel(0),
+            "green" | "g" => Grayscale::Channel(1),
+            "blue" | "b" => Grayscale::Channel(2),
+            other => {
+                let weights: Vec<f32> = other
+                    .split(',')
+                    .map(|w| w.trim().parse::<f32>())
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| format!("Unknown grayscale conversion {:?}", s))?;
+                let [r, g, b] = weights[..] else {
+                    return Err(format!("Channel weights are R,G,B, got {:?}", s));
+                };
+                let sum = r + g + b;
+                if sum.abs() < 1e-6 {
+                    return Err(format!("Channel weights can't add up to 0, got {:?}", s));
+                }
+                Grayscale::Weights([r / sum, g / sum, b / sum])
+            }
+        };
+        Ok(mode)
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Grayscale::Rec709 => "rec709".to_string(),
+            Grayscale::Rec601 => "rec601".to_string(),
+            Grayscale::Linear => "linear".to_string(),
+            Grayscale::Lab => "lab".to_string(),
+            Grayscale::Channel(c) => ["red", "green", "blue"][(*c).min(2)].to_string(),
+            Grayscale::Weights([r, g, b]) => format!("{},{},{}", r, g, b),
+        }
+    }
+
+    pub fn convert(&self, image: &DynamicImage) -> GrayImage {
+        let weighted = |[r, g, b]: [f32; 3]| move |p: [f32; 3]| (r * p[0] + g * p[1] + b * p[2]) * 255.0;
+        match *self {
+            Grayscale::Rec709 => image.to_luma8(),
+            Grayscale::Rec601 => map_rgb(image, weighted([0.299, 0.587, 0.114])),
+            Grayscale::Weights(weights) => map_rgb(image, weighted(weights)),
+            Grayscale::Channel(c) => map_rgb(image, |p| p[c.min(2)] * 255.0),
+            Grayscale::Linear => map_rgb(image, |p| srgb_encode(luminance(p)) * 255.0),
+            Grayscale::Lab => map_rgb(image, |p| lab_lightness(luminance(p)) / 100.0 * 255.0),
+        }
+    }
+}
+
+impl TryFrom<String> for Grayscale {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Grayscale, String> {
+        Grayscale::parse(&s)
+    }
+}
+
+impl From<Grayscale> for String {
+    fn from(mode: Grayscale) -> String {
+        mode.name()
+    }
+}
+
+/// One output value per pixel from its sRGB values in 0-1. Goes through 16 bits so
+/// deep inputs keep their precision.
+fn map_rgb(image: &DynamicImage, f: impl Fn([f32; 3]) -> f32) -> GrayImage {
+    let rgb = image.to_rgb16();
+    GrayImage::from_fn(rgb.width(), rgb.height(), |x, y| {
+        let p = rgb.get_pixel(x, y).0.map(|v| v as f32 / 65535.0);
+        Luma([f(p).round().clamp(0.0, 255.0) as u8])
+    })
+}
+
+/// Rec.709 luminance (Y) of an sRGB pixel, in linear light.
+fn luminance(p: [f32; 3]) -> f32 {
+    let [r, g, b] = p.map(srgb_decode);
+    0.2126 * r + 0.7152 * g + 0.0722 * b
+}
+
+/// sRGB value to linear light, both 0-1.
+pub fn srgb_decode(v: f32) -> f32 {
+    if v <= 0.04045 { v / 12.92 } else { ((v + 0.055) / 1.055).powf(2.4) }
+}
+
+/// Linear light back to an sRGB value, both 0-1.
+pub fn srgb_encode(v: f32) -> f32 {
+    let v = v.clamp(0.0, 1.0);
+    if v <= 0.0031308 { v * 12.92 } else { 1.055 * v.powf(1.0 / 2.4) - 0.055 }
+}
+
+/// CIELAB L* (0-100) for a luminance, white being 1.
+fn lab_lightness(y: f32) -> f32 {
+    let f = if y > 216.0 / 24389.0 { y.cbrt() } else { (24389.0 / 27.0 * y + 16.0) / 116.0 };
+    116.0 * f - 16.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    fn gray(mode: &str, rgb: [u8; 3]) -> u8 {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(1, 1, Rgb(rgb)));
+        Grayscale::parse(mode).unwrap().convert(&image).get_pixel(0, 0).0[0]
+    }
+
+    #[test]
+    fn every_mode_on_pure_red() {
+        let red = [255, 0, 0];
+        assert_eq!(gray("rec709", red), 54);
+        assert_eq!(gray("rec601", red), 76);
+        // Linear and L* both work from luminance 0.2126, then encode it differently
+        assert_eq!(gray("linear", red), 127);
+        assert_eq!(gray("lab", red), 136);
+        assert_eq!(gray("red", red), 255);
+        assert_eq!(gray("green", red), 0);
+        assert_eq!(gray("blue", red), 0);
+        assert_eq!(gray("1,1,2", red), 64);
+    }
+
+    #[test]
+    fn every_mode_keeps_black_white_and_neutral_gray() {
+        for mode in ["rec709", "rec601", "linear", "lab", "red", "green", "blue", "0.6,0.3,0.1"] {
+            assert_eq!(gray(mode, [0, 0, 0]), 0, "{}", mode);
+            assert_eq!(gray(mode, [255, 255, 255]), 255, "{}", mode);
+            // L* is its own scale: only its ends line up with sRGB
+            if mode != "lab" {
+                assert_eq!(gray(mode, [119, 119, 119]), 119, "{}", mode);
+            }
+        }
+        assert_eq!(gray("lab", [119, 119, 119]), 128);
+    }
+
+    #[test]
+    fn parse_normalizes_weights_and_rejects_nonsense() {
+        assert_eq!(Grayscale::parse("2,1,1").unwrap(), Grayscale::Weights([0.5, 0.25, 0.25]));
+        assert_eq!(Grayscale::parse(" Lab ").unwrap(), Grayscale::Lab);
+        assert!(Grayscale::parse("1,1").is_err());
+        assert!(Grayscale::parse("1,-1,0").is_err());
+        assert!(Grayscale::parse("purple").is_err());
+    }
+}
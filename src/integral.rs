@@ -0,0 +1,202 @@
+// Summed-area table. One pass over the image up front, then the sum (and mean)
+// of any rectangle is four lookups, no matter how big the block is. Build it once
+// per input and you can try every dice size and preset against it for free.
+//
+// A linear-light table sums 16-bit linear values instead of the 8-bit encoded ones,
+// so a half black, half white cell averages to the gray it looks like from across
+// the room (about 188) rather than 127.
+
+use image::GrayImage;
+
+use crate::grayscale::{srgb_decode, srgb_encode};
+
+pub struct IntegralImage {
+    width: u32,
+    height: u32,
+    // (width + 1) x (height + 1), first row and column are zero
+    sums: Vec<u64>,
+    /// Sums are of linear light, 0-65535 per pixel.
+    linear: bool,
+}
+
+impl IntegralImage {
+    /// Table of plain pixel sums.
+    pub fn new(input: &GrayImage) -> IntegralImage {
+        IntegralImage::from_fn(input, |v| v as u64)
+    }
+
+    /// Table of pixel sums in linear light, at 16 bits.
+    pub fn linear(input: &GrayImage) -> IntegralImage {
+        let lut: Vec<u64> = (0..=255)
+            .map(|v| (srgb_decode(v as f32 / 255.0) * 65535.0).round() as u64)
+            .collect();
+        IntegralImage {
+            linear: true,
+            ..IntegralImage::from_fn(input, |v| lut[v as usize])
+        }
+    }
+
+    /// Table of squared pixel sums, for variance.
+    pub fn of_squares(input: &GrayImage) -> IntegralImage {
+        IntegralImage::from_fn(input, |v| v as u64 * v as u64)
+    }
+
+    fn from_fn(input: &GrayImage, f: impl Fn(u8) -> u64) -> IntegralImage {
+        let (width, height) = input.dimensions();
+        let stride = width as usize + 1;
+        let mut sums = vec![0u64; stride * (height as usize + 1)];
+
+        for (y, row) in input.as_raw().chunks_exact(width.max(1) as usize).enumerate().take(height as usize) {
+            let mut row_sum = 0u64;
+            for (x, &v) in row.iter().enumerate() {
+                row_sum += f(v);
+                sums[(y + 1) * stride + x + 1] = sums[y * stride + x + 1] + row_sum;
+            }
+        }
+
+        IntegralImage {
+            width,
+            height,
+            sums,
+            linear: false,
+        }
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// Sum over the rectangle, clipped to the image.
+    pub fn sum(&self, x: u32, y: u32, w: u32, h: u32) -> u64 {
+        let (x0, y0, x1, y1) = self.clip(x, y, w, h);
+        let stride = self.width as usize + 1;
+        let at = |x: u32, y: u32| self.sums[y as usize * stride + x as usize];
+        at(x1, y1) + at(x0, y0) - at(x1, y0) - at(x0, y1)
+    }
+
+    /// Number of pixels in the rectangle after clipping.
+    pub fn area(&self, x: u32, y: u32, w: u32, h: u32) -> u64 {
+        let (x0, y0, x1, y1) = self.clip(x, y, w, h);
+        (x1 - x0) as u64 * (y1 - y0) as u64
+    }
+
+    /// Average over the rectangle, rounded down like the old per-pixel loop.
+    /// Zero if the rectangle is entirely outside the image. A linear table gives
+    /// its average encoded back to 0-255, rounded.
+    pub fn mean(&self, x: u32, y: u32, w: u32, h: u32) -> u8 {
+        if self.linear {
+            return self.level(x, y, w, h).round() as u8;
+        }
+        self.sum(x, y, w, h)
+            .checked_div(self.area(x, y, w, h))
+            .unwrap_or(0) as u8
+    }
+
+    /// Average on the 0-255 encoded scale the thresholds use. Unrounded for a linear
+    /// table; a plain one keeps the rounded-down `mean` it always used.
+    pub fn level(&self, x: u32, y: u32, w: u32, h: u32) -> f32 {
+        if !self.linear {
+            return self.mean(x, y, w, h) as f32;
+        }
+        let area = self.area(x, y, w, h);
+        if area == 0 {
+            return 0.0;
+        }
+        let linear = self.sum(x, y, w, h) as f64 / area as f64 / 65535.0;
+        srgb_encode(linear as f32) * 255.0
+    }
+
+    fn clip(&self, x: u32, y: u32, w: u32, h: u32) -> (u32, u32, u32, u32) {
+        let x0 = x.min(self.width);
+        let y0 = y.min(self.height);
+        let x1 = x.saturating_add(w).min(self.width);
+        let y1 = y.saturating_add(h).min(self.height);
+        (x0, y0, x1, y1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 37x23 of xorshift noise: odd sizes, so most dice sizes leave ragged edges.
+    fn noise() -> GrayImage {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        GrayImage::from_fn(37, 23, |_, _| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            image::Luma([(state >> 56) as u8])
+        })
+    }
+
+    /// Every block of every size from 1 to 12, including the ones hanging over the edges.
+    fn blocks(image: &GrayImage) -> impl Iterator<Item = (u32, u32, u32)> {
+        let (w, h) = image.dimensions();
+        (1..=12).flat_map(move |size| {
+            (0..h.div_ceil(size)).flat_map(move |by| (0..w.div_ceil(size)).map(move |bx| (bx * size, by * size, size)))
+        })
+    }
+
+    /// The pixels of a block, clipped to the image.
+    fn pixels(image: &GrayImage, x: u32, y: u32, size: u32) -> Vec<u8> {
+        let (w, h) = image.dimensions();
+        (y..(y + size).min(h))
+            .flat_map(|py| (x..(x + size).min(w)).map(move |px| (px, py)))
+            .map(|(px, py)| image.get_pixel(px, py).0[0])
+            .collect()
+    }
+
+    #[test]
+    fn mean_matches_a_plain_average() {
+        let image = noise();
+        let table = IntegralImage::new(&image);
+        for (x, y, size) in blocks(&image) {
+            let pixels = pixels(&image, x, y, size);
+            let sum: u64 = pixels.iter().map(|&v| v as u64).sum();
+            assert_eq!(table.sum(x, y, size, size), sum, "{}x{} at {},{}", size, size, x, y);
+            assert_eq!(table.area(x, y, size, size), pixels.len() as u64);
+            assert_eq!(table.mean(x, y, size, size), (sum / pixels.len() as u64) as u8, "{}x{} at {},{}", size, size, x, y);
+        }
+    }
+
+    #[test]
+    fn squares_match_a_plain_sum() {
+        let image = noise();
+        let table = IntegralImage::of_squares(&image);
+        for (x, y, size) in blocks(&image) {
+            let sum: u64 = pixels(&image, x, y, size).iter().map(|&v| v as u64 * v as u64).sum();
+            assert_eq!(table.sum(x, y, size, size), sum);
+        }
+    }
+
+    #[test]
+    fn outside_the_image_is_empty() {
+        let table = IntegralImage::new(&noise());
+        assert_eq!(table.area(40, 0, 8, 8), 0);
+        assert_eq!(table.mean(40, 0, 8, 8), 0);
+        assert_eq!(table.sum(0, 0, u32::MAX, u32::MAX), noise().pixels().map(|p| p.0[0] as u64).sum::<u64>());
+    }
+
+    #[test]
+    fn linear_level_matches_a_plain_average_in_linear_light() {
+        let image = noise();
+        let table = IntegralImage::linear(&image);
+        for (x, y, size) in blocks(&image) {
+            let pixels = pixels(&image, x, y, size);
+            let linear = pixels.iter().map(|&v| srgb_decode(v as f32 / 255.0) as f64).sum::<f64>() / pixels.len() as f64;
+            let expected = srgb_encode(linear as f32) * 255.0;
+            // Within a 16-bit step, which is about 0.05 levels at its steepest (near black)
+            let level = table.level(x, y, size, size);
+            assert!((level - expected).abs() < 0.05, "{}x{} at {},{}: {} vs {}", size, size, x, y, level, expected);
+            assert_eq!(table.mean(x, y, size, size), level.round() as u8);
+        }
+    }
+
+    #[test]
+    fn linear_light_averages_black_and_white_to_a_light_gray() {
+        let image = GrayImage::from_fn(2, 1, |x, _| image::Luma([if x == 0 { 0 } else { 255 }]));
+        assert_eq!(IntegralImage::new(&image).mean(0, 0, 2, 1), 127);
+        assert_eq!(IntegralImage::linear(&image).mean(0, 0, 2, 1), 188);
+    }
+}
@@ -0,0 +1,318 @@
+// Rendering with the dice you actually have. Loose dice can show any face, so all that
+// matters is how many there are; pre-glued panels come with a fixed count of each face.
+// With per-face counts the faces are handed out to keep the total tonal error as low
+// as possible, instead of every cell taking whatever its threshold says.
+//
+// The cost of a face for a cell is how far the cell's level falls outside that face's
+// band, squared, so with enough of everything the result is the plain threshold one.
+// That cost only grows as levels and faces move apart, so some optimal assignment is
+// monotone: sort the cells by level and the faces go out in runs, darkest first.
+// Picking the run lengths is a small dynamic program, exact and linear after the sort.
+
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use crate::dicelib::{DiceSides, Thresholds};
+use crate::dither::diffuse_by;
+use crate::layout::Cell;
+use crate::render::Placement;
+
+/// How faces are handed out when the counts bind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Solver {
+    /// Least total error over the whole mosaic. Cells don't look at their neighbours.
+    #[default]
+    Exact,
+    /// Floyd-Steinberg in reading order, each cell taking the nearest face still in
+    /// stock. The error from a forced face spreads to the next cells, but the faces
+    /// that run out run out for the bottom of the mosaic.
+    Diffuse,
+}
+
+impl Solver {
+    pub fn parse(s: &str) -> Option<Solver> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "exact" | "optimal" => Some(Solver::Exact),
+            "diffuse" | "greedy" => Some(Solver::Diffuse),
+            _ => None,
+        }
+    }
+}
+
+/// The `inventory` setting.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Inventory {
+    /// Dice on hand, any of which can show any face.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dice: Option<u32>,
+    /// Most dice showing each face, one to six pips.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub faces: Option<[u32; 6]>,
+    pub solver: Solver,
+}
+
+impl Inventory {
+    /// A total like `3000`, or six per-face counts like `500,480,520,500,500,500`.
+    pub fn parse(s: &str) -> Result<Inventory, String> {
+        let counts = s
+            .split(',')
+            .map(|c| c.trim().parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| format!("Expected a dice count or six face counts, got {:?}", s))?;
+        match counts[..] {
+            [dice] => Ok(Inventory {
+                dice: Some(dice),
+                ..Inventory::default()
+            }),
+            [a, b, c, d, e, f] => Ok(Inventory {
+                faces: Some([a, b, c, d, e, f]),
+                ..Inventory::default()
+            }),
+            _ => Err(format!("Expected a dice count or six face counts, got {:?}", s)),
+        }
+    }
+
+    /// How many dice of each face there are, the total cap spread over all six when
+    /// only that is known.
+    fn stock(&self) -> [u32; 6] {
+        self.faces.unwrap_or([self.dice.unwrap_or(u32::MAX); 6])
+    }
+
+    /// Dice missing for a mosaic of `needed`, of which `taken` are set aside per face.
+    pub fn short(&self, needed: u32, taken: [u32; 6]) -> u32 {
+        let stock = self.stock();
+        let over: u32 = (0..6).map(|k| taken[k].saturating_sub(stock[k])).sum();
+        let total = match self.faces {
+            Some(faces) => faces.iter().sum::<u32>().min(self.dice.unwrap_or(u32::MAX)),
+            None => self.dice.unwrap_or(u32::MAX),
+        };
+        needed.saturating_sub(total).max(over)
+    }
+
+    /// Faces for `levels` (one per cell) within the stock, less the `taken` counts.
+    /// There have to be enough dice, see `short`.
+    pub fn assign(&self, levels: Vec<f32>, cells: &[Cell], thresholds: &Thresholds, taken: [u32; 6]) -> Vec<Placement> {
+        let stock = self.stock();
+        let left: [usize; 6] = std::array::from_fn(|k| stock[k].saturating_sub(taken[k]) as usize);
+        let sides = match self.solver {
+            Solver::Exact => exact(&levels, thresholds, left),
+            Solver::Diffuse => greedy(levels, cells, thresholds, left),
+        };
+        cells
+            .iter()
+            .zip(sides)
+            .map(|(cell, side)| Placement { cell: *cell, side })
+            .collect()
+    }
+}
+
+/// How far `level` is outside face `k`'s band, squared.
+fn cost(level: f32, k: usize, bounds: &[u8; 5]) -> f64 {
+    let lo = if k == 0 { 0.0 } else { bounds[k - 1] as f32 + 0.5 };
+    let hi = if k == 5 { 255.0 } else { bounds[k] as f32 + 0.5 };
+    let off = (lo - level).max(level - hi).max(0.0) as f64;
+    off * off
+}
+
+/// The monotone assignment. After face `k`, `best[i]` is the least cost of giving the
+/// `i` darkest cells faces up to `k`; face `k` takes a run of at most `left[k]` ending
+/// at `i`, and the best start for every `i` comes off a sliding-window minimum.
+fn exact(levels: &[f32], thresholds: &Thresholds, left: [usize; 6]) -> Vec<DiceSides> {
+    let n = levels.len();
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|&a, &b| levels[a].total_cmp(&levels[b]));
+
+    let mut best = vec![0.0; n + 1];
+    best[1..].fill(f64::INFINITY);
+    let mut starts = vec![vec![0usize; n + 1]; 6];
+    for k in 0..6 {
+        // prefix[i]: cost of the i darkest cells all showing face k
+        let mut prefix = vec![0.0; n + 1];
+        for (i, &c) in order.iter().enumerate() {
+            prefix[i + 1] = prefix[i] + cost(levels[c], k, &thresholds.0);
+        }
+        let mut next = vec![f64::INFINITY; n + 1];
+        let mut window: VecDeque<usize> = VecDeque::new();
+        for i in 0..=n {
+            let key = |j: usize| best[j] - prefix[j];
+            while window.back().is_some_and(|&j| key(j) >= key(i)) {
+                window.pop_back();
+            }
+            window.push_back(i);
+            while window.front().is_some_and(|&j| i - j > left[k]) {
+                window.pop_front();
+            }
+            let j = window[0];
+            next[i] = key(j) + prefix[i];
+            starts[k][i] = j;
+        }
+        best = next;
+    }
+
+    let mut sides = vec![DiceSides::One; n];
+    let mut end = n;
+    for k in (0..6).rev() {
+        let start = starts[k][end];
+        for &c in &order[start..end] {
+            sides[c] = DiceSides::ALL[k];
+        }
+        end = start;
+    }
+    sides
+}
+
+fn greedy(levels: Vec<f32>, cells: &[Cell], thresholds: &Thresholds, mut left: [usize; 6]) -> Vec<DiceSides> {
+    diffuse_by(levels, cells, |level| {
+        let k = (0..6)
+            .filter(|&k| left[k] > 0)
+            .min_by(|&a, &b| cost(level, a, &thresholds.0).total_cmp(&cost(level, b, &thresholds.0)))
+            .unwrap_or(0);
+        left[k] = left[k].saturating_sub(1);
+        let side = DiceSides::ALL[k];
+        (side, thresholds.level(side) as f32)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dicelib::IntensityPreset;
+
+    /// Small deterministic numbers, enough to make up cases.
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (self.0 >> 33) % n
+        }
+    }
+
+    fn total_cost(levels: &[f32], sides: &[DiceSides], thresholds: &Thresholds) -> f64 {
+        levels.iter().zip(sides).map(|(&l, s)| cost(l, s.index(), &thresholds.0)).sum()
+    }
+
+    /// Every way of giving `levels` faces within `left`, the cheapest one's cost.
+    fn brute_force(levels: &[f32], thresholds: &Thresholds, left: [usize; 6]) -> f64 {
+        fn go(i: usize, levels: &[f32], thresholds: &Thresholds, left: &mut [usize; 6]) -> f64 {
+            if i == levels.len() {
+                return 0.0;
+            }
+            let mut best = f64::INFINITY;
+            for k in 0..6 {
+                if left[k] > 0 {
+                    left[k] -= 1;
+                    best = best.min(cost(levels[i], k, &thresholds.0) + go(i + 1, levels, thresholds, left));
+                    left[k] += 1;
+                }
+            }
+            best
+        }
+        go(0, levels, thresholds, &mut left.clone())
+    }
+
+    #[test]
+    fn exact_matches_brute_force() {
+        let thresholds = Thresholds::from_preset(&IntensityPreset::Default);
+        let mut rng = Lcg(7);
+        for _ in 0..300 {
+            let n = 1 + rng.below(6) as usize;
+            let levels: Vec<f32> = (0..n).map(|_| rng.below(2560) as f32 / 10.0).collect();
+            // Enough dice in all, but not necessarily of the right faces
+            let mut left = [0usize; 6];
+            for _ in 0..n + rng.below(3) as usize {
+                left[rng.below(6) as usize] += 1;
+            }
+
+            let sides = exact(&levels, &thresholds, left);
+            for k in 0..6 {
+                assert!(sides.iter().filter(|s| s.index() == k).count() <= left[k], "{:?} {:?}", levels, left);
+            }
+            let got = total_cost(&levels, &sides, &thresholds);
+            let want = brute_force(&levels, &thresholds, left);
+            assert!((got - want).abs() < 1e-6, "{:?} {:?}: {} vs {}", levels, left, got, want);
+        }
+    }
+
+    #[test]
+    fn exact_with_plenty_is_the_threshold_result() {
+        let thresholds = Thresholds::from_preset(&IntensityPreset::HighContrast);
+        let levels: Vec<f32> = (0..=255).map(|l| l as f32).collect();
+        let sides = exact(&levels, &thresholds, [levels.len(); 6]);
+        for (&level, side) in levels.iter().zip(sides) {
+            assert_eq!(side, thresholds.side_for(level as u8), "level {}", level);
+        }
+    }
+
+    #[test]
+    fn greedy_stays_in_stock() {
+        let thresholds = Thresholds::from_preset(&IntensityPreset::Default);
+        let cells: Vec<Cell> = (0..12)
+            .map(|i| Cell {
+                col: i % 4,
+                row: i / 4,
+                x: i % 4 * 8,
+                y: i / 4 * 8,
+                w: 8,
+                h: 8,
+            })
+            .collect();
+        let sides = greedy(vec![250.0; 12], &cells, &thresholds, [4, 4, 4, 0, 0, 0]);
+        for k in 0..6 {
+            assert!(sides.iter().filter(|s| s.index() == k).count() <= [4, 4, 4, 0, 0, 0][k]);
+        }
+    }
+
+    #[test]
+    fn short_counts_the_total_and_each_face() {
+        let total = Inventory::parse("100").unwrap();
+        assert_eq!(total.short(80, [0; 6]), 0);
+        assert_eq!(total.short(120, [0; 6]), 20);
+        // Set-aside dice come out of the same total
+        assert_eq!(total.short(120, [0, 0, 0, 0, 0, 30]), 20);
+
+        let faces = Inventory::parse("10,10,10,10,10,10").unwrap();
+        assert_eq!(faces.short(60, [0; 6]), 0);
+        assert_eq!(faces.short(70, [0; 6]), 10);
+        // 15 fill dice showing six pips, and only 10 of those
+        assert_eq!(faces.short(40, [0, 0, 0, 0, 0, 15]), 5);
+
+        let both = Inventory {
+            dice: Some(30),
+            ..faces.clone()
+        };
+        assert_eq!(both.short(40, [0; 6]), 10);
+    }
+
+    #[test]
+    fn assign_leaves_the_taken_dice_alone() {
+        let thresholds = Thresholds::from_preset(&IntensityPreset::Default);
+        let inventory = Inventory::parse("3,3,3,3,3,3").unwrap();
+        let cells: Vec<Cell> = (0..6)
+            .map(|i| Cell {
+                col: i,
+                row: 0,
+                x: i * 8,
+                y: 0,
+                w: 8,
+                h: 8,
+            })
+            .collect();
+        let taken = [0, 0, 0, 0, 0, 3];
+        assert_eq!(inventory.short(cells.len() as u32 + 3, taken), 0);
+        let placements = inventory.assign(vec![255.0; 6], &cells, &thresholds, taken);
+        assert!(placements.iter().all(|p| p.side != DiceSides::Six));
+        assert_eq!(placements.iter().filter(|p| p.side == DiceSides::Five).count(), 3);
+    }
+
+    #[test]
+    fn parse_takes_a_total_or_six_faces() {
+        assert_eq!(Inventory::parse(" 3000 ").unwrap().dice, Some(3000));
+        assert_eq!(Inventory::parse("1,2,3,4,5,6").unwrap().faces, Some([1, 2, 3, 4, 5, 6]));
+        assert!(Inventory::parse("1,2,3").is_err());
+        assert!(Inventory::parse("lots").is_err());
+    }
+}
@@ -0,0 +1,276 @@
+// Job files: a whole render written down, so it can be rerun and reviewed instead of
+// living in someone's answers to the prompts. TOML or JSON, picked by extension:
+//
+//     input = "photos/cat.jpg"
+//     dice_dir = "dice"
+//
+//     [render]
+//     dice_size = 16
+//     preset = "high-contrast"
+//     dither = "floyd-steinberg"
+//     fit = "center"
+//
+//     [physical]
+//     die_mm = 16
+//
+//     [[outputs]]
+//     path = "output/cat.png"
+//     [[outputs]]
+//     path = "output/cat.pdf"
+//
+// Relative paths are relative to the job file, not to wherever it's run from.
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cache::TileCache;
+use crate::dicelib::try_load_input;
+use crate::export::{write_csv, write_json, write_pdf, write_svg, Bom, Grid, OutputFormat, Physical};
+use crate::mask::{Key, Subject};
+use crate::pipeline::{self, Mosaic, Settings};
+use crate::preprocess::Op;
+use crate::text::{write_html, write_text, TextArt};
+
+/// Where the mosaic goes when a job doesn't say.
+pub const DEFAULT_OUTPUT: &str = "output/dice_output.png";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Job {
+    pub input: PathBuf,
+    pub dice_dir: PathBuf,
+    #[serde(default)]
+    pub render: Settings,
+    #[serde(default)]
+    pub physical: Option<Physical>,
+    #[serde(default)]
+    pub outputs: Vec<OutputSpec>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutputSpec {
+    pub path: PathBuf,
+    /// Defaults to the path's extension.
+    #[serde(default)]
+    pub format: Option<OutputFormat>,
+}
+
+impl OutputSpec {
+    pub fn new(path: impl Into<PathBuf>) -> OutputSpec {
+        OutputSpec {
+            path: path.into(),
+            format: None,
+        }
+    }
+
+    pub fn format(&self) -> Result<OutputFormat, String> {
+        self.format
+            .or_else(|| OutputFormat::from_path(&self.path))
+            .ok_or_else(|| format!("Can't tell the format of {:?}, give it an extension or a format", self.path))
+    }
+}
+
+impl Job {
+    /// Reads a `.toml` or `.json` job and resolves its paths against the file's directory.
+    pub fn load(path: &Path) -> Result<Job, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("Couldn't read job file {:?}: {}", path, e))?;
+        let is_json = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+        let parsed = if is_json {
+            serde_json::from_str(&text).map_err(|e| e.to_string())
+        } else {
+            toml::from_str(&text).map_err(|e| e.to_string())
+        };
+        let mut job: Job = match parsed {
+            Ok(job) => job,
+            Err(err) => upgrade(&text, is_json).ok_or_else(|| format!("Bad job file {:?}: {}", path, err))?,
+        };
+        job.render.check().map_err(|e| format!("Bad job file {:?}: {}", path, e))?;
+
+        let base = path.parent().unwrap_or(Path::new(""));
+        job.input = base.join(&job.input);
+        job.dice_dir = base.join(&job.dice_dir);
+        for output in &mut job.outputs {
+            output.path = base.join(&output.path);
+        }
+        if let Some(Subject { key: Key::Image { path, .. }, .. }) = &mut job.render.mask {
+            *path = base.join(&*path);
+        }
+        Ok(job)
+    }
+
+    /// The job as TOML, e.g. to save what the CLI flags added up to.
+    pub fn to_toml(&self) -> Result<String, String> {
+        toml::to_string_pretty(self).map_err(|e| format!("Couldn't write job as TOML: {}", e))
+    }
+
+    /// Outputs to write, falling back to `DEFAULT_OUTPUT`.
+    pub fn outputs(&self) -> Vec<OutputSpec> {
+        if self.outputs.is_empty() {
+            vec![OutputSpec::new(DEFAULT_OUTPUT)]
+        } else {
+            self.outputs.clone()
+        }
+    }
+
+    /// Renders the job and writes every output. Raster outputs share one render.
+    pub fn run(&self, cache: Option<&TileCache>) -> Result<Mosaic, String> {
+        let outputs = self.outputs();
+        let formats = outputs.iter().map(OutputSpec::format).collect::<Result<Vec<_>, _>>()?;
+
+        let settings = &self.render;
+        let (image, alpha) = try_load_input(&self.input.to_string_lossy(), settings)?;
+        let input = pipeline::prepare_input(image, settings);
+        let alpha = alpha.map(|a| pipeline::prepare_alpha(a, settings));
+        let mut mosaic = pipeline::plan_masked(&input, alpha.as_ref(), settings);
+        mosaic.source = self.input.file_name().map(|n| n.to_string_lossy().into_owned());
+        mosaic.check()?;
+
+        // Only load and paint tiles if some output needs pixels
+        let raster = if formats.iter().any(OutputFormat::is_raster) {
+            let tiles = pipeline::load_tiles(&self.dice_dir.to_string_lossy(), settings, cache)?;
+            Some(pipeline::render(&mosaic, &tiles, settings))
+        } else {
+            None
+        };
+        let text = formats
+            .iter()
+            .any(OutputFormat::is_text)
+            .then(|| TextArt::new(&input, alpha.as_ref(), settings));
+
+        for (output, format) in outputs.iter().zip(formats) {
+            let path = output.path.as_path();
+            match format {
+                OutputFormat::Png | OutputFormat::Tif => {
+                    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+                        std::fs::create_dir_all(dir).map_err(|e| format!("Failed to create output directory: {}", e))?;
+                    }
+                    let image = raster.as_ref().expect("rendered above for raster outputs");
+                    let format = match format {
+                        OutputFormat::Png => image::ImageFormat::Png,
+                        _ => image::ImageFormat::Tiff,
+                    };
+                    image
+                        .save_with_format(path, format)
+                        .map_err(|e| format!("Error saving {:?}: {}", path, e))?;
+                }
+                OutputFormat::Svg => write_svg(path, &mosaic, settings, self.physical.as_ref())?,
+                OutputFormat::Pdf => write_pdf(path, &mosaic, settings, self.physical.as_ref())?,
+                OutputFormat::Csv => write_csv(path, &mosaic.placements)?,
+                OutputFormat::Json => write_json(path, &Grid::new(&mosaic))?,
+                OutputFormat::Bom => write_json(path, &Bom::new(&mosaic, settings, self.physical.as_ref()))?,
+                OutputFormat::Txt | OutputFormat::Html => {
+                    let art = text.as_ref().expect("laid out above for text outputs");
+                    match format {
+                        OutputFormat::Txt => write_text(path, art)?,
+                        _ => write_html(path, art, settings)?,
+                    }
+                }
+            }
+        }
+        Ok(mosaic)
+    }
+}
+
+/// Reads a job saved before `[render]` had its tone steps, when `gamma` was a setting of
+/// its own. It becomes a `gamma` step at the front of `preprocess`, which does the same.
+/// `None` if that isn't what was wrong with the file.
+fn upgrade(text: &str, is_json: bool) -> Option<Job> {
+    let mut value: serde_json::Value = if is_json {
+        serde_json::from_str(text).ok()?
+    } else {
+        toml::from_str(text).ok()?
+    };
+    let render = value.get_mut("render")?.as_object_mut()?;
+    let gamma = render.remove("gamma")?.as_f64()?;
+    // The old setting skipped anything that wasn't above 0
+    if gamma > 0.0 && gamma != 1.0 {
+        let step = serde_json::to_value(Op::Gamma { gamma }).ok()?;
+        let steps = render.entry("preprocess").or_insert_with(|| serde_json::Value::Array(Vec::new()));
+        steps.as_array_mut()?.insert(0, step);
+    }
+    serde_json::from_value(value).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `text` to a job file of its own and loads it.
+    fn load(name: &str, text: &str) -> Result<Job, String> {
+        let dir = std::env::temp_dir().join(format!("dice-job-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, text).unwrap();
+        let job = Job::load(&path);
+        let _ = std::fs::remove_file(&path);
+        job
+    }
+
+    const HEAD: &str = "input = \"cat.jpg\"\ndice_dir = \"dice\"\n";
+
+    #[test]
+    fn paths_are_relative_to_the_job_file() {
+        let job = load("paths.toml", &format!("{}[render]\nmask = {{ key = \"image\", path = \"m.png\" }}\n", HEAD)).unwrap();
+        let base = std::env::temp_dir().join(format!("dice-job-test-{}", std::process::id()));
+        assert_eq!(job.input, base.join("cat.jpg"));
+        assert_eq!(job.dice_dir, base.join("dice"));
+        match job.render.mask.map(|m| m.key) {
+            Some(Key::Image { path, .. }) => assert_eq!(path, base.join("m.png")),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn settings_are_checked_on_load() {
+        let bad = [
+            "preprocess = [{ op = \"unsharp\", radius = 0.0 }]",
+            "preprocess = [{ op = \"levels\", black = 200, white = 100 }]",
+            "preprocess = [{ op = \"clahe\", tile = 4 }]",
+            "preprocess = [{ op = \"edge-boost\", amount = 2.0 }]",
+            "quadtree = { min_size = 8, max_size = 63, split_variance = 400 }",
+            "quadtree = { min_size = 8, max_size = 64, split_variance = nan }",
+            "anneal = { seconds = inf }",
+            "anneal = { blur = 0.0 }",
+            "ramp = \"x\"",
+            "alpha = { mode = \"empty\", coverage = 2.0 }",
+        ];
+        for line in bad {
+            let err = load("bad.toml", &format!("{}[render]\n{}\n", HEAD, line)).unwrap_err();
+            assert!(err.starts_with("Bad job file"), "{}: {}", line, err);
+        }
+        let good = "quadtree = { min_size = 8, max_size = 64, split_variance = 400 }\npreprocess = [{ op = \"unsharp\" }]";
+        assert!(load("good.toml", &format!("{}[render]\n{}\n", HEAD, good)).is_ok());
+    }
+
+    #[test]
+    fn json_jobs_are_checked_too() {
+        let text = r#"{"input": "cat.jpg", "dice_dir": "dice", "render": {"preprocess": [{"op": "gamma", "gamma": -1}]}}"#;
+        assert!(load("bad.json", text).is_err());
+    }
+
+    #[test]
+    fn unknown_settings_are_errors() {
+        let err = load("typo.toml", &format!("{}[render]\ndice_sise = 16\n", HEAD)).unwrap_err();
+        assert!(err.contains("dice_sise"), "{}", err);
+    }
+
+    #[test]
+    fn old_gamma_becomes_a_step() {
+        let job = load("gamma.toml", &format!("{}[render]\ngamma = 1.5\npreprocess = [{{ op = \"equalize\" }}]\n", HEAD)).unwrap();
+        assert_eq!(job.render.preprocess, vec![Op::Gamma { gamma: 1.5 }, Op::Equalize]);
+        let job = load("gamma.toml", &format!("{}[render]\ngamma = 1.0\n", HEAD)).unwrap();
+        assert!(job.render.preprocess.is_empty());
+    }
+
+    #[test]
+    fn saved_jobs_load_again() {
+        let job = load("saved.toml", &format!("{}[render]\ndice_size = 12\npreprocess = [{{ op = \"gamma\", gamma = 1.2 }}]\n", HEAD)).unwrap();
+        let again = load("saved.toml", &job.to_toml().unwrap()).unwrap();
+        assert_eq!(again.render.dice_size, 12);
+        assert_eq!(again.render.preprocess, job.render.preprocess);
+    }
+}
@@ -0,0 +1,211 @@
+// Where the dice go. The same cell is used to sample the source block and to
+// paste the die on the output, so whatever shape the layout has, the art lines up.
+
+use serde::{Deserialize, Serialize};
+
+/// How rows of dice are arranged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LayoutKind {
+    /// Plain rectangular grid. The classic.
+    Grid,
+    /// Running bond: every other row is shifted by half a die, like bricks.
+    Brick,
+    /// Hexagonal packing: half-die shift plus rows squeezed to sqrt(3)/2 pitch.
+    /// Meant for round tiles (buttons, coins) so they nest into each other.
+    Hex,
+}
+
+impl LayoutKind {
+    pub fn parse(s: &str) -> Option<LayoutKind> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "grid" | "rect" => Some(LayoutKind::Grid),
+            "brick" | "offset" => Some(LayoutKind::Brick),
+            "hex" | "hexagonal" => Some(LayoutKind::Hex),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LayoutKind::Grid => "grid",
+            LayoutKind::Brick => "brick",
+            LayoutKind::Hex => "hex",
+        }
+    }
+}
+
+/// One spot for one die. `x`/`y` are pixel coordinates, valid for both the
+/// source image and the output canvas.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cell {
+    pub col: u32,
+    pub row: u32,
+    pub x: u32,
+    pub y: u32,
+    pub w: u32,
+    pub h: u32,
+}
+
+/// A layout fitted to an area. Build it once, then walk `cells()`.
+#[derive(Debug, Clone, Copy)]
+pub struct Layout {
+    pub kind: LayoutKind,
+    pub cell_w: u32,
+    pub cell_h: u32,
+    pub rows: u32,
+    cols_even: u32,
+    cols_odd: u32,
+}
+
+impl Layout {
+    /// Fits as many whole cells of `cell` size as possible into `area`.
+    pub fn new(kind: LayoutKind, area: (u32, u32), cell: (u32, u32)) -> Layout {
+        let (area_w, area_h) = area;
+        let (cell_w, cell_h) = (cell.0.max(1), cell.1.max(1));
+
+        let mut layout = Layout {
+            kind,
+            cell_w,
+            cell_h,
+            rows: 0,
+            cols_even: area_w / cell_w,
+            cols_odd: 0,
+        };
+
+        // Rows: the last row still needs its full height inside the area
+        layout.rows = if area_h < cell_h {
+            0
+        } else {
+            (area_h - cell_h) / layout.row_pitch() + 1
+        };
+
+        // Shifted rows lose whatever doesn't fit after the half-die offset
+        layout.cols_odd = match kind {
+            LayoutKind::Grid => layout.cols_even,
+            LayoutKind::Brick | LayoutKind::Hex => area_w.saturating_sub(cell_w / 2) / cell_w,
+        };
+
+        layout
+    }
+
+    /// Vertical distance between the tops of two neighbouring rows.
+    pub fn row_pitch(&self) -> u32 {
+        match self.kind {
+            LayoutKind::Grid | LayoutKind::Brick => self.cell_h,
+            // sqrt(3)/2, never less than a pixel
+            LayoutKind::Hex => ((self.cell_h as f64 * 0.866_025_403_784_438_6).round() as u32).max(1),
+        }
+    }
+
+    /// Horizontal shift of a row.
+    pub fn row_offset(&self, row: u32) -> u32 {
+        match self.kind {
+            LayoutKind::Grid => 0,
+            LayoutKind::Brick | LayoutKind::Hex => (row % 2) * (self.cell_w / 2),
+        }
+    }
+
+    pub fn cols_in_row(&self, row: u32) -> u32 {
+        if row.is_multiple_of(2) { self.cols_even } else { self.cols_odd }
+    }
+
+    /// Every cell, row by row, left to right.
+    pub fn cells(&self) -> Vec<Cell> {
+        let mut cells = Vec::with_capacity(self.len());
+        for row in 0..self.rows {
+            let offset = self.row_offset(row);
+            let y = row * self.row_pitch();
+            for col in 0..self.cols_in_row(row) {
+                cells.push(Cell {
+                    col,
+                    row,
+                    x: offset + col * self.cell_w,
+                    y,
+                    w: self.cell_w,
+                    h: self.cell_h,
+                });
+            }
+        }
+        cells
+    }
+
+    /// Number of dice in the layout.
+    pub fn len(&self) -> usize {
+        (0..self.rows).map(|row| self.cols_in_row(row) as usize).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Smallest canvas holding every cell.
+    pub fn canvas_size(&self) -> (u32, u32) {
+        if self.rows == 0 {
+            return (0, 0);
+        }
+        let even_w = self.cols_even * self.cell_w;
+        let odd_w = if self.rows > 1 {
+            self.row_offset(1) + self.cols_odd * self.cell_w
+        } else {
+            0
+        };
+        let h = (self.rows - 1) * self.row_pitch() + self.cell_h;
+        (even_w.max(odd_w), h)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn positions(layout: &Layout) -> Vec<(u32, u32, u32, u32)> {
+        layout.cells().iter().map(|c| (c.col, c.row, c.x, c.y)).collect()
+    }
+
+    #[test]
+    fn grid_cells_fill_whole_dice_only() {
+        let layout = Layout::new(LayoutKind::Grid, (20, 17), (8, 8));
+        assert_eq!(positions(&layout), vec![(0, 0, 0, 0), (1, 0, 8, 0), (0, 1, 0, 8), (1, 1, 8, 8)]);
+        assert_eq!(layout.canvas_size(), (16, 16));
+    }
+
+    #[test]
+    fn brick_shifts_every_other_row_by_half_a_die() {
+        let layout = Layout::new(LayoutKind::Brick, (20, 24), (8, 8));
+        assert_eq!(
+            positions(&layout),
+            vec![(0, 0, 0, 0), (1, 0, 8, 0), (0, 1, 4, 8), (1, 1, 12, 8), (0, 2, 0, 16), (1, 2, 8, 16)]
+        );
+        assert_eq!(layout.canvas_size(), (20, 24));
+    }
+
+    #[test]
+    fn brick_drops_a_shifted_die_that_would_stick_out() {
+        let layout = Layout::new(LayoutKind::Brick, (16, 16), (8, 8));
+        assert_eq!(positions(&layout), vec![(0, 0, 0, 0), (1, 0, 8, 0), (0, 1, 4, 8)]);
+        assert_eq!(layout.canvas_size(), (16, 16));
+    }
+
+    #[test]
+    fn hex_rows_are_closer_together() {
+        // Row pitch 8 * sqrt(3)/2 rounds to 7
+        let layout = Layout::new(LayoutKind::Hex, (20, 22), (8, 8));
+        assert_eq!(layout.row_pitch(), 7);
+        assert_eq!(
+            positions(&layout),
+            vec![(0, 0, 0, 0), (1, 0, 8, 0), (0, 1, 4, 7), (1, 1, 12, 7), (0, 2, 0, 14), (1, 2, 8, 14)]
+        );
+        assert_eq!(layout.canvas_size(), (20, 22));
+        assert_eq!(layout.len(), 6);
+    }
+
+    #[test]
+    fn too_small_an_area_has_no_cells() {
+        for kind in [LayoutKind::Grid, LayoutKind::Brick, LayoutKind::Hex] {
+            let layout = Layout::new(kind, (20, 7), (8, 8));
+            assert!(layout.is_empty());
+            assert_eq!(layout.canvas_size(), (0, 0));
+        }
+    }
+}
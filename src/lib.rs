@@ -0,0 +1,29 @@
+// Dice art as a library. The CLI in main.rs is just one way to drive it.
+pub mod alpha;
+pub mod anneal;
+pub mod batch;
+pub mod cache;
+pub mod compare;
+pub mod dicelib;
+pub mod dither;
+pub mod export;
+pub mod grayscale;
+pub mod integral;
+pub mod inventory;
+pub mod job;
+pub mod layout;
+pub mod mask;
+pub mod overlay;
+pub mod palette;
+pub mod pipeline;
+pub mod preprocess;
+pub mod preview;
+pub mod quadtree;
+pub mod render;
+pub mod rulers;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod stream;
+pub mod text;
+#[cfg(feature = "tui")]
+pub mod tui;
@@ -1,78 +1,290 @@
 // use std::env; // For grabbing command-line arguments... no need for clap really.
 use std::path::Path; // Handy for working with file paths
-use image::{imageops, GrayImage, RgbaImage}; // Just the essentials for image processing
-mod dicelib;
-use dicelib::{add_reference_text, map_intensity_to_dice_side, load_image, Dice, DiceSides, IntensityPreset};
+use image::GrayImage; // Just the essentials for image processing
+use dice::alpha::Alpha;
+use dice::anneal::Anneal;
+use dice::batch::{expand_inputs, output_paths, run_batch, BatchOptions, DEFAULT_TEMPLATE};
+use dice::cache::TileCache;
+use dice::compare::{contact_sheet, CompareOptions};
+use dice::dicelib::{load_dice_dir, try_load_input, Dice, IntensityPreset, Thresholds};
+use dice::dither::Dither;
+use dice::export::{write_csv, OutputFormat, Physical};
+use dice::grayscale::Grayscale;
+use dice::inventory::{Inventory, Solver};
+use dice::job::{Job, OutputSpec};
+use dice::layout::LayoutKind;
+use dice::mask::{Key, Subject};
+use dice::overlay::OverlayPosition;
+use dice::palette::Palette;
+use dice::pipeline::{self, Decorations, Fit, Settings};
+use dice::preprocess::Op;
+use dice::preview::{preview, terminal_columns, PreviewMode};
+use dice::rulers::RulerOptions;
+use dice::quadtree::QuadtreeOptions;
+#[cfg(feature = "server")]
+use dice::server::ServeOptions;
+#[cfg(feature = "tui")]
+use dice::tui::TuiOptions;
+use dice::text::{write_html, write_text, Ramp, TextArt};
+use dice::stream::{canvas_bytes, strip_rows_for_budget, write_streaming, DEFAULT_STRIP_BYTES};
 
 struct Images {
     input: GrayImage,
-    dice: [Dice; 6] // Six dice, one for each side. Simple and clean.
+    alpha: Option<GrayImage>, // Input's alpha, when transparent cells are left empty
+    source: Option<String>, // Input file name, for the legend
+    dice: [Dice; 6], // Six dice, one for each side. Simple and clean.
+    settings: Settings, // Everything the prompts and flags decided
+    tile_list: Option<String>, // Where to dump the list of placed tiles, if anywhere
+    output: String, // Where the mosaic goes (.png or .tif)
+    stream: bool, // Write in strips instead of building the whole canvas
+    max_memory: Option<u64>, // Canvas memory cap in bytes, streams automatically past it
+    preview: Option<PreviewMode>, // Show the grid in the terminal and confirm before writing
+    preview_width: Option<u32>, // Columns for the preview, the terminal's width if unset
 }
 
-fn load_dice_images_d(dice_dir: &str) -> [Dice; 6] {
-    // Read the dice directory and grab all the files
-    let mut dice_image_paths: Vec<_> = std::fs::read_dir(dice_dir)
-        .expect("Couldn't find the dice directory. Double-check your path!")
-        .filter_map(|entry| entry.ok())
-        .map(|entry| entry.path())
-        .filter(|path| path.is_file())
-        .collect();
-
-    // Make sure we have exactly 6 dice images. No more, no less.
-    if dice_image_paths.len() != 6 {
-        panic!("You need exactly 6 dice images. No shortcuts allowed!");
-    }
-
-    // Sort the paths to keep things consistent (alphabetical order is nice)
-    dice_image_paths.sort();
-
-    // Ask the user for the dice size
-    println!("Enter the dice size you want (e.g., 32 for 32x32 pixels):");
-    let mut d_size_input = String::new();
-    std::io::stdin().read_line(&mut d_size_input).unwrap();
-    let d_size: u32 = match d_size_input.trim_end().parse() {
-        Ok(size) if size > 0 => size,
-        _ => {
-            println!("Invalid size. Defaulting to 32x32.");
-            32
-        }
-    };
+// Prints the question, hands back the trimmed answer
+fn ask(question: &str) -> String {
+    println!("{}", question);
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).unwrap();
+    answer.trim().to_string()
+}
 
-    // Load and resize the dice images
-    let mut dice_array: [Option<Dice>; 6] = Default::default();
-    for (i, image_path) in dice_image_paths.iter().enumerate() {
-        let image_data = std::fs::read(image_path)
-            .unwrap_or_else(|_| panic!("Couldn't read dice image at {:?}", image_path));
-        let image = image::load_from_memory(&image_data)
-            .expect(&format!("Couldn't load dice image {:?}", image_path));
-
-        // Resize the dice image to the user-specified size
-        let resized_image = image.resize_exact(d_size, d_size, imageops::FilterType::Lanczos3);
-
-        dice_array[i] = Some(Dice {
-            side: match i {
-                0 => DiceSides::One,
-                1 => DiceSides::Two,
-                2 => DiceSides::Three,
-                3 => DiceSides::Four,
-                4 => DiceSides::Five,
-                5 => DiceSides::Six,
-                _ => unreachable!(), // This should never happen. If it does, something's seriously wrong.
-            },
-            image: resized_image,
-        });
-    }
+// Flags shared by the interactive mode and batch mode
+fn render_args() -> Vec<clap::Arg> {
+    vec![
+        clap::Arg::new("layout")
+            .short('l')
+            .long("layout")
+            .value_name("LAYOUT")
+            .help("How to arrange the dice: grid, brick (offset rows) or hex (round tiles)")
+            .value_parser(["grid", "brick", "hex"])
+            .default_value("grid")
+            .num_args(1),
+        clap::Arg::new("quadtree")
+            .short('q')
+            .long("quadtree")
+            .help("Variable-size dice: big ones in flat areas, small ones where there's detail")
+            .action(clap::ArgAction::SetTrue)
+            .conflicts_with("layout"),
+        clap::Arg::new("min_tile")
+            .long("min-tile")
+            .value_name("PIXELS")
+            .help("Smallest die in quadtree mode")
+            .value_parser(clap::value_parser!(u32).range(1..))
+            .default_value("8")
+            .requires("quadtree"),
+        clap::Arg::new("max_tile")
+            .long("max-tile")
+            .value_name("PIXELS")
+            .help("Largest die in quadtree mode: --min-tile doubled some number of times")
+            .value_parser(clap::value_parser!(u32).range(1..))
+            .default_value("64")
+            .requires("quadtree"),
+        clap::Arg::new("split_variance")
+            .long("split-variance")
+            .value_name("VARIANCE")
+            .help("Quadtree splits a block when its luma variance is above this")
+            .value_parser(clap::value_parser!(f64))
+            .default_value("400")
+            .requires("quadtree"),
+        clap::Arg::new("fit")
+            .long("fit")
+            .value_name("FIT")
+            .help("Crop before dicing: square (from the top-left), center (square from the middle) or full (no crop)")
+            .value_parser(["square", "center", "full"])
+            .default_value("square"),
+        clap::Arg::new("thresholds")
+            .long("thresholds")
+            .value_name("T1,T2,T3,T4,T5")
+            .help("Custom upper intensity bounds of faces 1-5, replacing the preset's, e.g. 40,90,140,190,230")
+            .value_parser(Thresholds::parse),
+        clap::Arg::new("dither")
+            .long("dither")
+            .value_name("MODE")
+            .help("Spread each die's rounding error onto its neighbours: none or floyd-steinberg")
+            .value_parser(["none", "floyd-steinberg"])
+            .default_value("none"),
+        clap::Arg::new("inventory")
+            .long("inventory")
+            .value_name("COUNTS")
+            .help("Dice on hand: a total, or six counts (one to six pips) to hand the faces out within")
+            .value_parser(Inventory::parse),
+        clap::Arg::new("solver")
+            .long("solver")
+            .value_name("SOLVER")
+            .help("How --inventory counts are met: exact (least total error) or diffuse (greedy, with error diffusion)")
+            .value_parser(["exact", "diffuse"]),
+        clap::Arg::new("anneal")
+            .long("anneal")
+            .help("Refine the faces as a whole (simulated annealing) so the mosaic matches the input when both are blurred, as seen from afar")
+            .action(clap::ArgAction::SetTrue),
+        clap::Arg::new("anneal_iterations")
+            .long("anneal-iterations")
+            .value_name("MOVES")
+            .help("Moves --anneal tries (default 500 per die)")
+            .value_parser(clap::value_parser!(u64))
+            .requires("anneal"),
+        clap::Arg::new("anneal_seed")
+            .long("anneal-seed")
+            .value_name("SEED")
+            .help("Random seed for --anneal; the same seed gives the same mosaic")
+            .value_parser(clap::value_parser!(u64))
+            .requires("anneal"),
+        clap::Arg::new("anneal_seconds")
+            .long("anneal-seconds")
+            .value_name("SECONDS")
+            .help("Time budget for --anneal, stops early when it runs out")
+            .value_parser(clap::value_parser!(f64))
+            .requires("anneal"),
+        clap::Arg::new("anneal_blur")
+            .long("anneal-blur")
+            .value_name("DICE")
+            .help("Viewing blur for --anneal, in dice (default 1); bigger is farther away")
+            .value_parser(clap::value_parser!(f32))
+            .requires("anneal"),
+        clap::Arg::new("palette")
+            .long("palette")
+            .value_name("DARK,LIGHT")
+            .help("Recolor the dice, DARK for the pips and LIGHT for the body, e.g. #8b0000,#fffff0")
+            .value_parser(Palette::parse),
+        clap::Arg::new("overlay")
+            .long("overlay")
+            .value_name("POSITION")
+            .help("Where the debug text goes: a corner, or footer (a band added under the art)")
+            .value_parser(["top-left", "top-right", "bottom-left", "bottom-right", "footer"])
+            .default_value("top-left"),
+        clap::Arg::new("legend")
+            .long("legend")
+            .help("Add a legend (face counts, thresholds, source file) to the debug text; turns the text on")
+            .action(clap::ArgAction::SetTrue),
+        clap::Arg::new("font_size")
+            .long("font-size")
+            .value_name("PIXELS")
+            .help("Debug text size (defaults to scaling with the image)")
+            .value_parser(clap::value_parser!(f32)),
+        clap::Arg::new("rulers")
+            .long("rulers")
+            .value_name("EVERY")
+            .help("Number rows and columns in a margin and draw a section line every EVERY dice (0 for no lines)")
+            .value_parser(clap::value_parser!(u32))
+            .num_args(0..=1)
+            .default_missing_value("10"),
+        clap::Arg::new("ramp")
+            .long("ramp")
+            .value_name("CHARS")
+            .help("Characters for .txt/.html outputs, darkest first, e.g. \"@%#*+=-:. \" (defaults to die faces)")
+            .value_parser(Ramp::parse),
+        clap::Arg::new("grayscale")
+            .long("grayscale")
+            .value_name("MODE")
+            .help("How color inputs become gray: rec709 (default), rec601, linear, lab, red, green, blue, or channel weights R,G,B")
+            .value_parser(Grayscale::parse),
+        clap::Arg::new("alpha")
+            .long("alpha")
+            .value_name("MODE")
+            .help("Transparent inputs: ignore (default), composite[=COLOR] onto a background (white), or empty[=COVERAGE] to leave cells less than COVERAGE opaque (0.5) without a die")
+            .value_parser(Alpha::parse),
+        clap::Arg::new("mask")
+            .long("mask")
+            .value_name("KEY")
+            .help("Dice on the subject only: image=PATH (white is subject), luma=VALUE[,TOL] or chroma=COLOR[,TOL] to key out a backdrop, or flood[=TOL] from the corners")
+            .value_parser(Key::parse),
+        clap::Arg::new("mask_fill")
+            .long("mask-fill")
+            .value_name("FACE")
+            .help("Face (1-6) for the cells around the subject instead of leaving them empty")
+            .value_parser(clap::value_parser!(u8).range(1..=6)),
+        clap::Arg::new("no_auto_orient")
+            .long("no-auto-orient")
+            .help("Ignore the input's EXIF orientation instead of turning it upright")
+            .action(clap::ArgAction::SetTrue),
+        clap::Arg::new("linear_light")
+            .long("linear-light")
+            .help("Average each die's area in linear light at 16 bits, so mixed light/dark areas don't come out too dark")
+            .action(clap::ArgAction::SetTrue),
+        clap::Arg::new("preprocess")
+            .long("preprocess")
+            .value_name("STEP")
+            .help("Tone step run on the input before dicing, in the order given: gamma=1.2, levels=20,235[,GAMMA], brightness=10, contrast=25, curve=0:0,96:70,255:255, equalize, clahe[=TILE,CLIP], unsharp[=RADIUS,AMOUNT,THRESHOLD], edges[=sobel|canny,AMOUNT] or preserve-edges[=THRESHOLD,STRENGTH] (repeatable, or ;-separated)")
+            .value_parser(Op::parse_list)
+            .action(clap::ArgAction::Append),
+        clap::Arg::new("cache_dir")
+            .long("cache-dir")
+            .value_name("DIRECTORY")
+            .help("Where resized tiles are cached (defaults to $DICE_CACHE_DIR or ~/.cache/dice)")
+            .num_args(1),
+        clap::Arg::new("no_cache")
+            .long("no-cache")
+            .help("Always load and resize the dice images from scratch")
+            .action(clap::ArgAction::SetTrue)
+            .conflicts_with("cache_dir"),
+    ]
+}
 
-    // Convert the Option array into a proper array. No room for None here.
-    core::array::from_fn(|i| dice_array[i].clone().expect("Failed to load dice images."))
+// Settings the interactive mode asks for, as flags for batch and run
+fn settings_args() -> Vec<clap::Arg> {
+    vec![
+        clap::Arg::new("dice_size")
+            .short('s')
+            .long("dice-size")
+            .value_name("PIXELS")
+            .help("Dice size in pixels")
+            .value_parser(clap::value_parser!(u32).range(1..))
+            .default_value("32"),
+        clap::Arg::new("preset")
+            .short('p')
+            .long("preset")
+            .value_name("PRESET")
+            .help("Intensity preset")
+            .value_parser(["default", "high-contrast", "low-contrast", "bright", "dark"])
+            .default_value("default"),
+        clap::Arg::new("invert_input")
+            .long("invert-input")
+            .help("Invert the input images")
+            .action(clap::ArgAction::SetTrue),
+        clap::Arg::new("invert_dice")
+            .long("invert-dice")
+            .help("Invert the dice colors")
+            .action(clap::ArgAction::SetTrue),
+        clap::Arg::new("output_size")
+            .long("output-size")
+            .value_name("WIDTHxHEIGHT")
+            .help("Center-fit every input into this size first, e.g. 1920x1080")
+            .value_parser(parse_size),
+        clap::Arg::new("debug")
+            .long("debug")
+            .help("Add the debug info text to every output")
+            .action(clap::ArgAction::SetTrue),
+    ]
 }
 
-fn load_images_dynamic() -> Images {
+fn cli() -> clap::Command {
     // Using clap for argument parsing. Because why not?
-    let matches = clap::Command::new("Dice Image Processor")
+    let cli = clap::Command::new("Dice Image Processor")
         .version("1.0")
         .author("Your Name <your.email@example.com>")
         .about("Turns your images into dice art. Pretty cool, huh?")
+        .subcommand_negates_reqs(true)
+        .args_conflicts_with_subcommands(true)
+        .subcommand(
+            clap::Command::new("cache")
+                .about("Manage the resized tile cache")
+                .subcommand_required(true)
+                .subcommand(clap::Command::new("clear").about("Delete every cached tile"))
+                .subcommand(clap::Command::new("info").about("Show where the cache lives and how big it is"))
+                .arg(
+                    clap::Arg::new("cache_dir")
+                        .long("cache-dir")
+                        .value_name("DIRECTORY")
+                        .help("Cache directory (defaults to $DICE_CACHE_DIR or ~/.cache/dice)")
+                        .global(true)
+                        .num_args(1),
+                ),
+        )
+        .subcommand(batch_cli())
+        .subcommand(run_cli())
+        .subcommand(compare_cli())
         .arg(
             clap::Arg::new("input")
                 .short('i')
@@ -91,8 +303,808 @@ fn load_images_dynamic() -> Images {
                 .required(true)
                 .num_args(1),
         )
-        .get_matches();
+        .args(render_args())
+        .arg(
+            clap::Arg::new("tile_list")
+                .long("tile-list")
+                .value_name("CSV_FILE")
+                .help("Write every placed tile (x, y, size, face) to a CSV file")
+                .num_args(1),
+        )
+        .arg(
+            clap::Arg::new("output")
+                .short('o')
+                .long("output")
+                .value_name("OUTPUT_FILE")
+                .help("Where to save the mosaic (.png or .tif, or .txt/.html for text)")
+                .default_value("output/dice_output.png")
+                .num_args(1),
+        )
+        .arg(
+            clap::Arg::new("stream")
+                .long("stream")
+                .help("Render and write the output in strips instead of holding the whole canvas in memory")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            clap::Arg::new("max_memory")
+                .long("max-memory")
+                .value_name("MB")
+                .help("Memory cap for the output canvas; bigger outputs are streamed in strips that fit")
+                .value_parser(clap::value_parser!(u64).range(1..)),
+        )
+        .arg(
+            clap::Arg::new("preview")
+                .long("preview")
+                .value_name("MODE")
+                .help("Show the dice grid in the terminal and ask before writing: faces, blocks, sixel, kitty or auto")
+                .value_parser(["auto", "faces", "blocks", "sixel", "kitty"])
+                .num_args(0..=1)
+                .default_missing_value("auto"),
+        )
+        .arg(
+            clap::Arg::new("preview_width")
+                .long("preview-width")
+                .value_name("COLUMNS")
+                .help("Preview width in terminal columns (defaults to the terminal's width)")
+                .value_parser(clap::value_parser!(u32).range(8..)),
+        );
+    #[cfg(feature = "tui")]
+    let cli = cli.subcommand(tui_cli());
+    #[cfg(feature = "server")]
+    let cli = cli.subcommand(serve_cli());
+    cli
+}
+
+fn batch_cli() -> clap::Command {
+    clap::Command::new("batch")
+        .about("Render a whole directory or glob of images with the same dice, no questions asked")
+        .arg(
+            clap::Arg::new("inputs")
+                .value_name("INPUTS")
+                .help("Input files, directories or glob patterns (quote globs so the shell leaves them alone)")
+                .required(true)
+                .num_args(1..),
+        )
+        .arg(
+            clap::Arg::new("dice_dir")
+                .short('d')
+                .long("dice-dir")
+                .value_name("DICE_DIRECTORY")
+                .help("Path to the directory containing dice images (exactly 6 images)")
+                .required(true)
+                .num_args(1),
+        )
+        .args(settings_args())
+        .args(render_args())
+        .arg(
+            clap::Arg::new("template")
+                .short('o')
+                .long("output-template")
+                .value_name("TEMPLATE")
+                .help("Output path per image; {stem} {name} {ext} {dir} {index} {size} {preset} {layout} get filled in, and no two images may end up at the same path")
+                .default_value(DEFAULT_TEMPLATE),
+        )
+        .arg(
+            clap::Arg::new("jobs")
+                .short('j')
+                .long("jobs")
+                .value_name("N")
+                .help("Images rendered at the same time (defaults to the number of cores)")
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            clap::Arg::new("continue_on_error")
+                .short('k')
+                .long("continue-on-error")
+                .help("Keep going when an image fails instead of stopping")
+                .action(clap::ArgAction::SetTrue),
+        )
+}
+
+fn run_cli() -> clap::Command {
+    clap::Command::new("run")
+        .about("Render a job file (TOML or JSON); any flag given here overrides the file")
+        .arg(
+            clap::Arg::new("job")
+                .value_name("JOB_FILE")
+                .help("Job file, e.g. dice.toml")
+                .required(true),
+        )
+        .arg(
+            clap::Arg::new("input")
+                .short('i')
+                .long("input")
+                .value_name("INPUT_FILE")
+                .help("Input image instead of the job's"),
+        )
+        .arg(
+            clap::Arg::new("dice_dir")
+                .short('d')
+                .long("dice-dir")
+                .value_name("DICE_DIRECTORY")
+                .help("Dice directory instead of the job's"),
+        )
+        .args(settings_args())
+        .args(render_args())
+        .arg(
+            clap::Arg::new("die_mm")
+                .long("die-mm")
+                .value_name("MM")
+                .help("Physical edge length of one die, sizes the SVG/PDF outputs")
+                .value_parser(clap::value_parser!(f64)),
+        )
+        .arg(
+            clap::Arg::new("output")
+                .short('o')
+                .long("output")
+                .value_name("OUTPUT_FILE")
+                .help("Write these outputs instead of the job's (.png .tif .svg .pdf .csv .txt .html .json); repeatable")
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            clap::Arg::new("print")
+                .long("print")
+                .help("Print the job with the overrides applied, as TOML, and don't render")
+                .action(clap::ArgAction::SetTrue),
+        )
+}
+
+#[cfg(feature = "server")]
+fn serve_cli() -> clap::Command {
+    clap::Command::new("serve")
+        .about("Serve the renderer over HTTP: POST an image to /render, GET /health")
+        .arg(
+            clap::Arg::new("dice_dir")
+                .short('d')
+                .long("dice-dir")
+                .value_name("DICE_DIRECTORY")
+                .help("Path to the directory containing dice images (exactly 6 images)")
+                .required(true),
+        )
+        .arg(
+            clap::Arg::new("addr")
+                .long("addr")
+                .value_name("HOST:PORT")
+                .help("Address to listen on")
+                .default_value("127.0.0.1:8080"),
+        )
+        .arg(
+            clap::Arg::new("workers")
+                .short('j')
+                .long("workers")
+                .value_name("N")
+                .help("Renders running at once (defaults to the number of cores)")
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            clap::Arg::new("queue")
+                .long("queue")
+                .value_name("N")
+                .help("Renders allowed to wait for a worker; past that requests get a 503")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("16"),
+        )
+        .arg(
+            clap::Arg::new("max_body")
+                .long("max-body")
+                .value_name("MB")
+                .help("Biggest upload accepted")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .default_value("20"),
+        )
+        .arg(
+            clap::Arg::new("max_input")
+                .long("max-input")
+                .value_name("MEGAPIXELS")
+                .help("Biggest input image accepted")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .default_value("50"),
+        )
+        .arg(
+            clap::Arg::new("max_output")
+                .long("max-output")
+                .value_name("MEGAPIXELS")
+                .help("Biggest PNG/TIFF the server will render")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .default_value("100"),
+        )
+        .arg(
+            clap::Arg::new("max_dice")
+                .long("max-dice")
+                .value_name("N")
+                .help("Most dice in a mosaic the server will lay out, whatever the format")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .default_value("250000"),
+        )
+        .arg(
+            clap::Arg::new("cache_dir")
+                .long("cache-dir")
+                .value_name("DIRECTORY")
+                .help("Where resized tiles are cached (defaults to $DICE_CACHE_DIR or ~/.cache/dice)")
+                .num_args(1),
+        )
+        .arg(
+            clap::Arg::new("no_cache")
+                .long("no-cache")
+                .help("Always load and resize the dice images from scratch")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with("cache_dir"),
+        )
+}
+
+#[cfg(feature = "tui")]
+fn tui_cli() -> clap::Command {
+    clap::Command::new("tui")
+        .about("Tune the settings with a live preview, then save a job file and its outputs")
+        .arg(
+            clap::Arg::new("job")
+                .long("job")
+                .value_name("JOB_FILE")
+                .help("Job file to start from if it exists, and where `s` saves")
+                .default_value("dice.toml"),
+        )
+        .arg(
+            clap::Arg::new("input")
+                .short('i')
+                .long("input")
+                .value_name("INPUT_FILE")
+                .help("Input image (required unless the job file has one)"),
+        )
+        .arg(
+            clap::Arg::new("dice_dir")
+                .short('d')
+                .long("dice-dir")
+                .value_name("DICE_DIRECTORY")
+                .help("Dice directory (required unless the job file has one)"),
+        )
+        .args(settings_args())
+        .args(render_args())
+        .arg(
+            clap::Arg::new("output")
+                .short('o')
+                .long("output")
+                .value_name("OUTPUT_FILE")
+                .help("Outputs to write on save (defaults to the job's, or output/dice_output.png); repeatable")
+                .action(clap::ArgAction::Append),
+        )
+}
+
+fn compare_cli() -> clap::Command {
+    // The render flags minus the ones the sheet varies (or that don't fit a grid of thumbnails)
+    let varied = [
+        "thresholds", "dither", "quadtree", "min_tile", "max_tile", "split_variance", "overlay", "legend", "font_size", "rulers",
+        "ramp",
+    ];
+    clap::Command::new("compare")
+        .about("Render one image with several presets, dither modes and dice sizes into a labeled contact sheet")
+        .arg(
+            clap::Arg::new("input")
+                .short('i')
+                .long("input")
+                .value_name("INPUT_FILE")
+                .help("Path to the input image file")
+                .required(true),
+        )
+        .arg(
+            clap::Arg::new("dice_dir")
+                .short('d')
+                .long("dice-dir")
+                .value_name("DICE_DIRECTORY")
+                .help("Path to the directory containing dice images (exactly 6 images)")
+                .required(true),
+        )
+        .arg(
+            clap::Arg::new("presets")
+                .short('p')
+                .long("presets")
+                .value_name("PRESETS")
+                .help("Presets to compare, one column each")
+                .value_parser(["default", "high-contrast", "low-contrast", "bright", "dark"])
+                .value_delimiter(',')
+                .default_values(IntensityPreset::ALL.map(|p| p.name())),
+        )
+        .arg(
+            clap::Arg::new("dithers")
+                .long("dithers")
+                .value_name("MODES")
+                .help("Dither modes to compare")
+                .value_parser(["none", "floyd-steinberg"])
+                .value_delimiter(',')
+                .default_values(Dither::ALL.map(|d| d.name())),
+        )
+        .arg(
+            clap::Arg::new("sizes")
+                .short('s')
+                .long("sizes")
+                .value_name("PIXELS")
+                .help("Dice sizes to compare")
+                .value_parser(clap::value_parser!(u32).range(1..))
+                .value_delimiter(',')
+                .default_values(["16", "32"]),
+        )
+        .arg(
+            clap::Arg::new("thumb_width")
+                .long("thumb-width")
+                .value_name("PIXELS")
+                .help("Width of each thumbnail on the sheet")
+                .value_parser(clap::value_parser!(u32).range(16..))
+                .default_value("320"),
+        )
+        .args(settings_args().into_iter().filter(|a| ["invert_input", "invert_dice", "output_size"].contains(&a.get_id().as_str())))
+        .args(render_args().into_iter().filter(|a| !varied.contains(&a.get_id().as_str())))
+        .arg(
+            clap::Arg::new("output")
+                .short('o')
+                .long("output")
+                .value_name("OUTPUT_FILE")
+                .help("Where to save the contact sheet")
+                .default_value("output/compare.png"),
+        )
+}
+
+fn parse_size(s: &str) -> Result<(u32, u32), String> {
+    let (w, h) = s
+        .split_once(['x', 'X'])
+        .ok_or_else(|| format!("Expected WIDTHxHEIGHT, got {:?}", s))?;
+    let w: u32 = w.trim().parse().map_err(|_| format!("Bad width in {:?}", s))?;
+    let h: u32 = h.trim().parse().map_err(|_| format!("Bad height in {:?}", s))?;
+    if w == 0 || h == 0 {
+        return Err("Width and height have to be more than 0".to_string());
+    }
+    Ok((w, h))
+}
+
+fn open_cache(matches: &clap::ArgMatches) -> TileCache {
+    match matches.get_one::<String>("cache_dir") {
+        Some(dir) => TileCache::new(dir),
+        None => TileCache::new(TileCache::default_dir()),
+    }
+}
+
+fn cache_command(matches: &clap::ArgMatches) {
+    let cache = open_cache(matches);
+    match matches.subcommand() {
+        Some(("clear", _)) => match cache.clear() {
+            Ok(removed) => println!("Removed {} cached tiles from {:?}", removed, cache.dir()),
+            Err(err) => eprintln!("Error clearing tile cache: {}", err),
+        },
+        Some(("info", _)) => match cache.stats() {
+            Ok((count, bytes)) => {
+                println!("Tile cache: {:?}", cache.dir());
+                println!("{} tiles, {:.1} KB", count, bytes as f64 / 1024.0);
+            }
+            Err(err) => eprintln!("Error reading tile cache: {}", err),
+        },
+        _ => unreachable!("clap makes a cache subcommand required"),
+    }
+}
+
+// Whether a flag should be applied: always, or only when it was actually typed out,
+// so job file values survive the flag defaults. Flags a subcommand doesn't take
+// (compare leaves out the ones it varies) are never given.
+fn given(matches: &clap::ArgMatches, id: &str, only_explicit: bool) -> bool {
+    if !matches.try_contains_id(id).unwrap_or(false) {
+        false
+    } else if only_explicit {
+        matches.value_source(id) == Some(clap::parser::ValueSource::CommandLine)
+    } else {
+        true
+    }
+}
+
+// A switch, off when the subcommand doesn't have it
+fn flag(matches: &clap::ArgMatches, id: &str) -> bool {
+    matches.try_get_one::<bool>(id).ok().flatten().copied().unwrap_or(false)
+}
+
+// The flags from `render_args`, folded into settings
+fn apply_render_args(matches: &clap::ArgMatches, settings: &mut Settings, only_explicit: bool) {
+    if given(matches, "layout", only_explicit) {
+        settings.layout = matches
+            .get_one::<String>("layout")
+            .and_then(|l| LayoutKind::parse(l))
+            .unwrap_or(LayoutKind::Grid);
+        if only_explicit {
+            settings.quadtree = None;
+        }
+    }
+    if !only_explicit || flag(matches, "quadtree") {
+        let current = settings.quadtree;
+        let value = |id: &str, fallback: Option<f64>| match (given(matches, id, only_explicit), fallback) {
+            (false, Some(v)) => v,
+            _ => *matches.get_one::<f64>(id).unwrap(),
+        };
+        let size = |id: &str, fallback: Option<u32>| match (given(matches, id, only_explicit), fallback) {
+            (false, Some(v)) => v,
+            _ => *matches.get_one::<u32>(id).unwrap(),
+        };
+        settings.quadtree = flag(matches, "quadtree").then(|| QuadtreeOptions {
+            min_size: size("min_tile", current.map(|q| q.min_size)),
+            max_size: size("max_tile", current.map(|q| q.max_size)),
+            split_variance: value("split_variance", current.map(|q| q.split_variance)),
+        });
+    }
+    if !only_explicit || flag(matches, "anneal") {
+        // Flags left out keep the job file's values
+        let current = settings.anneal.clone().unwrap_or_default();
+        settings.anneal = flag(matches, "anneal").then(|| Anneal {
+            iterations: matches.get_one::<u64>("anneal_iterations").copied().or(current.iterations),
+            seed: matches.get_one::<u64>("anneal_seed").copied().unwrap_or(current.seed),
+            seconds: matches.get_one::<f64>("anneal_seconds").copied().or(current.seconds),
+            blur: matches.get_one::<f32>("anneal_blur").copied().unwrap_or(current.blur),
+        });
+    }
+    if given(matches, "fit", only_explicit) {
+        settings.fit = matches.get_one::<String>("fit").and_then(|f| Fit::parse(f)).unwrap_or_default();
+    }
+    if given(matches, "thresholds", only_explicit) {
+        settings.thresholds = matches.get_one::<Thresholds>("thresholds").copied();
+    }
+    if given(matches, "dither", only_explicit) {
+        settings.dither = matches.get_one::<String>("dither").and_then(|d| Dither::parse(d)).unwrap_or_default();
+    }
+    if given(matches, "palette", only_explicit) {
+        settings.palette = matches.get_one::<Palette>("palette").copied();
+    }
+    if given(matches, "overlay", only_explicit) {
+        settings.overlay.position = matches
+            .get_one::<String>("overlay")
+            .and_then(|p| OverlayPosition::parse(p))
+            .unwrap_or_default();
+    }
+    if !only_explicit || flag(matches, "legend") {
+        settings.overlay.legend = flag(matches, "legend");
+    }
+    if given(matches, "font_size", only_explicit) {
+        settings.overlay.font_size = matches.get_one::<f32>("font_size").copied();
+    }
+    if given(matches, "ramp", only_explicit) {
+        settings.ramp = matches.get_one::<Ramp>("ramp").cloned();
+    }
+    if given(matches, "grayscale", only_explicit) {
+        settings.grayscale = matches.get_one::<Grayscale>("grayscale").copied().unwrap_or_default();
+    }
+    if given(matches, "alpha", only_explicit) {
+        settings.alpha = matches.get_one::<Alpha>("alpha").copied().unwrap_or_default();
+    }
+    if given(matches, "inventory", only_explicit) {
+        // Counts from the command line keep the job file's solver
+        let solver = settings.inventory.as_ref().map(|i| i.solver).unwrap_or_default();
+        settings.inventory = matches.get_one::<Inventory>("inventory").map(|i| Inventory { solver, ..i.clone() });
+    }
+    if given(matches, "solver", only_explicit)
+        && let Some(inventory) = &mut settings.inventory
+    {
+        inventory.solver = matches.get_one::<String>("solver").and_then(|s| Solver::parse(s)).unwrap_or_default();
+    }
+    if given(matches, "mask", only_explicit) {
+        // A key on the command line keeps the job file's coverage and fill
+        if let Some(key) = matches.get_one::<Key>("mask").cloned() {
+            match &mut settings.mask {
+                Some(mask) => mask.key = key,
+                None => settings.mask = Some(Subject::new(key)),
+            }
+        }
+    }
+    if given(matches, "mask_fill", only_explicit)
+        && let Some(mask) = &mut settings.mask
+    {
+        mask.fill = matches.get_one::<u8>("mask_fill").copied();
+    }
+    if !only_explicit || flag(matches, "no_auto_orient") {
+        settings.auto_orient = !flag(matches, "no_auto_orient");
+    }
+    if !only_explicit || flag(matches, "linear_light") {
+        settings.linear_light = flag(matches, "linear_light");
+    }
+    if given(matches, "preprocess", only_explicit) {
+        settings.preprocess = matches
+            .get_many::<Vec<Op>>("preprocess")
+            .map(|steps| steps.flatten().cloned().collect())
+            .unwrap_or_default();
+    }
+    if given(matches, "rulers", only_explicit) {
+        settings.rulers = matches.get_one::<u32>("rulers").map(|&every| RulerOptions {
+            every,
+            ..settings.rulers.unwrap_or_default()
+        });
+    }
+    if let Err(err) = settings.check() {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
+
+// The flags from `settings_args`, folded into settings
+fn apply_settings_args(matches: &clap::ArgMatches, settings: &mut Settings, only_explicit: bool) {
+    if given(matches, "dice_size", only_explicit) {
+        settings.dice_size = *matches.get_one::<u32>("dice_size").unwrap();
+    }
+    if given(matches, "preset", only_explicit) {
+        settings.preset = matches
+            .get_one::<String>("preset")
+            .and_then(|p| IntensityPreset::parse(p))
+            .unwrap_or(IntensityPreset::Default);
+    }
+    if given(matches, "output_size", only_explicit) {
+        settings.output_size = matches.get_one::<(u32, u32)>("output_size").copied();
+    }
+    // Switches only ever turn things on
+    if !only_explicit || matches.get_flag("invert_input") {
+        settings.invert_input = matches.get_flag("invert_input");
+    }
+    if !only_explicit || matches.get_flag("invert_dice") {
+        settings.invert_dice = matches.get_flag("invert_dice");
+    }
+    if !only_explicit || matches.get_flag("debug") {
+        settings.add_debug = matches.get_flag("debug");
+    }
+}
+
+// Everything but what the sheet varies: the shared render flags, inverting and the output size
+fn compare_settings(matches: &clap::ArgMatches) -> Settings {
+    let mut settings = Settings {
+        invert_input: matches.get_flag("invert_input"),
+        invert_dice: matches.get_flag("invert_dice"),
+        output_size: matches.get_one::<(u32, u32)>("output_size").copied(),
+        ..Settings::default()
+    };
+    apply_render_args(matches, &mut settings, false);
+    settings
+}
+
+fn compare_command(matches: &clap::ArgMatches) {
+    let settings = compare_settings(matches);
+
+    let opts = CompareOptions {
+        presets: matches
+            .get_many::<String>("presets")
+            .unwrap()
+            .filter_map(|p| IntensityPreset::parse(p))
+            .collect(),
+        dithers: matches.get_many::<String>("dithers").unwrap().filter_map(|d| Dither::parse(d)).collect(),
+        sizes: matches.get_many::<u32>("sizes").unwrap().copied().collect(),
+        thumb_width: *matches.get_one::<u32>("thumb_width").unwrap(),
+    };
+
+    let input = matches.get_one::<String>("input").unwrap();
+    let dice_dir = matches.get_one::<String>("dice_dir").unwrap();
+    let output = Path::new(matches.get_one::<String>("output").unwrap());
+    let cache = (!matches.get_flag("no_cache")).then(|| open_cache(matches));
+    println!("Rendering {} variants...", opts.variants().len());
+    let sheet = try_load_input(input, &settings)
+        .and_then(|(image, alpha)| contact_sheet(&image, alpha.as_ref(), dice_dir, &settings, &opts, cache.as_ref()));
+    let sheet = match sheet {
+        Ok(sheet) => sheet,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(parent_dir) = output.parent() {
+        std::fs::create_dir_all(parent_dir).expect("Failed to create output directory");
+    }
+    match sheet.save(output) {
+        Ok(()) => println!("Contact sheet saved to {}", output.display()),
+        Err(err) => eprintln!("Error saving contact sheet: {}", err),
+    }
+}
+
+fn run_command(matches: &clap::ArgMatches) {
+    let job_path = Path::new(matches.get_one::<String>("job").unwrap());
+    let mut job = match Job::load(job_path) {
+        Ok(job) => job,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+
+    // Flags beat the file
+    if let Some(input) = matches.get_one::<String>("input") {
+        job.input = input.into();
+    }
+    if let Some(dice_dir) = matches.get_one::<String>("dice_dir") {
+        job.dice_dir = dice_dir.into();
+    }
+    apply_settings_args(matches, &mut job.render, true);
+    apply_render_args(matches, &mut job.render, true);
+    if let Some(die_mm) = matches.get_one::<f64>("die_mm") {
+        job.physical = Some(Physical { die_mm: *die_mm });
+    }
+    if let Some(outputs) = matches.get_many::<String>("output") {
+        job.outputs = outputs.map(OutputSpec::new).collect();
+    }
+
+    if matches.get_flag("print") {
+        match job.to_toml() {
+            Ok(toml) => print!("{}", toml),
+            Err(err) => eprintln!("{}", err),
+        }
+        return;
+    }
+
+    let cache = (!matches.get_flag("no_cache")).then(|| open_cache(matches));
+    let mosaic = match job.run(cache.as_ref()) {
+        Ok(mosaic) => mosaic,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    for output in job.outputs() {
+        println!("Output saved to {}", output.path.display());
+    }
+    println!("Total dice used: {}", mosaic.total_dice());
+    println!("Output image size: {}x{}", mosaic.size.0, mosaic.size.1);
+    if let Some(physical) = &job.physical {
+        println!("{}", physical.summary(&mosaic, &job.render));
+    }
+}
+
+#[cfg(feature = "server")]
+fn serve_command(matches: &clap::ArgMatches) {
+    let mb = |id: &str| matches.get_one::<u64>(id).unwrap() * 1024 * 1024;
+    let megapixels = |id: &str| matches.get_one::<u64>(id).unwrap() * 1_000_000;
+    let workers = matches
+        .get_one::<usize>("workers")
+        .copied()
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+    let opts = ServeOptions {
+        addr: matches.get_one::<String>("addr").unwrap().clone(),
+        dice_dir: matches.get_one::<String>("dice_dir").unwrap().into(),
+        workers,
+        queue: *matches.get_one::<usize>("queue").unwrap(),
+        max_body: mb("max_body"),
+        max_input_pixels: megapixels("max_input"),
+        max_output_pixels: megapixels("max_output"),
+        max_dice: *matches.get_one::<u64>("max_dice").unwrap(),
+        cache: (!matches.get_flag("no_cache")).then(|| open_cache(matches)),
+    };
+    if let Err(err) = dice::server::serve(opts) {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(feature = "tui")]
+fn tui_command(matches: &clap::ArgMatches) {
+    let job_path = Path::new(matches.get_one::<String>("job").unwrap());
+    let input = matches.get_one::<String>("input");
+    let dice_dir = matches.get_one::<String>("dice_dir");
+
+    // Pick up where a saved job left off, flags on top
+    let mut job = if job_path.exists() {
+        Job::load(job_path).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        })
+    } else {
+        let (Some(input), Some(dice_dir)) = (input, dice_dir) else {
+            eprintln!("No job file at {}, so --input and --dice-dir are needed", job_path.display());
+            std::process::exit(1);
+        };
+        Job {
+            input: input.into(),
+            dice_dir: dice_dir.into(),
+            render: Settings::default(),
+            physical: None,
+            outputs: Vec::new(),
+        }
+    };
+    if let Some(input) = input {
+        job.input = input.into();
+    }
+    if let Some(dice_dir) = dice_dir {
+        job.dice_dir = dice_dir.into();
+    }
+    apply_settings_args(matches, &mut job.render, true);
+    apply_render_args(matches, &mut job.render, true);
+    if let Some(outputs) = matches.get_many::<String>("output") {
+        job.outputs = outputs.map(OutputSpec::new).collect();
+    }
+
+    let cache = (!matches.get_flag("no_cache")).then(|| open_cache(matches));
+    let opts = TuiOptions {
+        job,
+        path: job_path.to_path_buf(),
+    };
+    if let Err(err) = dice::tui::run(opts, cache) {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
 
+fn batch_command(matches: &clap::ArgMatches) {
+    let mut settings = Settings::default();
+    apply_settings_args(matches, &mut settings, false);
+    apply_render_args(matches, &mut settings, false);
+
+    let patterns: Vec<String> = matches.get_many::<String>("inputs").unwrap().cloned().collect();
+    let inputs = match expand_inputs(&patterns) {
+        Ok(inputs) if !inputs.is_empty() => inputs,
+        Ok(_) => {
+            eprintln!("No input images found.");
+            std::process::exit(1);
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let template = matches.get_one::<String>("template").unwrap();
+    let outputs = match output_paths(template, &inputs, &settings) {
+        Ok(outputs) => outputs,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+
+    // One tile set for the whole batch
+    let cache = (!matches.get_flag("no_cache")).then(|| open_cache(matches));
+    let dice_dir = matches.get_one::<String>("dice_dir").unwrap();
+    let tiles = match pipeline::load_tiles(dice_dir, &settings, cache.as_ref()) {
+        Ok(tiles) => tiles,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+
+    let opts = BatchOptions {
+        jobs: matches
+            .get_one::<usize>("jobs")
+            .copied()
+            .unwrap_or_else(|| std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1)),
+        continue_on_error: matches.get_flag("continue_on_error"),
+    };
+    println!("Rendering {} images with {} jobs...", inputs.len(), opts.jobs);
+    let results = run_batch(&inputs, &outputs, &tiles, &settings, &opts);
+
+    for r in &results {
+        match &r.outcome {
+            Ok(dice) => println!(
+                "ok   {} -> {} ({} dice, {:.1}s)",
+                r.input.display(),
+                r.output.display(),
+                dice,
+                r.elapsed.as_secs_f64()
+            ),
+            Err(_) => println!("FAIL {}", r.input.display()),
+        }
+    }
+
+    let failed: Vec<_> = results.iter().filter(|r| r.outcome.is_err()).collect();
+    let skipped = inputs.len() - results.len();
+    println!();
+    println!(
+        "Batch done: {} ok, {} failed, {} skipped",
+        results.len() - failed.len(),
+        failed.len(),
+        skipped
+    );
+    if !failed.is_empty() {
+        // Summary table of what went wrong
+        let width = failed
+            .iter()
+            .map(|r| r.input.display().to_string().len())
+            .max()
+            .unwrap_or(0)
+            .max("INPUT".len());
+        println!();
+        println!("{:<width$}  ERROR", "INPUT", width = width);
+        for r in &failed {
+            let err = r.outcome.as_ref().err().map(String::as_str).unwrap_or("");
+            println!("{:<width$}  {}", r.input.display().to_string(), err, width = width);
+        }
+        std::process::exit(1);
+    }
+}
+
+fn load_images_dynamic(matches: &clap::ArgMatches) -> Images {
     let input = matches
         .get_one::<String>("input")
         .expect("Input file is required")
@@ -103,45 +1115,86 @@ fn load_images_dynamic() -> Images {
         .expect("Dice directory is required")
         .to_string();
 
+    let mut settings = Settings::default();
+    apply_render_args(matches, &mut settings, false);
+
+    let tile_list = matches.get_one::<String>("tile_list").cloned();
+    let output = matches
+        .get_one::<String>("output")
+        .expect("Output path has a default")
+        .to_string();
+    let stream = matches.get_flag("stream");
+    let max_memory = matches.get_one::<u64>("max_memory").map(|mb| mb * 1024 * 1024);
+    let preview = matches
+        .get_one::<String>("preview")
+        .map(|mode| PreviewMode::parse(mode).expect("clap checked the mode"));
+    let preview_width = matches.get_one::<u32>("preview_width").copied();
+
+    // Bail early if the input doesn't exist
+    let Some(input) = validate_input(input) else {
+        std::process::exit(1);
+    };
+
     // Load the input image
-    let mut i: GrayImage = load_image(&input);
-    let (original_width, original_height) = i.dimensions(); // Save the original dimensions
+    let (i, alpha) = match try_load_input(&input, &settings) {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
 
-    let mut dice = load_dice_images_d(&dice_dir);
+    // Ask the user for the dice size. Quadtree dice come in several sizes, so
+    // those are loaded at the biggest and scaled down from there.
+    if settings.quadtree.is_none() {
+        let d_size_input = ask("Enter the dice size you want (e.g., 32 for 32x32 pixels):");
+        settings.dice_size = match d_size_input.parse() {
+            Ok(size) if size > 0 => size,
+            _ => {
+                println!("Invalid size. Defaulting to 32x32.");
+                32
+            }
+        };
+    }
+
+    let cache = (!matches.get_flag("no_cache")).then(|| open_cache(matches));
+    let dice = match load_dice_dir(&dice_dir, settings.tile_size(), cache.as_ref()) {
+        Ok(dice) => dice,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
 
     // Ask if the user wants to invert the input image
-    println!("Invert the input image? (y/n):");
-    let mut invert_i = String::new();
-    std::io::stdin().read_line(&mut invert_i).unwrap();
-    if invert_i.trim().eq_ignore_ascii_case("y") {
-        imageops::invert(&mut i);
+    settings.invert_input = ask("Invert the input image? (y/n):").eq_ignore_ascii_case("y");
+    if settings.invert_input {
         println!("Image inverted.");
     } else {
         println!("Keeping it original. I like it.");
     }
 
-    // Crop the input image to a square. Dice grids love squares.
-    let square_size = original_width.min(original_height);
-    i = imageops::crop_imm(&i, 0, 0, square_size, square_size).to_image();
-
     // Ask if the user wants to invert the dice colors
-    println!("Invert the dice colors? (y/n):");
-    let mut invert_dice_i = String::new();
-    std::io::stdin().read_line(&mut invert_dice_i).unwrap();
-    let invert_dice = invert_dice_i.trim().eq_ignore_ascii_case("y");
-
-    if invert_dice {
-        for d in &mut dice {
-            d.image.invert();
-        }
+    settings.invert_dice = ask("Invert the dice colors? (y/n):").eq_ignore_ascii_case("y");
+    if settings.invert_dice {
         println!("Dice colors inverted. Edgy.");
     } else {
         println!("Dice colors untouched. Classic.");
     }
 
+    let source = Path::new(&input).file_name().map(|n| n.to_string_lossy().into_owned());
     Images {
         dice,
         input: i,
+        alpha,
+        source,
+        settings,
+        tile_list,
+        output,
+        stream,
+        max_memory,
+        preview,
+        preview_width,
     }
 }
 
@@ -159,45 +1212,33 @@ fn validate_input(i: String) -> Option<String> {
 }
 
 fn main() {
+    let matches = cli().get_matches();
+    match matches.subcommand() {
+        Some(("cache", sub)) => return cache_command(sub),
+        Some(("batch", sub)) => return batch_command(sub),
+        Some(("run", sub)) => return run_command(sub),
+        Some(("compare", sub)) => return compare_command(sub),
+        #[cfg(feature = "tui")]
+        Some(("tui", sub)) => return tui_command(sub),
+        #[cfg(feature = "server")]
+        Some(("serve", sub)) => return serve_command(sub),
+        _ => {}
+    }
+
     // Load the dice and input image
-    let mut dicks: Images = load_images_dynamic();
-    if dicks.dice.is_empty() || dicks.dice[0].image.width() == 0 || dicks.dice[0].image.height() == 0 {
+    let dicks: Images = load_images_dynamic(&matches);
+    if dicks.dice[0].image.width() == 0 || dicks.dice[0].image.height() == 0 {
         eprintln!("Dice images are missing or invalid. Fix it.");
         return;
     }
+    let mut settings = dicks.settings;
 
-    let dw = dicks.dice[0].image.width();
-    let dh = dicks.dice[0].image.height();
-
-    // Resize the input image if needed
-    match resize_output(&dicks.input) {
-        Some(resized_input) => {
-            dicks.input = resized_input;
-        }
-        None => {}
-    }
-
-    // Recalculate grid dimensions based on the resized input image
-    let (iwidth, iheight) = dicks.input.dimensions();
-    let num_dice_x = iwidth / dw;
-    let num_dice_y = iheight / dh;
-
-    // Prepare the output image
-    let mut ow = num_dice_x * dw;
-    let mut oh = num_dice_y * dh;
-    let mut oi = RgbaImage::new(ow, oh);
+    // Custom output size, if the user wants one
+    settings.output_size = ask_output_size();
 
     // Ask the user for an intensity preset
-    println!("Pick your intensity preset:");
-    println!("1. Default");
-    println!("2. High Contrast");
-    println!("3. Low Contrast");
-    println!("4. Bright");
-    println!("5. Dark");
-
-    let mut preset_input = String::new();
-    std::io::stdin().read_line(&mut preset_input).unwrap();
-    let preset = match preset_input.trim() {
+    let preset_input = ask("Pick your intensity preset:\n1. Default\n2. High Contrast\n3. Low Contrast\n4. Bright\n5. Dark");
+    settings.preset = match preset_input.as_str() {
         "1" => IntensityPreset::Default,
         "2" => IntensityPreset::HighContrast,
         "3" => IntensityPreset::LowContrast,
@@ -209,69 +1250,97 @@ fn main() {
         }
     };
 
-    // Map blocks to dice and construct the output
-    for grid_y in 0..num_dice_y {
-        for grid_x in 0..num_dice_x {
-            let block_start_x = grid_x * dw;
-            let block_start_y = grid_y * dh;
+    settings.add_debug = ask("Do you want to add debug info to output image? (y/n):").eq_ignore_ascii_case("y");
 
-            let block_view = imageops::crop_imm(&dicks.input, block_start_x, block_start_y, dw, dh);
+    // Map blocks to dice. Each cell is sampled and later pasted at the same spot.
+    let mut tiles = pipeline::tile_set(dicks.dice, &settings);
+    let input = pipeline::prepare_input(dicks.input, &settings);
+    let alpha = dicks.alpha.map(|a| pipeline::prepare_alpha(a, &settings));
+    let mut mosaic = pipeline::plan_masked(&input, alpha.as_ref(), &settings);
+    if let Err(e) = mosaic.check() {
+        eprintln!("{}", e);
+        return;
+    }
+    mosaic.source = dicks.source;
 
-            let mut total_intensity: u64 = 0;
-            let num_pixels_in_block = (dw * dh) as u64;
-            for pixel in block_view.to_image().pixels() {
-                total_intensity += pixel[0] as u64;
-            }
-            let avg_intensity = if num_pixels_in_block > 0 {
-                (total_intensity / num_pixels_in_block) as u8
-            } else {
-                0
-            };
-
-            let target_side: DiceSides = map_intensity_to_dice_side(avg_intensity, &preset);
-
-            let dice_to_draw = dicks.dice.iter().find(|&d| d.side == target_side);
-
-            if let Some(dice) = dice_to_draw {
-                let paste_x = grid_x * dw;
-                let paste_y = grid_y * dh;
-                let dice_rgba = dice.image.to_rgba8();
-                imageops::overlay(&mut oi, &dice_rgba, paste_x as i64, paste_y as i64);
-            } else {
-                eprintln!(
-                    "Warning: Could not find dice for side {:?} at grid ({}, {})",
-                    target_side, grid_x, grid_y
-                );
-            }
+    // Look before writing: the preview is drawn from the plan, not the full canvas
+    if let Some(mode) = dicks.preview {
+        let columns = dicks.preview_width.unwrap_or_else(terminal_columns);
+        print!("{}", preview(&mosaic, &mut tiles, mode, columns));
+        if !ask("Write the output? (y/n):").eq_ignore_ascii_case("y") {
+            println!("Nothing written.");
+            return;
         }
     }
+    let decorations = Decorations::new(&mosaic, &settings);
+    let (ow, oh) = decorations.canvas_size();
 
-    println!("Do you want to add debug info to output image? (y/n):");
-    let mut invert_i = String::new();
-    std::io::stdin().read_line(&mut invert_i).unwrap();
-    if invert_i.trim().eq_ignore_ascii_case("y") {
-        add_reference_text(
-            &mut oi,
-            (dw, dh),
-            num_dice_x * num_dice_y,
-            (ow, oh),
-        );
+    // Too big for the memory cap (or asked to)? Then never build the full canvas.
+    let output_path = dicks.output.as_str();
+    let stream = dicks.stream || dicks.max_memory.is_some_and(|cap| canvas_bytes((ow, oh)) > cap);
+    let format = OutputFormat::from_path(Path::new(output_path));
+    if let Some(format) = format.filter(OutputFormat::is_text) {
+        // Characters instead of dice, nothing to paint
+        let art = TextArt::new(&input, alpha.as_ref(), &settings);
+        let result = match format {
+            OutputFormat::Txt => write_text(Path::new(output_path), &art),
+            _ => write_html(Path::new(output_path), &art, &settings),
+        };
+        if let Err(err) = result {
+            eprintln!("{}", err);
+        }
+    } else if stream {
+        let budget = dicks.max_memory.unwrap_or(DEFAULT_STRIP_BYTES);
+        let strip_rows = strip_rows_for_budget(ow, oh, budget);
+        println!("Streaming output in strips of {} rows", strip_rows);
+        let placements = decorations.placements(&mosaic);
+        let result = write_streaming(Path::new(output_path), &placements, &tiles, (ow, oh), strip_rows, |y0, strip| {
+            decorations.draw(strip, y0)
+        });
+        if let Err(err) = result {
+            eprintln!("Error saving output image: {}", err);
+        }
+    } else {
+        let oi = pipeline::render(&mosaic, &tiles, &settings);
+
+        // Save the output image
+        if let Some(parent_dir) = Path::new(output_path).parent() {
+            std::fs::create_dir_all(parent_dir).expect("Failed to create output directory");
+        }
+        oi.save(output_path).unwrap_or_else(|err| {
+            eprintln!("Error saving output image: {}", err);
+        });
+    }
+    if settings.add_debug || settings.overlay.legend {
         println!("Debug info added to image");
     } else {
         println!("No debug info added.");
     }
-
-    // Save the output image
-    let output_path = "output/dice_output.png";
-    if let Some(parent_dir) = Path::new(output_path).parent() {
-        std::fs::create_dir_all(parent_dir).expect("Failed to create output directory");
+    if let Some(tile_list) = &dicks.tile_list {
+        match write_csv(Path::new(tile_list), &mosaic.placements) {
+            Ok(()) => println!("Tile list saved to {}", tile_list),
+            Err(err) => eprintln!("Error saving tile list: {}", err),
+        }
     }
-    oi.save(output_path).unwrap_or_else(|err| {
-        eprintln!("Error saving output image: {}", err);
-    });
+
+    let (iwidth, iheight) = mosaic.input_size;
     println!("Original image size: {}x{}", iwidth, iheight);
-    println!("Dice size used: {}x{}", dw, dh);
-    println!("Total dice used: {}", num_dice_x * num_dice_y);
+    match &settings.quadtree {
+        Some(q) => {
+            println!("Dice sizes used: {} to {} (quadtree)", q.min_size, settings.tile_size());
+            for (w, h) in tiles.sizes() {
+                let count = mosaic.placements.iter().filter(|p| (p.cell.w, p.cell.h) == (w, h)).count();
+                if count > 0 {
+                    println!("  {}x{}: {} dice", w, h, count);
+                }
+            }
+        }
+        None => {
+            println!("Dice size used: {}x{}", settings.dice_size, settings.dice_size);
+            println!("Layout: {}", settings.layout.name());
+        }
+    }
+    println!("Total dice used: {}", mosaic.total_dice());
     println!("Output image size: {}x{}", ow, oh);
     println!("Output saved to {}", output_path);
 
@@ -281,70 +1350,151 @@ fn main() {
     std::io::stdin().read_line(&mut input).unwrap();
 }
 
-fn resize_output(input: &GrayImage) -> Option<GrayImage> {
-    println!("Do you want to set a custom output image size? (y/n):");
-    let mut custom_size_input = String::new();
-    std::io::stdin().read_line(&mut custom_size_input).unwrap();
-    if custom_size_input.trim().eq_ignore_ascii_case("y") {
-        println!("Enter the desired output image width (e.g., 1920 for desktop wallpaper):");
-        let mut output_width_input = String::new();
-        std::io::stdin().read_line(&mut output_width_input).unwrap();
-        let output_width: u32 = match output_width_input.trim().parse() {
-            Ok(width) if width > 0 => width,
-            _ => {
-                println!("Invalid width. Using default width of 1920.");
-                1920
-            }
-        };
+fn ask_output_size() -> Option<(u32, u32)> {
+    if !ask("Do you want to set a custom output image size? (y/n):").eq_ignore_ascii_case("y") {
+        return None;
+    }
 
-        println!("Enter the desired output image height (e.g., 1080 for desktop wallpaper):");
-        let mut output_height_input = String::new();
-        std::io::stdin().read_line(&mut output_height_input).unwrap();
-        let output_height: u32 = match output_height_input.trim().parse() {
-            Ok(height) if height > 0 => height,
-            _ => {
-                println!("Invalid height. Using default height of 1080.");
-                1080
-            }
-        };
+    let output_width: u32 = match ask("Enter the desired output image width (e.g., 1920 for desktop wallpaper):").parse() {
+        Ok(width) if width > 0 => width,
+        _ => {
+            println!("Invalid width. Using default width of 1920.");
+            1920
+        }
+    };
 
-        println!("Custom output size set to {}x{}", output_width, output_height);
+    let output_height: u32 = match ask("Enter the desired output image height (e.g., 1080 for desktop wallpaper):").parse() {
+        Ok(height) if height > 0 => height,
+        _ => {
+            println!("Invalid height. Using default height of 1080.");
+            1080
+        }
+    };
 
-        // Create a new blank image with the desired dimensions
-        let mut resized_input = GrayImage::new(output_width, output_height);
+    println!("Custom output size set to {}x{}", output_width, output_height);
+    Some((output_width, output_height))
+}
 
-        // Calculate the aspect ratio of the original image
-        let (input_width, input_height) = input.dimensions();
-        let aspect_ratio = input_width as f32 / input_height as f32;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        // Calculate the new dimensions for the original image while maintaining aspect ratio
-        let (new_width, new_height) = if output_width as f32 / output_height as f32 > aspect_ratio {
-            // Constrain by height
-            let new_width = (output_height as f32 * aspect_ratio).round() as u32;
-            (new_width, output_height)
-        } else {
-            // Constrain by width
-            let new_height = (output_width as f32 / aspect_ratio).round() as u32;
-            (output_width, new_height)
-        };
+    fn job_settings() -> Settings {
+        Settings {
+            dice_size: 12,
+            preset: IntensityPreset::Dark,
+            dither: Dither::FloydSteinberg,
+            quadtree: Some(QuadtreeOptions { min_size: 8, max_size: 32, split_variance: 50.0 }),
+            ..Settings::default()
+        }
+    }
 
-        // Resize the original image to the new dimensions
-        let scaled_input = image::imageops::resize(
-            input,
-            new_width,
-            new_height,
-            imageops::FilterType::Lanczos3, // High-quality resizing filter
-        );
+    fn run_with(args: &[&str]) -> Settings {
+        let matches = run_cli().try_get_matches_from(["run", "dice.toml"].iter().chain(args)).unwrap();
+        let mut settings = job_settings();
+        apply_settings_args(&matches, &mut settings, true);
+        apply_render_args(&matches, &mut settings, true);
+        settings
+    }
 
-        // Calculate the offsets to center the scaled image
-        let offset_x = ((output_width - new_width) / 2) as i64;
-        let offset_y = ((output_height - new_height) / 2) as i64;
+    #[test]
+    fn run_keeps_the_job_file_without_flags() {
+        let settings = run_with(&[]);
+        assert_eq!(settings.dice_size, 12);
+        assert_eq!(settings.preset, IntensityPreset::Dark);
+        assert_eq!(settings.dither, Dither::FloydSteinberg);
+        assert_eq!(settings.quadtree.map(|q| (q.min_size, q.max_size)), Some((8, 32)));
+    }
 
-        // Overlay the scaled image onto the new blank image
-        imageops::overlay(&mut resized_input, &scaled_input, offset_x, offset_y);
+    #[test]
+    fn run_flags_override_only_what_they_name() {
+        let settings = run_with(&["-s", "8", "--dither", "none"]);
+        assert_eq!(settings.dice_size, 8);
+        assert_eq!(settings.dither, Dither::None);
+        assert_eq!(settings.preset, IntensityPreset::Dark);
+        assert!(settings.quadtree.is_some());
+    }
 
-        Some(resized_input)
-    } else {
-        None
+    #[test]
+    fn run_quadtree_flags_keep_the_job_file_sizes_left_out() {
+        let settings = run_with(&["--quadtree", "--max-tile", "64"]);
+        let quadtree = settings.quadtree.unwrap();
+        assert_eq!((quadtree.min_size, quadtree.max_size), (8, 64));
+        assert_eq!(quadtree.split_variance, 50.0);
+    }
+
+    #[test]
+    fn run_layout_flag_replaces_the_job_files_quadtree() {
+        let settings = run_with(&["--layout", "brick"]);
+        assert_eq!(settings.layout, LayoutKind::Brick);
+        assert!(settings.quadtree.is_none());
+    }
+
+    fn compare_with(args: &[&str]) -> Settings {
+        let base = ["compare", "-i", "cat.jpg", "-d", "dice"];
+        compare_settings(&compare_cli().try_get_matches_from(base.iter().chain(args)).unwrap())
+    }
+
+    #[test]
+    fn compare_takes_the_shared_render_flags() {
+        let settings = compare_with(&["--layout", "hex", "--fit", "full", "--invert-dice"]);
+        assert_eq!(settings.layout, LayoutKind::Hex);
+        assert_eq!(settings.fit, Fit::Full);
+        assert!(settings.invert_dice);
+        for varied in ["--dither", "--quadtree", "--thresholds", "--ramp"] {
+            let args = ["compare", "-i", "cat.jpg", "-d", "dice", varied];
+            let err = compare_cli().try_get_matches_from(args).unwrap_err();
+            assert_eq!(err.kind(), clap::error::ErrorKind::UnknownArgument, "{}", varied);
+        }
+    }
+
+    #[test]
+    fn compare_takes_preprocess() {
+        let settings = compare_with(&["--preprocess", "levels=20,235", "--preprocess", "gamma=1.2"]);
+        assert_eq!(settings.preprocess.len(), 2);
+        assert_eq!(settings.preprocess[1], Op::Gamma { gamma: 1.2 });
+    }
+
+    #[test]
+    fn compare_takes_grayscale() {
+        assert_eq!(compare_with(&["--grayscale", "lab"]).grayscale, Grayscale::Lab);
+    }
+
+    #[test]
+    fn compare_takes_linear_light() {
+        assert!(compare_with(&["--linear-light"]).linear_light);
+        assert!(!compare_with(&[]).linear_light);
+    }
+
+    #[test]
+    fn compare_takes_no_auto_orient() {
+        assert!(!compare_with(&["--no-auto-orient"]).auto_orient);
+        assert!(compare_with(&[]).auto_orient);
+    }
+
+    #[test]
+    fn compare_takes_alpha() {
+        assert_eq!(compare_with(&["--alpha", "empty"]).alpha, Alpha::Empty { coverage: 0.5 });
+    }
+
+    #[test]
+    fn compare_takes_the_mask() {
+        let mask = compare_with(&["--mask", "flood=30", "--mask-fill", "6"]).mask.unwrap();
+        assert_eq!(mask.key, Key::Flood { tolerance: 30 });
+        assert_eq!(mask.fill, Some(6));
+    }
+
+    #[test]
+    fn compare_takes_the_inventory() {
+        let inventory = compare_with(&["--inventory", "3000", "--solver", "diffuse"]).inventory.unwrap();
+        assert_eq!(inventory.dice, Some(3000));
+        assert_eq!(inventory.solver, Solver::Diffuse);
+    }
+
+    #[test]
+    fn compare_takes_anneal() {
+        let anneal = compare_with(&["--anneal", "--anneal-seed", "7", "--anneal-seconds", "2"]).anneal.unwrap();
+        assert_eq!((anneal.seed, anneal.seconds), (7, Some(2.0)));
+        assert!(compare_with(&[]).anneal.is_none());
     }
 }
@@ -0,0 +1,299 @@
+// Dice on the subject only. A mask says which pixels are subject: a mask image, or
+// one keyed out of the input itself by luma, by color, or by flood filling the
+// backdrop in from the corners. Cells that are mostly backdrop get no die, or a die
+// showing a fixed face, so a portrait doesn't spend half its dice on a white wall.
+//
+//   [render]
+//   mask = { key = "flood", tolerance = 24, fill = 6 }
+//
+//   dice -i cat.jpg -d dice/ --mask chroma=#00b140,60 --mask-fill 1
+
+use std::path::PathBuf;
+
+use image::{imageops, DynamicImage, GrayImage, Luma};
+use serde::{Deserialize, Serialize};
+
+use crate::dicelib::DiceSides;
+use crate::palette::Color;
+
+/// Where the subject mask comes from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "key", rename_all = "lowercase")]
+pub enum Key {
+    /// A mask image the input's size (it gets stretched if not): white or opaque is
+    /// subject, black or transparent is backdrop. `invert` swaps them.
+    Image {
+        path: PathBuf,
+        #[serde(default)]
+        invert: bool,
+    },
+    /// Pixels whose luma is within `tolerance` of `luma` are backdrop.
+    Luma {
+        luma: u8,
+        #[serde(default = "default_tolerance")]
+        tolerance: u8,
+    },
+    /// Pixels within `tolerance` (RGB distance) of `color` are backdrop, like a green screen.
+    Chroma {
+        color: Color,
+        #[serde(default = "default_tolerance")]
+        tolerance: u8,
+    },
+    /// Backdrop is whatever connects to a corner without any channel straying more
+    /// than `tolerance` from that corner's color.
+    Flood {
+        #[serde(default = "default_tolerance")]
+        tolerance: u8,
+    },
+}
+
+fn default_tolerance() -> u8 {
+    24
+}
+
+fn half() -> f32 {
+    0.5
+}
+
+/// "VALUE,TOLERANCE" or just "VALUE".
+fn split(args: &str) -> (&str, Option<&str>) {
+    args.split_once(',').map(|(v, t)| (v.trim(), Some(t))).unwrap_or((args, None))
+}
+
+/// The `mask` setting: which pixels are subject, and what the cells around it get.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Subject {
+    #[serde(flatten)]
+    pub key: Key,
+    /// Share of a cell (0 to 1) that has to be subject for it to get a die from the input.
+    #[serde(default = "half")]
+    pub coverage: f32,
+    /// Pips (1-6) on the dice around the subject. Unset leaves those cells empty.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fill: Option<u8>,
+}
+
+impl Key {
+    /// `image=PATH`, `luma=VALUE[,TOLERANCE]`, `chroma=COLOR[,TOLERANCE]` or
+    /// `flood[=TOLERANCE]`. Tolerances default to 24. A `!` before `image` inverts it.
+    pub fn parse(s: &str) -> Result<Key, String> {
+        let (name, args) = s.trim().split_once('=').map(|(n, a)| (n.trim(), Some(a.trim()))).unwrap_or((s.trim(), None));
+        let tolerance = |t: Option<&str>| match t {
+            None => Ok(default_tolerance()),
+            Some(t) => t.trim().parse::<u8>().map_err(|_| format!("Tolerance goes from 0 to 255, got {:?}", t)),
+        };
+        match (name.to_ascii_lowercase().as_str(), args) {
+            ("image" | "!image", Some(path)) if !path.is_empty() => Ok(Key::Image {
+                path: PathBuf::from(path),
+                invert: name.starts_with('!'),
+            }),
+            ("luma", Some(args)) => {
+                let (luma, t) = split(args);
+                Ok(Key::Luma {
+                    luma: luma.parse().map_err(|_| format!("Luma goes from 0 to 255, got {:?}", luma))?,
+                    tolerance: tolerance(t)?,
+                })
+            }
+            ("chroma", Some(args)) => {
+                let (color, t) = split(args);
+                Ok(Key::Chroma {
+                    color: Color::parse(color)?,
+                    tolerance: tolerance(t)?,
+                })
+            }
+            ("flood", t) => Ok(Key::Flood { tolerance: tolerance(t)? }),
+            _ => Err(format!(
+                "Unknown mask {:?}, expected image=PATH, luma=VALUE[,TOL], chroma=COLOR[,TOL] or flood[=TOL]",
+                s
+            )),
+        }
+    }
+
+    /// 255 where the subject is, 0 on the backdrop, the size of `image`.
+    fn mask(&self, image: &DynamicImage, luma: &GrayImage) -> Result<GrayImage, String> {
+        let (w, h) = luma.dimensions();
+        let keyed = |backdrop: &dyn Fn(u32, u32) -> bool| GrayImage::from_fn(w, h, |x, y| Luma([if backdrop(x, y) { 0 } else { 255 }]));
+        match self {
+            Key::Image { path, invert } => {
+                let loaded = image::open(path).map_err(|e| format!("Failed to load mask {:?}: {}", path, e))?;
+                let mut mask = match loaded.color().has_alpha() {
+                    true => {
+                        let la = loaded.to_luma_alpha8();
+                        GrayImage::from_fn(la.width(), la.height(), |x, y| {
+                            let [l, a] = la.get_pixel(x, y).0;
+                            Luma([(l as u16 * a as u16 / 255) as u8])
+                        })
+                    }
+                    false => loaded.to_luma8(),
+                };
+                if mask.dimensions() != (w, h) {
+                    mask = imageops::resize(&mask, w, h, imageops::FilterType::Triangle);
+                }
+                if *invert {
+                    imageops::invert(&mut mask);
+                }
+                Ok(mask)
+            }
+            Key::Luma { luma: key, tolerance } => Ok(keyed(&|x, y| luma.get_pixel(x, y).0[0].abs_diff(*key) <= *tolerance)),
+            Key::Chroma { color, tolerance } => {
+                let rgb = image.to_rgb8();
+                let limit = *tolerance as u32 * *tolerance as u32;
+                Ok(keyed(&|x, y| {
+                    let p = rgb.get_pixel(x, y).0;
+                    (0..3).map(|i| (p[i].abs_diff(color.0[i]) as u32).pow(2)).sum::<u32>() <= limit
+                }))
+            }
+            Key::Flood { tolerance } => Ok(flood_corners(&image.to_rgb8(), *tolerance)),
+        }
+    }
+}
+
+impl Subject {
+    /// Cells half subject or more get a die, the rest are left empty.
+    pub fn new(key: Key) -> Subject {
+        Subject {
+            key,
+            coverage: half(),
+            fill: None,
+        }
+    }
+
+    /// The subject mask for a loaded input, the size of `luma` (its gray version).
+    pub fn mask(&self, image: &DynamicImage, luma: &GrayImage) -> Result<GrayImage, String> {
+        if !(0.0..=1.0).contains(&self.coverage) {
+            return Err(format!("Mask coverage goes from 0 to 1, got {}", self.coverage));
+        }
+        if self.fill.is_some_and(|pips| !(1..=6).contains(&pips)) {
+            return Err(format!("Mask fill is a face from 1 to 6, got {}", self.fill.unwrap_or_default()));
+        }
+        self.key.mask(image, luma)
+    }
+
+    /// The face for the cells around the subject, if they get one.
+    pub fn fill_face(&self) -> Option<DiceSides> {
+        self.fill.filter(|pips| (1..=6).contains(pips)).map(|pips| DiceSides::ALL[pips as usize - 1])
+    }
+}
+
+/// Flood fill from all four corners, each against its own corner's color.
+fn flood_corners(rgb: &image::RgbImage, tolerance: u8) -> GrayImage {
+    let (w, h) = rgb.dimensions();
+    let mut mask = GrayImage::from_pixel(w, h, Luma([255]));
+    if w == 0 || h == 0 {
+        return mask;
+    }
+    for (cx, cy) in [(0, 0), (w - 1, 0), (0, h - 1), (w - 1, h - 1)] {
+        let seed = rgb.get_pixel(cx, cy).0;
+        let close = |x: u32, y: u32| {
+            let p = rgb.get_pixel(x, y).0;
+            (0..3).all(|i| p[i].abs_diff(seed[i]) <= tolerance)
+        };
+        // Scanline fill: mark a whole run of the row, then queue one seed per run of
+        // fillable pixels above and below it. Keeps the stack about as long as the
+        // image is wide, even on a big flat backdrop.
+        let open = |mask: &GrayImage, x: u32, y: u32| mask.get_pixel(x, y).0[0] != 0 && close(x, y);
+        let mut stack = vec![(cx, cy)];
+        while let Some((x, y)) = stack.pop() {
+            if !open(&mask, x, y) {
+                continue;
+            }
+            let (mut left, mut right) = (x, x);
+            while left > 0 && open(&mask, left - 1, y) {
+                left -= 1;
+            }
+            while right + 1 < w && open(&mask, right + 1, y) {
+                right += 1;
+            }
+            for x in left..=right {
+                mask.put_pixel(x, y, Luma([0]));
+            }
+            for ny in [y.checked_sub(1), Some(y + 1).filter(|&ny| ny < h)].into_iter().flatten() {
+                let mut in_run = false;
+                for x in left..=right {
+                    let fillable = open(&mask, x, ny);
+                    if fillable && !in_run {
+                        stack.push((x, ny));
+                    }
+                    in_run = fillable;
+                }
+            }
+        }
+    }
+    mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    #[test]
+    fn parse_reads_every_key() {
+        assert_eq!(
+            Key::parse("image=cat-mask.png").unwrap(),
+            Key::Image {
+                path: PathBuf::from("cat-mask.png"),
+                invert: false
+            }
+        );
+        assert_eq!(
+            Key::parse("!image=m.png").unwrap(),
+            Key::Image {
+                path: PathBuf::from("m.png"),
+                invert: true
+            }
+        );
+        assert_eq!(Key::parse("luma=255, 20").unwrap(), Key::Luma { luma: 255, tolerance: 20 });
+        assert_eq!(Key::parse("luma=0").unwrap(), Key::Luma { luma: 0, tolerance: 24 });
+        assert_eq!(
+            Key::parse("chroma=#00b140,60").unwrap(),
+            Key::Chroma {
+                color: Color([0, 0xb1, 0x40]),
+                tolerance: 60
+            }
+        );
+        assert_eq!(Key::parse("flood").unwrap(), Key::Flood { tolerance: 24 });
+        assert_eq!(Key::parse("FLOOD=5").unwrap(), Key::Flood { tolerance: 5 });
+        for bad in ["image", "image=", "luma", "luma=300", "chroma=green", "flood=-1", "blur=3"] {
+            assert!(Key::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    /// Rows of `#` (dark) and `.` (light) as an image.
+    fn picture(rows: &[&str]) -> RgbImage {
+        RgbImage::from_fn(rows[0].len() as u32, rows.len() as u32, |x, y| {
+            let v = if rows[y as usize].as_bytes()[x as usize] == b'#' { 0 } else { 255 };
+            Rgb([v, v, v])
+        })
+    }
+
+    /// The mask as rows, `S` for subject and `.` for backdrop.
+    fn subject(mask: &GrayImage) -> Vec<String> {
+        mask.rows().map(|row| row.map(|p| if p.0[0] == 0 { '.' } else { 'S' }).collect()).collect()
+    }
+
+    #[test]
+    fn flood_stops_at_the_subject_and_keeps_its_holes() {
+        let rgb = picture(&["........", ".######.", ".#....#.", ".#.##.#.", ".#....#.", ".######.", "........"]);
+        assert_eq!(
+            subject(&flood_corners(&rgb, 24)),
+            ["........", ".SSSSSS.", ".SSSSSS.", ".SSSSSS.", ".SSSSSS.", ".SSSSSS.", "........"]
+        );
+    }
+
+    #[test]
+    fn flood_follows_winding_backdrop() {
+        // The backdrop snakes up and down between the walls, so runs have to be
+        // picked up both above and below
+        let rgb = picture(&["..#....", ".##.##.", ".#..#..", ".#.##.#", "...#..."]);
+        assert_eq!(subject(&flood_corners(&rgb, 24)), ["..S....", ".SS.SS.", ".S..S..", ".S.SS.S", "...S..."]);
+    }
+
+    #[test]
+    fn each_corner_floods_with_its_own_color() {
+        // Dark top-left corner, light everywhere else: both are backdrop, the dark
+        // column that doesn't touch a corner isn't
+        let rgb = picture(&["##....", "#...#.", "....#.", "......"]);
+        assert_eq!(subject(&flood_corners(&rgb, 24)), ["......", "....S.", "....S.", "......"]);
+    }
+}
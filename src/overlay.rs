@@ -0,0 +1,210 @@
+// Text drawn over (or under) the mosaic: the debug line and an optional legend.
+// Text is measured glyph by glyph with ab_glyph so the backing box fits exactly, the
+// font grows with the image, and the block can sit in any corner or in a footer band
+// added below the art so it doesn't cover anything.
+
+use ab_glyph::{Font, FontVec, PxScale, ScaleFont};
+use image::{Rgba, RgbaImage};
+use imageproc::drawing::{draw_filled_rect_mut, draw_text_mut};
+use imageproc::rect::Rect;
+use serde::{Deserialize, Serialize};
+
+use crate::dicelib::{font, DiceSides};
+use crate::pipeline::{Mosaic, Settings};
+
+const BACKGROUND: Rgba<u8> = Rgba([0, 0, 0, 255]);
+const TEXT: Rgba<u8> = Rgba([255, 255, 255, 255]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverlayPosition {
+    #[default]
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    /// A band added under the art, the mosaic itself stays untouched.
+    Footer,
+}
+
+impl OverlayPosition {
+    pub fn parse(s: &str) -> Option<OverlayPosition> {
+        match s.trim().to_ascii_lowercase().replace('_', "-").as_str() {
+            "top-left" => Some(OverlayPosition::TopLeft),
+            "top-right" => Some(OverlayPosition::TopRight),
+            "bottom-left" => Some(OverlayPosition::BottomLeft),
+            "bottom-right" => Some(OverlayPosition::BottomRight),
+            "footer" => Some(OverlayPosition::Footer),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OverlayOptions {
+    pub position: OverlayPosition,
+    /// Add face counts, thresholds and the source file under the debug line.
+    pub legend: bool,
+    /// Font size in pixels; scales with the image when unset.
+    pub font_size: Option<f32>,
+}
+
+/// Font size that reads well on a `width`x`height` image.
+pub fn auto_font_size((width, height): (u32, u32)) -> f32 {
+    (width.min(height) as f32 / 50.0).clamp(14.0, 96.0)
+}
+
+/// Width of `text` in pixels, kerning included.
+pub fn text_width(font: &FontVec, scale: PxScale, text: &str) -> f32 {
+    let scaled = font.as_scaled(scale);
+    let mut width = 0.0;
+    let mut previous = None;
+    for c in text.chars() {
+        let glyph = scaled.glyph_id(c);
+        if let Some(previous) = previous {
+            width += scaled.kern(previous, glyph);
+        }
+        width += scaled.h_advance(glyph);
+        previous = Some(glyph);
+    }
+    width
+}
+
+/// Height of one line of text, line gap included.
+pub fn line_height(font: &FontVec, scale: PxScale) -> f32 {
+    let scaled = font.as_scaled(scale);
+    scaled.height() + scaled.line_gap()
+}
+
+/// The lines for a mosaic: the classic debug line, plus the legend if asked for.
+pub fn overlay_lines(mosaic: &Mosaic, settings: &Settings) -> Vec<String> {
+    let d = settings.tile_size();
+    let mut lines = vec![format!(
+        "Dice size: {}x{}, Total dice: {}, Image size: {}x{}",
+        d,
+        d,
+        mosaic.total_dice(),
+        mosaic.size.0,
+        mosaic.size.1
+    )];
+    if !settings.overlay.legend {
+        return lines;
+    }
+
+    let mut counts = [0u32; 6];
+    for p in &mosaic.placements {
+        counts[p.side.index()] += 1;
+    }
+    let faces: Vec<String> = DiceSides::ALL
+        .iter()
+        .map(|side| format!("{}: {}", side.pips(), counts[side.index()]))
+        .collect();
+    lines.push(format!("Faces  {}", faces.join("   ")));
+
+    let bounds: Vec<String> = settings.thresholds().0.iter().map(|b| b.to_string()).collect();
+    let origin = match settings.thresholds {
+        Some(_) => "custom".to_string(),
+        None => settings.preset.name().to_string(),
+    };
+    lines.push(format!("Thresholds  {} ({})", bounds.join(" / "), origin));
+    if let Some(source) = &mosaic.source {
+        lines.push(format!("Source  {}", source));
+    }
+    lines
+}
+
+/// A laid-out block of text for one canvas. Build it, then `draw` it onto the whole
+/// canvas or onto each strip of it.
+pub struct Overlay {
+    lines: Vec<String>,
+    font: FontVec,
+    scale: PxScale,
+    /// Box around the text, in canvas coordinates.
+    rect: (u32, u32, u32, u32),
+    padding: u32,
+    line_height: u32,
+    footer: u32,
+}
+
+impl Overlay {
+    /// Lays `lines` out on an art area of `art` size. A footer grows the canvas instead
+    /// of covering the art, see `canvas_size`.
+    pub fn new(lines: Vec<String>, art: (u32, u32), position: OverlayPosition, font_size: Option<f32>) -> Overlay {
+        let font = font();
+        let size = font_size.unwrap_or_else(|| auto_font_size(art));
+        let scale = PxScale::from(size);
+        let padding = (size * 0.3).ceil() as u32;
+        let line_height = line_height(&font, scale).ceil() as u32;
+        let text_w = lines
+            .iter()
+            .map(|line| text_width(&font, scale, line).ceil() as u32)
+            .max()
+            .unwrap_or(0);
+        let (w, h) = (text_w + 2 * padding, line_height * lines.len() as u32 + 2 * padding);
+
+        let (aw, ah) = art;
+        let (x, y, footer) = match position {
+            OverlayPosition::TopLeft => (0, 0, 0),
+            OverlayPosition::TopRight => (aw.saturating_sub(w), 0, 0),
+            OverlayPosition::BottomLeft => (0, ah.saturating_sub(h), 0),
+            OverlayPosition::BottomRight => (aw.saturating_sub(w), ah.saturating_sub(h), 0),
+            OverlayPosition::Footer => (0, ah, h),
+        };
+        // The footer band spans the full width
+        let w = if footer > 0 { w.max(aw) } else { w };
+
+        Overlay {
+            lines,
+            font,
+            scale,
+            rect: (x, y, w, h),
+            padding,
+            line_height,
+            footer,
+        }
+    }
+
+    /// The overlay a mosaic gets from its settings, if any.
+    pub fn for_mosaic(mosaic: &Mosaic, settings: &Settings) -> Option<Overlay> {
+        if !settings.add_debug && !settings.overlay.legend {
+            return None;
+        }
+        Some(Overlay::new(
+            overlay_lines(mosaic, settings),
+            mosaic.size,
+            settings.overlay.position,
+            settings.overlay.font_size,
+        ))
+    }
+
+    /// Canvas size needed for `art`: the same, or taller by the footer band (and wider
+    /// if the text is wider than the art).
+    pub fn canvas_size(&self, (w, h): (u32, u32)) -> (u32, u32) {
+        if self.footer > 0 {
+            (w.max(self.rect.2), h + self.footer)
+        } else {
+            (w, h)
+        }
+    }
+
+    /// Moves the block, for when the art itself moves on the canvas (rulers add a margin).
+    pub fn shift(&mut self, dx: u32, dy: u32) {
+        self.rect.0 += dx;
+        self.rect.1 += dy;
+    }
+
+    /// Draws onto `image`, which holds canvas rows starting at `y0` (0 for a whole canvas).
+    pub fn draw(&self, image: &mut RgbaImage, y0: u32) {
+        let (x, y, w, h) = self.rect;
+        if y + h <= y0 || y >= y0 + image.height() || w == 0 {
+            return;
+        }
+        let top = y as i32 - y0 as i32;
+        draw_filled_rect_mut(image, Rect::at(x as i32, top).of_size(w, h), BACKGROUND);
+        for (i, line) in self.lines.iter().enumerate() {
+            let line_y = top + (self.padding + i as u32 * self.line_height) as i32;
+            draw_text_mut(image, TEXT, (x + self.padding) as i32, line_y, self.scale, &self.font, line);
+        }
+    }
+}
@@ -0,0 +1,93 @@
+// Dice colors. The tile images are treated as a tone map: black parts of a die take
+// the `dark` color, white parts the `light` one, everything in between is blended.
+// So the stock white dice with black pips can come out as ivory dice with red pips.
+
+use image::{DynamicImage, Rgba};
+use serde::{Deserialize, Serialize};
+
+/// An sRGB color, written as "#rrggbb" in job files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Color(pub [u8; 3]);
+
+impl Color {
+    pub const BLACK: Color = Color([0, 0, 0]);
+    pub const WHITE: Color = Color([255, 255, 255]);
+
+    pub fn parse(s: &str) -> Result<Color, String> {
+        let hex = s.trim().trim_start_matches('#');
+        let bad = || format!("Expected a color like #ff8800, got {:?}", s);
+        let expanded = match hex.len() {
+            3 => hex.chars().flat_map(|c| [c, c]).collect::<String>(),
+            6 => hex.to_string(),
+            _ => return Err(bad()),
+        };
+        let channel = |i: usize| u8::from_str_radix(&expanded[i..i + 2], 16).map_err(|_| bad());
+        Ok(Color([channel(0)?, channel(2)?, channel(4)?]))
+    }
+
+    pub fn hex(&self) -> String {
+        format!("#{:02x}{:02x}{:02x}", self.0[0], self.0[1], self.0[2])
+    }
+}
+
+impl TryFrom<String> for Color {
+    type Error = String;
+    fn try_from(s: String) -> Result<Color, String> {
+        Color::parse(&s)
+    }
+}
+
+impl From<Color> for String {
+    fn from(c: Color) -> String {
+        c.hex()
+    }
+}
+
+/// Two-color palette for the dice. With the stock dice `dark` colors the pips and
+/// `light` the body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Palette {
+    pub dark: Color,
+    pub light: Color,
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette {
+            dark: Color::BLACK,
+            light: Color::WHITE,
+        }
+    }
+}
+
+impl Palette {
+    /// "dark,light", e.g. "#8b0000,#fffff0".
+    pub fn parse(s: &str) -> Result<Palette, String> {
+        let (dark, light) = s
+            .split_once(',')
+            .ok_or_else(|| format!("Expected two colors like #000000,#ffffff, got {:?}", s))?;
+        Ok(Palette {
+            dark: Color::parse(dark)?,
+            light: Color::parse(light)?,
+        })
+    }
+
+    /// Color for a tone, 0 = dark, 255 = light.
+    pub fn at(&self, tone: u8) -> [u8; 3] {
+        let t = tone as u32;
+        core::array::from_fn(|i| ((self.dark.0[i] as u32 * (255 - t) + self.light.0[i] as u32 * t + 127) / 255) as u8)
+    }
+
+    /// Recolors a tile, keeping its alpha.
+    pub fn apply(&self, image: &DynamicImage) -> DynamicImage {
+        let mut rgba = image.to_rgba8();
+        let luma = image.to_luma8();
+        for (px, tone) in rgba.pixels_mut().zip(luma.pixels()) {
+            let [r, g, b] = self.at(tone[0]);
+            *px = Rgba([r, g, b, px[3]]);
+        }
+        DynamicImage::ImageRgba8(rgba)
+    }
+}
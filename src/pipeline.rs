@@ -0,0 +1,479 @@
+// One render start to finish, no prompts. The interactive CLI asks its questions,
+// fills in a `Settings` and hands over; batch mode does the same from flags, and
+// job files straight from TOML/JSON.
+
+use image::{imageops, GrayImage, RgbaImage};
+use serde::{Deserialize, Serialize};
+
+use crate::alpha::Alpha;
+use crate::anneal::Anneal;
+use crate::cache::TileCache;
+use crate::dicelib::{load_dice_dir, Dice, IntensityPreset, Thresholds};
+use crate::dither::{assign_dithered, assign_levels, cell_levels, Dither};
+use crate::grayscale::Grayscale;
+use crate::integral::IntegralImage;
+use crate::inventory::Inventory;
+use crate::layout::{Cell, Layout, LayoutKind};
+use crate::mask::Subject;
+use crate::overlay::{Overlay, OverlayOptions};
+use crate::palette::Palette;
+use crate::preprocess::{self, Op};
+use crate::quadtree::{quadtree_cells, QuadtreeOptions};
+use crate::render::{canvas_size, render_mosaic, Placement, TileSet};
+use crate::rulers::{RulerOptions, Rulers};
+use crate::text::Ramp;
+
+/// How the input is cropped before the dice go on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Fit {
+    /// Biggest square from the top-left corner. What it always did.
+    #[default]
+    Square,
+    /// Biggest square from the middle.
+    Center,
+    /// No crop, the whole image.
+    Full,
+}
+
+impl Fit {
+    pub const ALL: [Fit; 3] = [Fit::Square, Fit::Center, Fit::Full];
+
+    pub fn parse(s: &str) -> Option<Fit> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "square" => Some(Fit::Square),
+            "center" | "centre" => Some(Fit::Center),
+            "full" | "none" => Some(Fit::Full),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Fit::Square => "square",
+            Fit::Center => "center",
+            Fit::Full => "full",
+        }
+    }
+}
+
+/// Everything that decides what the mosaic looks like. This is also the `[render]`
+/// table of a job file, with the same field names.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub dice_size: u32,
+    pub layout: LayoutKind,
+    pub quadtree: Option<QuadtreeOptions>,
+    pub preset: IntensityPreset,
+    /// Custom face bounds, used instead of the preset's.
+    pub thresholds: Option<Thresholds>,
+    pub dither: Dither,
+    /// Dice on hand. Faces are handed out within the counts instead of by threshold alone.
+    pub inventory: Option<Inventory>,
+    /// Refine the faces as a whole against a blurred view, see `anneal::Anneal`.
+    pub anneal: Option<Anneal>,
+    pub fit: Fit,
+    /// Recolor the dice, `None` keeps the images as they are.
+    pub palette: Option<Palette>,
+    /// Turn the input upright per its EXIF orientation.
+    pub auto_orient: bool,
+    /// How a color input is turned into luma.
+    pub grayscale: Grayscale,
+    /// What transparent parts of the input turn into.
+    pub alpha: Alpha,
+    /// Dice on the subject only, see `mask::Subject`.
+    pub mask: Option<Subject>,
+    /// Average each cell in linear light (at 16 bits) instead of on the encoded
+    /// values, so mixed light and dark cells read as light as they look.
+    pub linear_light: bool,
+    pub invert_input: bool,
+    /// Tone steps run on the cropped input, in order.
+    pub preprocess: Vec<Op>,
+    pub invert_dice: bool,
+    /// Center-fit the (cropped) input into this canvas before laying out dice.
+    pub output_size: Option<(u32, u32)>,
+    pub add_debug: bool,
+    /// Where the debug text goes and whether it carries the legend.
+    pub overlay: OverlayOptions,
+    /// Numbered margins and section lines, for building from the preview.
+    pub rulers: Option<RulerOptions>,
+    /// Characters for the text outputs, darkest first; die faces when unset.
+    pub ramp: Option<Ramp>,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            dice_size: 32,
+            layout: LayoutKind::Grid,
+            quadtree: None,
+            preset: IntensityPreset::Default,
+            thresholds: None,
+            dither: Dither::None,
+            inventory: None,
+            anneal: None,
+            fit: Fit::Square,
+            palette: None,
+            auto_orient: true,
+            grayscale: Grayscale::default(),
+            alpha: Alpha::default(),
+            mask: None,
+            linear_light: false,
+            invert_input: false,
+            preprocess: Vec::new(),
+            invert_dice: false,
+            output_size: None,
+            add_debug: false,
+            overlay: OverlayOptions::default(),
+            rulers: None,
+            ramp: None,
+        }
+    }
+}
+
+impl Settings {
+    /// Size the dice get loaded at. Quadtree scales down from its biggest die.
+    pub fn tile_size(&self) -> u32 {
+        match &self.quadtree {
+            Some(q) => q.max_size.max(q.min_size),
+            None => self.dice_size,
+        }
+    }
+
+    /// The face bounds in use: the custom ones if set, otherwise the preset's.
+    pub fn thresholds(&self) -> Thresholds {
+        self.thresholds.unwrap_or_else(|| Thresholds::from_preset(&self.preset))
+    }
+
+    /// Catches values that parse fine but can't be rendered, e.g. from a job file.
+    pub fn check(&self) -> Result<(), String> {
+        if let Some(q) = &self.quadtree {
+            q.check()?;
+        }
+        for op in &self.preprocess {
+            op.check()?;
+        }
+        if let Some(anneal) = &self.anneal {
+            anneal.check()?;
+        }
+        self.alpha.check()?;
+        Ok(())
+    }
+
+    /// Smallest share of a cell a mask has to cover for it to get a die from the input,
+    /// when cells are cut out at all. The subject mask's wins over the alpha one.
+    pub fn coverage(&self) -> Option<f32> {
+        match &self.mask {
+            Some(subject) => Some(subject.coverage),
+            None => self.alpha.coverage(),
+        }
+    }
+}
+
+/// The face decisions for one input, before any pixels are painted.
+pub struct Mosaic {
+    pub placements: Vec<Placement>,
+    /// Output canvas size.
+    pub size: (u32, u32),
+    /// Size of the prepared input the dice were sampled from.
+    pub input_size: (u32, u32),
+    /// Name of the input, for the legend.
+    pub source: Option<String>,
+    /// Dice the inventory is short by. The faces are the unconstrained ones when it is.
+    pub short: u32,
+}
+
+impl Mosaic {
+    pub fn total_dice(&self) -> u32 {
+        self.placements.len() as u32
+    }
+
+    /// Whether there's a mosaic worth rendering: at least one die, and enough of them.
+    pub fn check(&self) -> Result<(), String> {
+        if self.placements.is_empty() {
+            return Err(format!(
+                "Input image ({}x{}) is smaller than a single die. Pick smaller dice.",
+                self.input_size.0, self.input_size.1
+            ));
+        }
+        if self.short > 0 {
+            return Err(format!(
+                "The mosaic needs {} dice, {} more than the inventory has. Pick bigger dice or add some.",
+                self.total_dice(),
+                self.short
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Loads the dice directory at the size the settings need, inverted if asked,
+/// and gets every tile size ready. Load once, render as many inputs as you like.
+pub fn load_tiles(dice_dir: &str, settings: &Settings, cache: Option<&TileCache>) -> Result<TileSet, String> {
+    let dice = load_dice_dir(dice_dir, settings.tile_size(), cache)?;
+    Ok(tile_set(dice, settings))
+}
+
+/// Same as `load_tiles` for dice you already have.
+pub fn tile_set(mut dice: [Dice; 6], settings: &Settings) -> TileSet {
+    for d in &mut dice {
+        if settings.invert_dice {
+            d.image.invert();
+        }
+        if let Some(palette) = &settings.palette {
+            d.image = palette.apply(&d.image);
+        }
+    }
+    let mut tiles = TileSet::new(&dice);
+    if let Some(q) = &settings.quadtree {
+        // Every size a quadtree can produce: max, max/2, ... down to min
+        let mut size = q.max_size.max(q.min_size);
+        while size >= q.min_size.max(1) {
+            tiles.prepare(size, size);
+            size /= 2;
+        }
+    }
+    tiles
+}
+
+/// Inverts, crops (per `fit`), runs the preprocessing steps and fits into the output
+/// size, in that order.
+pub fn prepare_input(mut input: GrayImage, settings: &Settings) -> GrayImage {
+    if settings.invert_input {
+        imageops::invert(&mut input);
+    }
+    let mut input = crop(input, settings.fit);
+    preprocess::apply_all(&settings.preprocess, &mut input);
+
+    match settings.output_size {
+        Some((ow, oh)) => fit_into(&input, ow, oh),
+        None => input,
+    }
+}
+
+/// Crops and fits a mask (alpha and/or subject, see `dicelib::split_input`) the same
+/// way `prepare_input` does the image, so the two stay lined up. The bars `fit_into`
+/// adds count as cut out.
+pub fn prepare_alpha(alpha: GrayImage, settings: &Settings) -> GrayImage {
+    let alpha = crop(alpha, settings.fit);
+    match settings.output_size {
+        Some((ow, oh)) => fit_into(&alpha, ow, oh),
+        None => alpha,
+    }
+}
+
+// Crop the input image to a square. Dice grids love squares.
+fn crop(input: GrayImage, fit: Fit) -> GrayImage {
+    let (w, h) = input.dimensions();
+    let square_size = w.min(h);
+    match fit {
+        Fit::Square => imageops::crop_imm(&input, 0, 0, square_size, square_size).to_image(),
+        Fit::Center => {
+            let (x, y) = ((w - square_size) / 2, (h - square_size) / 2);
+            imageops::crop_imm(&input, x, y, square_size, square_size).to_image()
+        }
+        Fit::Full => input,
+    }
+}
+
+/// Scales `input` to fit a `width`x`height` canvas without distorting it and
+/// centers it. The blank bars stay black, so they come out as background dice.
+pub fn fit_into(input: &GrayImage, width: u32, height: u32) -> GrayImage {
+    let mut canvas = GrayImage::new(width, height);
+
+    // Calculate the new dimensions for the original image while maintaining aspect ratio
+    let (input_width, input_height) = input.dimensions();
+    let aspect_ratio = input_width as f32 / input_height as f32;
+    let (new_width, new_height) = if width as f32 / height as f32 > aspect_ratio {
+        // Constrain by height
+        (((height as f32 * aspect_ratio).round() as u32).max(1), height)
+    } else {
+        // Constrain by width
+        (width, ((width as f32 / aspect_ratio).round() as u32).max(1))
+    };
+
+    let scaled = imageops::resize(input, new_width, new_height, imageops::FilterType::Lanczos3);
+
+    // Center the scaled image
+    let offset_x = (width.saturating_sub(new_width) / 2) as i64;
+    let offset_y = (height.saturating_sub(new_height) / 2) as i64;
+    imageops::overlay(&mut canvas, &scaled, offset_x, offset_y);
+    canvas
+}
+
+/// Lays out the cells over a prepared input and picks a face for each.
+pub fn plan(input: &GrayImage, settings: &Settings) -> Mosaic {
+    plan_masked(input, None, settings)
+}
+
+/// `plan` for an input with a prepared mask (see `prepare_alpha`): cells it cuts out
+/// get no die, or the subject mask's fill face. The canvas stays the full size.
+pub fn plan_masked(input: &GrayImage, alpha: Option<&GrayImage>, settings: &Settings) -> Mosaic {
+    let input_size = input.dimensions();
+    let integral = integral(input, settings);
+    let (cells, cut) = masked_cells(input, alpha, settings);
+    let thresholds = settings.thresholds();
+    let fill = settings.mask.as_ref().and_then(Subject::fill_face);
+    // Fill dice come out of the inventory too
+    let mut taken = [0; 6];
+    if let Some(side) = fill {
+        taken[side.index()] = cut.len() as u32;
+    }
+    let short = settings
+        .inventory
+        .as_ref()
+        .map_or(0, |inventory| inventory.short(cells.len() as u32 + taken.iter().sum::<u32>(), taken));
+    let mut levels = preprocess::edge_levels(&settings.preprocess, input, &integral, &cells);
+    // The solver and the optimizer both want every level up front
+    if levels.is_none() && (settings.inventory.is_some() || settings.anneal.is_some()) {
+        levels = Some(cell_levels(&integral, &cells));
+    }
+    let target = settings.anneal.as_ref().and(levels.clone());
+    let mut placements = match (&settings.inventory, levels) {
+        (Some(inventory), Some(levels)) if short == 0 => inventory.assign(levels, &cells, &thresholds, taken),
+        (_, Some(levels)) => assign_levels(levels, &cells, &thresholds, settings.dither),
+        (_, None) => assign_dithered(&integral, &cells, &thresholds, settings.dither),
+    };
+    if let (Some(anneal), Some(target)) = (&settings.anneal, target) {
+        // Face counts the inventory set only move by swapping
+        let keep_counts = short == 0 && settings.inventory.as_ref().is_some_and(|i| i.faces.is_some());
+        anneal.refine(&mut placements, &target, &thresholds, keep_counts);
+    }
+    if let Some(side) = fill {
+        placements.extend(cut.into_iter().map(|cell| Placement { cell, side }));
+        // Back in reading order, so overlapping (hex) dice stack and export like the rest
+        placements.sort_by_key(|p| (p.cell.y, p.cell.x));
+    }
+    let canvas = match &settings.quadtree {
+        Some(_) if alpha.is_some() => cells_extent(&self::cells(input, settings)),
+        Some(_) => canvas_size(&placements),
+        None => layout(input_size, settings).canvas_size(),
+    };
+
+    Mosaic {
+        placements,
+        size: canvas,
+        input_size,
+        source: None,
+        short,
+    }
+}
+
+/// The table cell levels are read from: linear light if the settings ask for it.
+pub fn integral(input: &GrayImage, settings: &Settings) -> IntegralImage {
+    if settings.linear_light {
+        IntegralImage::linear(input)
+    } else {
+        IntegralImage::new(input)
+    }
+}
+
+/// `cells`, less the ones a mask cuts out (too transparent, or not the subject).
+pub fn solid_cells(input: &GrayImage, alpha: Option<&GrayImage>, settings: &Settings) -> Vec<Cell> {
+    masked_cells(input, alpha, settings).0
+}
+
+/// `cells` split into the ones the mask keeps and the ones it cuts out.
+pub fn masked_cells(input: &GrayImage, alpha: Option<&GrayImage>, settings: &Settings) -> (Vec<Cell>, Vec<Cell>) {
+    let cells = cells(input, settings);
+    let (Some(alpha), Some(coverage)) = (alpha, settings.coverage()) else {
+        return (cells, Vec::new());
+    };
+    let opaque = IntegralImage::new(alpha);
+    cells.into_iter().partition(|c| {
+        let area = opaque.area(c.x, c.y, c.w, c.h);
+        area > 0 && opaque.sum(c.x, c.y, c.w, c.h) as f64 >= coverage as f64 * 255.0 * area as f64
+    })
+}
+
+fn cells_extent(cells: &[Cell]) -> (u32, u32) {
+    cells.iter().fold((0, 0), |(w, h), c| (w.max(c.x + c.w), h.max(c.y + c.h)))
+}
+
+/// The cells the dice go in: the layout's, or the quadtree's if it's on.
+pub fn cells(input: &GrayImage, settings: &Settings) -> Vec<Cell> {
+    match &settings.quadtree {
+        Some(q) => quadtree_cells(input, q),
+        None => layout(input.dimensions(), settings).cells(),
+    }
+}
+
+/// The regular layout the settings ask for over an input this size.
+pub(crate) fn layout(input_size: (u32, u32), settings: &Settings) -> Layout {
+    let size = settings.dice_size.max(1);
+    Layout::new(settings.layout, input_size, (size, size))
+}
+
+/// Paints the mosaic, with debug text, legend and rulers if the settings ask for them.
+pub fn render(mosaic: &Mosaic, tiles: &TileSet, settings: &Settings) -> RgbaImage {
+    let decorations = Decorations::new(mosaic, settings);
+    let mut out = render_mosaic(&decorations.placements(mosaic), tiles, decorations.canvas_size());
+    decorations.draw(&mut out, 0);
+    out
+}
+
+/// Everything drawn over and around the dice: the debug text/legend and the rulers.
+/// Works on a whole canvas or strip by strip, so streamed outputs get them too.
+pub struct Decorations {
+    overlay: Option<Overlay>,
+    rulers: Option<Rulers>,
+    size: (u32, u32),
+}
+
+impl Decorations {
+    pub fn new(mosaic: &Mosaic, settings: &Settings) -> Decorations {
+        let mut overlay = Overlay::for_mosaic(mosaic, settings);
+        let mut size = overlay.as_ref().map_or(mosaic.size, |o| o.canvas_size(mosaic.size));
+        let rulers = Rulers::for_mosaic(mosaic, settings, size);
+        if let Some(rulers) = &rulers {
+            let (dx, dy) = rulers.offset();
+            if let Some(overlay) = &mut overlay {
+                overlay.shift(dx, dy);
+            }
+            size = rulers.canvas_size();
+        }
+        Decorations { overlay, rulers, size }
+    }
+
+    /// Size of the final image.
+    pub fn canvas_size(&self) -> (u32, u32) {
+        self.size
+    }
+
+    /// The dice where they land on the final canvas.
+    pub fn placements(&self, mosaic: &Mosaic) -> Vec<Placement> {
+        match &self.rulers {
+            Some(rulers) => rulers.shift(&mosaic.placements),
+            None => mosaic.placements.clone(),
+        }
+    }
+
+    /// Draws onto `image`, which holds canvas rows starting at `y0` (0 for a whole canvas).
+    pub fn draw(&self, image: &mut RgbaImage, y0: u32) {
+        if let Some(rulers) = &self.rulers {
+            rulers.draw(image, y0);
+        }
+        if let Some(overlay) = &self.overlay {
+            overlay.draw(image, y0);
+        }
+    }
+}
+
+/// Prepares the input (and its alpha mask, if any), plans and renders it. `source`
+/// names the input in the legend.
+pub fn run(
+    input: GrayImage,
+    alpha: Option<GrayImage>,
+    tiles: &TileSet,
+    settings: &Settings,
+    source: Option<&str>,
+) -> Result<(Mosaic, RgbaImage), String> {
+    let input = prepare_input(input, settings);
+    let alpha = alpha.map(|a| prepare_alpha(a, settings));
+    let mut mosaic = plan_masked(&input, alpha.as_ref(), settings);
+    mosaic.source = source.map(str::to_string);
+    mosaic.check()?;
+    let out = render(&mosaic, tiles, settings);
+    Ok((mosaic, out))
+}
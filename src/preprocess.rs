@@ -0,0 +1,650 @@
+// Work on the luma image before it's cut into cells. Six faces is a harsh quantizer,
+// so photos usually want their black and white points pulled in, some gamma or
+// contrast, a hand-drawn curve or their histogram spread out first. And averaging
+// over a big die wipes out outlines, so edges can be sharpened or drawn in too.
+// Steps run in the order given, on the cropped input, right before the dice are
+// laid out. `preserve-edges` is the odd one: it works on the cells once they're known.
+//
+// In a job file (`[render]`):
+//
+//     preprocess = [
+//       { op = "levels", black = 20, white = 235 },
+//       { op = "curve", points = [[0, 0], [96, 70], [255, 255]] },
+//     ]
+//
+// On the command line (and in `DiceOptions::set_preprocess`) the same steps are
+// `levels=20,235` and `curve=0:0,96:70,255:255`.
+
+use image::GrayImage;
+use imageproc::edges::canny;
+use imageproc::filter::gaussian_blur_f32;
+use imageproc::gradients::sobel_gradients;
+use serde::{Deserialize, Serialize};
+
+use crate::integral::IntegralImage;
+use crate::layout::Cell;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Op {
+    /// Above 1 brightens the midtones, below 1 darkens them.
+    Gamma { gamma: f64 },
+    /// Maps `black` to 0 and `white` to 255, with an optional midtone gamma.
+    Levels {
+        black: u8,
+        white: u8,
+        #[serde(default = "one")]
+        gamma: f64,
+    },
+    /// Both in -100..100: brightness shifts by that percentage of the range,
+    /// contrast scales around mid-gray (-100 flattens everything, 100 doubles).
+    BrightnessContrast {
+        #[serde(default)]
+        brightness: f64,
+        #[serde(default)]
+        contrast: f64,
+    },
+    /// (input, output) points, straight lines between them and flat past the ends.
+    Curve { points: Vec<(u8, u8)> },
+    /// Spreads the histogram over the whole range, so every face gets used.
+    Equalize,
+    /// Contrast-limited adaptive equalization: each `tile`-pixel square gets its own
+    /// histogram, clipped at `clip` times the average bin so noise isn't blown up,
+    /// blended smoothly between tiles. Brings out local detail equalize flattens.
+    Clahe {
+        #[serde(default = "clahe_tile")]
+        tile: u32,
+        #[serde(default = "clahe_clip")]
+        clip: f64,
+    },
+    /// Adds back `amount` times the difference from a blur of `radius` pixels, where
+    /// that difference is at least `threshold` (so flat noise is left alone).
+    Unsharp {
+        #[serde(default = "unsharp_radius")]
+        radius: f64,
+        #[serde(default = "one")]
+        amount: f64,
+        #[serde(default)]
+        threshold: u8,
+    },
+    /// Draws the edges in dark: the edge map, scaled by `amount` (0 to 1), is taken
+    /// off the luma. Sobel gives soft edges that follow their strength, Canny thin lines.
+    EdgeBoost {
+        #[serde(default)]
+        method: EdgeMethod,
+        #[serde(default = "half")]
+        amount: f64,
+    },
+    /// Darkens a cell a strong edge runs through, by up to `strength` (0 to 1) of its
+    /// level for an edge right across it. An edge is a Sobel step of `threshold` or
+    /// more. Looks at the input after every other step, wherever it's listed.
+    PreserveEdges {
+        #[serde(default = "edge_threshold")]
+        threshold: u8,
+        #[serde(default = "half")]
+        strength: f64,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EdgeMethod {
+    #[default]
+    Sobel,
+    Canny,
+}
+
+impl EdgeMethod {
+    pub fn parse(s: &str) -> Option<EdgeMethod> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "sobel" => Some(EdgeMethod::Sobel),
+            "canny" => Some(EdgeMethod::Canny),
+            _ => None,
+        }
+    }
+}
+
+/// Biggest unsharp radius, in pixels. The blur's kernel grows with it.
+const MAX_RADIUS: f64 = 100.0;
+
+fn one() -> f64 {
+    1.0
+}
+
+fn clahe_tile() -> u32 {
+    64
+}
+
+fn clahe_clip() -> f64 {
+    4.0
+}
+
+fn unsharp_radius() -> f64 {
+    2.0
+}
+
+fn half() -> f64 {
+    0.5
+}
+
+fn edge_threshold() -> u8 {
+    48
+}
+
+impl Op {
+    /// One step in its command-line form: `gamma=1.2`, `levels=20,235[,GAMMA]`,
+    /// `brightness=10`, `contrast=25`, `brightness-contrast=10,25`,
+    /// `curve=IN:OUT,IN:OUT,...`, `equalize`, `clahe[=TILE[,CLIP]]`,
+    /// `unsharp[=RADIUS[,AMOUNT[,THRESHOLD]]]`, `edges[=sobel|canny][,AMOUNT]` or
+    /// `preserve-edges[=THRESHOLD[,STRENGTH]]`.
+    pub fn parse(s: &str) -> Result<Op, String> {
+        let (name, args) = s.trim().split_once('=').unwrap_or((s.trim(), ""));
+        let numbers = |expected: &str| -> Result<Vec<f64>, String> {
+            if args.trim().is_empty() {
+                return Ok(Vec::new());
+            }
+            args.split(',')
+                .map(|v| v.trim().parse::<f64>().map_err(|_| format!("Bad {} value {:?}, expected {}", name, v.trim(), expected)))
+                .collect()
+        };
+        let op = match name.trim().to_ascii_lowercase().as_str() {
+            "gamma" => match numbers("a number")?[..] {
+                [gamma] => Op::Gamma { gamma },
+                _ => return Err("gamma takes one value, e.g. gamma=1.2".to_string()),
+            },
+            "levels" => match numbers("BLACK,WHITE[,GAMMA]")?[..] {
+                [black, white] => Op::Levels {
+                    black: byte(black)?,
+                    white: byte(white)?,
+                    gamma: 1.0,
+                },
+                [black, white, gamma] => Op::Levels {
+                    black: byte(black)?,
+                    white: byte(white)?,
+                    gamma,
+                },
+                _ => return Err("levels takes BLACK,WHITE[,GAMMA], e.g. levels=20,235".to_string()),
+            },
+            "brightness" => match numbers("a number")?[..] {
+                [brightness] => Op::BrightnessContrast { brightness, contrast: 0.0 },
+                _ => return Err("brightness takes one value, e.g. brightness=10".to_string()),
+            },
+            "contrast" => match numbers("a number")?[..] {
+                [contrast] => Op::BrightnessContrast { brightness: 0.0, contrast },
+                _ => return Err("contrast takes one value, e.g. contrast=25".to_string()),
+            },
+            "brightness-contrast" => match numbers("BRIGHTNESS,CONTRAST")?[..] {
+                [brightness, contrast] => Op::BrightnessContrast { brightness, contrast },
+                _ => return Err("brightness-contrast takes BRIGHTNESS,CONTRAST".to_string()),
+            },
+            "curve" => {
+                let points = args
+                    .split(',')
+                    .map(|point| {
+                        let (x, y) = point.split_once(':').ok_or_else(|| format!("Curve points are IN:OUT, got {:?}", point))?;
+                        let value = |v: &str| v.trim().parse::<u8>().map_err(|_| format!("Bad curve value {:?}, expected 0 to 255", v));
+                        Ok((value(x)?, value(y)?))
+                    })
+                    .collect::<Result<Vec<_>, String>>()?;
+                Op::Curve { points }
+            }
+            "equalize" => match numbers("nothing")?[..] {
+                [] => Op::Equalize,
+                _ => return Err("equalize doesn't take values".to_string()),
+            },
+            "clahe" => match numbers("TILE[,CLIP]")?[..] {
+                [] => Op::Clahe {
+                    tile: clahe_tile(),
+                    clip: clahe_clip(),
+                },
+                [tile] => Op::Clahe {
+                    tile: tile as u32,
+                    clip: clahe_clip(),
+                },
+                [tile, clip] => Op::Clahe { tile: tile as u32, clip },
+                _ => return Err("clahe takes TILE[,CLIP], e.g. clahe=64,4".to_string()),
+            },
+            "unsharp" => match numbers("RADIUS[,AMOUNT[,THRESHOLD]]")?[..] {
+                [] => Op::Unsharp {
+                    radius: unsharp_radius(),
+                    amount: 1.0,
+                    threshold: 0,
+                },
+                [radius] => Op::Unsharp {
+                    radius,
+                    amount: 1.0,
+                    threshold: 0,
+                },
+                [radius, amount] => Op::Unsharp {
+                    radius,
+                    amount,
+                    threshold: 0,
+                },
+                [radius, amount, threshold] => Op::Unsharp {
+                    radius,
+                    amount,
+                    threshold: byte(threshold)?,
+                },
+                _ => return Err("unsharp takes RADIUS[,AMOUNT[,THRESHOLD]], e.g. unsharp=2,1".to_string()),
+            },
+            "edges" | "edge-boost" => {
+                let mut method = EdgeMethod::default();
+                let mut amount = half();
+                for arg in args.split(',').map(str::trim).filter(|a| !a.is_empty()) {
+                    match (EdgeMethod::parse(arg), arg.parse::<f64>()) {
+                        (Some(m), _) => method = m,
+                        (None, Ok(a)) => amount = a,
+                        _ => return Err(format!("Bad edges value {:?}, expected sobel, canny or an amount", arg)),
+                    }
+                }
+                Op::EdgeBoost { method, amount }
+            }
+            "preserve-edges" => match numbers("THRESHOLD[,STRENGTH]")?[..] {
+                [] => Op::PreserveEdges {
+                    threshold: edge_threshold(),
+                    strength: half(),
+                },
+                [threshold] => Op::PreserveEdges {
+                    threshold: byte(threshold)?,
+                    strength: half(),
+                },
+                [threshold, strength] => Op::PreserveEdges {
+                    threshold: byte(threshold)?,
+                    strength,
+                },
+                _ => return Err("preserve-edges takes THRESHOLD[,STRENGTH], e.g. preserve-edges=48,0.5".to_string()),
+            },
+            other => return Err(format!("Unknown preprocessing step {:?}", other)),
+        };
+        op.check()?;
+        Ok(op)
+    }
+
+    /// Several steps separated by `;`, e.g. `levels=20,235; gamma=1.1`.
+    pub fn parse_list(s: &str) -> Result<Vec<Op>, String> {
+        s.split(';').filter(|step| !step.trim().is_empty()).map(Op::parse).collect()
+    }
+
+    /// Catches values that would make a step meaningless. Steps read from a job file
+    /// only get here through `Settings::check`.
+    pub fn check(&self) -> Result<(), String> {
+        let numbers = match self {
+            Op::Gamma { gamma } | Op::Levels { gamma, .. } => vec![*gamma],
+            Op::BrightnessContrast { brightness, contrast } => vec![*brightness, *contrast],
+            Op::Clahe { clip, .. } => vec![*clip],
+            Op::Unsharp { radius, amount, .. } => vec![*radius, *amount],
+            Op::EdgeBoost { amount, .. } => vec![*amount],
+            Op::PreserveEdges { strength, .. } => vec![*strength],
+            Op::Curve { .. } | Op::Equalize => Vec::new(),
+        };
+        if let Some(bad) = numbers.iter().find(|v| !v.is_finite()) {
+            return Err(format!("Preprocessing values have to be finite numbers, got {}", bad));
+        }
+        match self {
+            Op::Gamma { gamma } | Op::Levels { gamma, .. } if *gamma <= 0.0 => {
+                Err(format!("Gamma has to be above 0, got {}", gamma))
+            }
+            Op::Levels { black, white, .. } if black >= white => {
+                Err(format!("Levels need black below white, got {} and {}", black, white))
+            }
+            Op::Curve { points } if points.len() < 2 => Err("A curve needs at least 2 points".to_string()),
+            Op::Clahe { tile, .. } if *tile < 8 => Err(format!("CLAHE tiles need to be at least 8 pixels, got {}", tile)),
+            Op::Clahe { clip, .. } if *clip < 1.0 => Err(format!("CLAHE clip limit has to be at least 1, got {}", clip)),
+            Op::Unsharp { radius, .. } if *radius <= 0.0 || *radius > MAX_RADIUS => {
+                Err(format!("Unsharp radius goes from above 0 to {}, got {}", MAX_RADIUS, radius))
+            }
+            Op::Unsharp { amount, .. } if *amount < 0.0 => Err(format!("Unsharp amount can't be negative, got {}", amount)),
+            Op::EdgeBoost { amount, .. } if !(0.0..=1.0).contains(amount) => {
+                Err(format!("Edge amount goes from 0 to 1, got {}", amount))
+            }
+            Op::PreserveEdges { strength, .. } if !(0.0..=1.0).contains(strength) => {
+                Err(format!("Edge strength goes from 0 to 1, got {}", strength))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The step as a lookup table, for the ones that only look at one pixel at a time.
+    fn lut(&self) -> Option<[u8; 256]> {
+        let map = |f: &dyn Fn(f64) -> f64| {
+            let mut lut = [0u8; 256];
+            for (v, out) in lut.iter_mut().enumerate() {
+                *out = f(v as f64).round().clamp(0.0, 255.0) as u8;
+            }
+            lut
+        };
+        let gamma = |x: f64, gamma: f64| x.clamp(0.0, 1.0).powf(1.0 / gamma.max(0.01));
+        Some(match self {
+            Op::Gamma { gamma: g } => map(&|v| gamma(v / 255.0, *g) * 255.0),
+            Op::Levels { black, white, gamma: g } => {
+                let (black, range) = (*black as f64, (*white as f64 - *black as f64).max(1.0));
+                map(&|v| gamma((v - black) / range, *g) * 255.0)
+            }
+            Op::BrightnessContrast { brightness, contrast } => {
+                let factor = 1.0 + contrast.clamp(-100.0, 100.0) / 100.0;
+                let shift = brightness.clamp(-100.0, 100.0) * 2.55;
+                map(&|v| (v - 127.5) * factor + 127.5 + shift)
+            }
+            Op::Curve { points } => {
+                let mut points = points.clone();
+                points.sort_by_key(|p| p.0);
+                map(&|v| curve_at(&points, v))
+            }
+            Op::Equalize | Op::Clahe { .. } | Op::Unsharp { .. } | Op::EdgeBoost { .. } | Op::PreserveEdges { .. } => {
+                return None;
+            }
+        })
+    }
+
+    pub fn apply(&self, image: &mut GrayImage) {
+        if let Some(lut) = self.lut() {
+            apply_lut(image, &lut);
+            return;
+        }
+        match self {
+            Op::Equalize => {
+                let lut = equalize_lut(&histogram(image.pixels().map(|p| p.0[0])), u32::MAX);
+                apply_lut(image, &lut);
+            }
+            Op::Clahe { tile, clip } => clahe(image, (*tile).max(8), *clip),
+            Op::Unsharp { radius, amount, threshold } => {
+                let blurred = gaussian_blur_f32(image, *radius as f32);
+                for (p, b) in image.pixels_mut().zip(blurred.pixels()) {
+                    let detail = p.0[0] as f64 - b.0[0] as f64;
+                    if detail.abs() >= *threshold as f64 {
+                        p.0[0] = (p.0[0] as f64 + amount * detail).round().clamp(0.0, 255.0) as u8;
+                    }
+                }
+            }
+            Op::EdgeBoost { method, amount } => {
+                let edges = edge_map(image, *method);
+                for (p, e) in image.pixels_mut().zip(edges) {
+                    p.0[0] = (p.0[0] as f64 - amount * e as f64).round().clamp(0.0, 255.0) as u8;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Runs every step in order.
+pub fn apply_all(ops: &[Op], image: &mut GrayImage) {
+    for op in ops {
+        op.apply(image);
+    }
+}
+
+/// Edge strength per pixel, 0 to 255. Sobel is scaled so a hard step of N levels
+/// comes out around N; Canny is 255 on its lines and 0 elsewhere.
+fn edge_map(image: &GrayImage, method: EdgeMethod) -> Vec<u8> {
+    match method {
+        EdgeMethod::Sobel => sobel_gradients(image).pixels().map(|g| (g.0[0] / 4).min(255) as u8).collect(),
+        EdgeMethod::Canny => canny(image, 50.0, 120.0).into_raw(),
+    }
+}
+
+/// Mean level of every cell, with `preserve-edges` applied if it's in `ops`: `None`
+/// when it isn't, so callers can keep their plain path.
+pub fn edge_levels(ops: &[Op], input: &GrayImage, integral: &IntegralImage, cells: &[Cell]) -> Option<Vec<f32>> {
+    let (threshold, strength) = ops.iter().rev().find_map(|op| match op {
+        Op::PreserveEdges { threshold, strength } => Some((*threshold, *strength)),
+        _ => None,
+    })?;
+    let strong = GrayImage::from_raw(
+        input.width(),
+        input.height(),
+        edge_map(input, EdgeMethod::Sobel).into_iter().map(|e| u8::from(e >= threshold)).collect(),
+    )
+    .expect("edge map is the size of the input");
+    let strong = IntegralImage::new(&strong);
+
+    Some(
+        cells
+            .iter()
+            .map(|c| {
+                // An edge straight across a cell lights up about two pixels per row
+                let across = strong.sum(c.x, c.y, c.w, c.h) as f64 / (2 * c.w.max(c.h)) as f64;
+                let level = integral.level(c.x, c.y, c.w, c.h) as f64;
+                (level * (1.0 - strength * across.min(1.0))) as f32
+            })
+            .collect(),
+    )
+}
+
+fn apply_lut(image: &mut GrayImage, lut: &[u8; 256]) {
+    image.pixels_mut().for_each(|p| p.0[0] = lut[p.0[0] as usize]);
+}
+
+fn histogram(values: impl Iterator<Item = u8>) -> [u32; 256] {
+    let mut hist = [0u32; 256];
+    for v in values {
+        hist[v as usize] += 1;
+    }
+    hist
+}
+
+/// Maps each level through the histogram's CDF, stretched so the darkest level in use
+/// lands on 0. Bins above `limit` are clipped first and the excess spread over all of
+/// them, which is what keeps CLAHE from turning flat noise into texture.
+fn equalize_lut(hist: &[u32; 256], limit: u32) -> [u8; 256] {
+    let mut hist = *hist;
+    let mut excess = 0;
+    for bin in hist.iter_mut() {
+        if *bin > limit {
+            excess += *bin - limit;
+            *bin = limit;
+        }
+    }
+    let (share, rest) = (excess / 256, excess % 256);
+    for (i, bin) in hist.iter_mut().enumerate() {
+        *bin += share + u32::from((i as u32) < rest);
+    }
+
+    let total: u32 = hist.iter().sum();
+    let first = hist.iter().copied().find(|&n| n > 0).unwrap_or(0);
+    let mut lut = [0u8; 256];
+    let mut cdf = 0;
+    for (out, &n) in lut.iter_mut().zip(&hist) {
+        cdf += n;
+        *out = if total > first {
+            ((cdf.saturating_sub(first)) as f64 * 255.0 / (total - first) as f64).round() as u8
+        } else {
+            0
+        };
+    }
+    lut
+}
+
+/// One clipped equalization per tile, then every pixel blends the tables of the four
+/// tile centres around it so there are no seams.
+fn clahe(image: &mut GrayImage, tile: u32, clip: f64) {
+    let (w, h) = image.dimensions();
+    if w == 0 || h == 0 {
+        return;
+    }
+    let (nx, ny) = (w.div_ceil(tile), h.div_ceil(tile));
+    let mut luts = Vec::with_capacity((nx * ny) as usize);
+    for ty in 0..ny {
+        for tx in 0..nx {
+            let (x0, y0) = (tx * tile, ty * tile);
+            let (x1, y1) = ((x0 + tile).min(w), (y0 + tile).min(h));
+            let hist = histogram((y0..y1).flat_map(|y| (x0..x1).map(move |x| (x, y))).map(|(x, y)| image.get_pixel(x, y).0[0]));
+            let pixels = (x1 - x0) * (y1 - y0);
+            let limit = ((clip * pixels as f64 / 256.0).ceil() as u32).max(1);
+            luts.push(equalize_lut(&hist, limit));
+        }
+    }
+
+    // Position of a pixel between tile centres: the two tiles and how far toward the second
+    let between = |p: u32, n: u32| -> (usize, usize, f64) {
+        let t = (p as f64 + 0.5) / tile as f64 - 0.5;
+        if t <= 0.0 {
+            return (0, 0, 0.0);
+        }
+        let i = (t.floor() as u32).min(n - 1);
+        if i + 1 >= n {
+            return (i as usize, i as usize, 0.0);
+        }
+        (i as usize, i as usize + 1, t - i as f64)
+    };
+    for y in 0..h {
+        let (y0, y1, fy) = between(y, ny);
+        for x in 0..w {
+            let (x0, x1, fx) = between(x, nx);
+            let p = image.get_pixel_mut(x, y);
+            let v = p.0[0] as usize;
+            let at = |tx: usize, ty: usize| luts[ty * nx as usize + tx][v] as f64;
+            let top = at(x0, y0) * (1.0 - fx) + at(x1, y0) * fx;
+            let bottom = at(x0, y1) * (1.0 - fx) + at(x1, y1) * fx;
+            p.0[0] = (top * (1.0 - fy) + bottom * fy).round() as u8;
+        }
+    }
+}
+
+fn byte(v: f64) -> Result<u8, String> {
+    if (0.0..=255.0).contains(&v) {
+        Ok(v.round() as u8)
+    } else {
+        Err(format!("{} is outside 0 to 255", v))
+    }
+}
+
+/// Piecewise-linear through sorted points, flat before the first and after the last.
+fn curve_at(points: &[(u8, u8)], v: f64) -> f64 {
+    let (Some(first), Some(last)) = (points.first(), points.last()) else {
+        return v;
+    };
+    if v <= first.0 as f64 {
+        return first.1 as f64;
+    }
+    for pair in points.windows(2) {
+        let ((x0, y0), (x1, y1)) = ((pair[0].0 as f64, pair[0].1 as f64), (pair[1].0 as f64, pair[1].1 as f64));
+        if v <= x1 {
+            return if x1 > x0 { y0 + (v - x0) / (x1 - x0) * (y1 - y0) } else { y1 };
+        }
+    }
+    last.1 as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_reads_tone_steps() {
+        assert_eq!(Op::parse("gamma=1.2").unwrap(), Op::Gamma { gamma: 1.2 });
+        assert_eq!(
+            Op::parse("levels=20,235").unwrap(),
+            Op::Levels {
+                black: 20,
+                white: 235,
+                gamma: 1.0
+            }
+        );
+        assert_eq!(
+            Op::parse("contrast=25").unwrap(),
+            Op::BrightnessContrast {
+                brightness: 0.0,
+                contrast: 25.0
+            }
+        );
+        assert_eq!(
+            Op::parse("curve=0:0, 96:70 ,255:255").unwrap(),
+            Op::Curve {
+                points: vec![(0, 0), (96, 70), (255, 255)]
+            }
+        );
+        assert_eq!(Op::parse_list("gamma=1.5; ;gamma=2").unwrap(), vec![Op::Gamma { gamma: 1.5 }, Op::Gamma { gamma: 2.0 }]);
+        assert!(Op::parse("sharpen").is_err());
+        assert!(Op::parse("levels=20").is_err());
+        assert!(Op::parse("curve=0:0,96:300").is_err());
+    }
+
+    #[test]
+    fn check_catches_meaningless_steps() {
+        assert_eq!(
+            Op::parse("edges=canny").unwrap(),
+            Op::EdgeBoost {
+                method: EdgeMethod::Canny,
+                amount: 0.5
+            }
+        );
+        let bad = [
+            Op::Gamma { gamma: 0.0 },
+            Op::Gamma { gamma: f64::NAN },
+            Op::Levels {
+                black: 200,
+                white: 100,
+                gamma: 1.0,
+            },
+            Op::Curve { points: vec![(0, 0)] },
+            Op::Clahe { tile: 4, clip: 4.0 },
+            Op::Clahe { tile: 64, clip: 0.5 },
+            Op::Unsharp {
+                radius: 0.0,
+                amount: 1.0,
+                threshold: 0,
+            },
+            Op::Unsharp {
+                radius: f64::INFINITY,
+                amount: 1.0,
+                threshold: 0,
+            },
+            Op::EdgeBoost {
+                method: EdgeMethod::Sobel,
+                amount: 2.0,
+            },
+            Op::PreserveEdges {
+                threshold: 48,
+                strength: -0.5,
+            },
+        ];
+        for op in bad {
+            assert!(op.check().is_err(), "{:?}", op);
+        }
+        for step in ["gamma=1.2", "levels=0,255", "clahe=8,1", "unsharp=100", "edges=1", "preserve-edges=0,0"] {
+            assert!(Op::parse(step).is_ok(), "{}", step);
+        }
+    }
+
+    #[test]
+    fn equalize_spreads_the_levels_in_use() {
+        let mut hist = [0; 256];
+        hist[100] = 10;
+        hist[101] = 10;
+        hist[150] = 20;
+        let lut = equalize_lut(&hist, u32::MAX);
+        assert_eq!((lut[100], lut[101], lut[150]), (0, 85, 255));
+        assert!(lut.windows(2).all(|w| w[0] <= w[1]));
+    }
+
+    #[test]
+    fn clipping_limits_the_stretch() {
+        // One busy bin: unclipped it jumps straight to white, clipped it can't
+        let mut hist = [1; 256];
+        hist[128] = 10_000;
+        let plain = equalize_lut(&hist, u32::MAX);
+        let clipped = equalize_lut(&hist, 4);
+        assert!(plain[128] - plain[127] > 200);
+        assert!(clipped[128] - clipped[127] < 10);
+        assert!(clipped.windows(2).all(|w| w[0] <= w[1]));
+    }
+
+    #[test]
+    fn clahe_leaves_a_flat_image_flat() {
+        assert_eq!(Op::parse("clahe").unwrap(), Op::Clahe { tile: 64, clip: 4.0 });
+        let mut image = GrayImage::from_pixel(48, 48, image::Luma([90]));
+        clahe(&mut image, 16, 4.0);
+        let first = image.get_pixel(0, 0).0[0];
+        assert!(image.pixels().all(|p| p.0[0] == first));
+    }
+
+    #[test]
+    fn lookup_steps_hit_their_ends() {
+        let levels = Op::parse("levels=20,235").unwrap().lut().unwrap();
+        assert_eq!((levels[0], levels[20], levels[235], levels[255]), (0, 0, 255, 255));
+        let gamma = Op::parse("gamma=2").unwrap().lut().unwrap();
+        assert_eq!((gamma[0], gamma[255]), (0, 255));
+        assert!(gamma[64] > 64);
+    }
+}
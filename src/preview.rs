@@ -0,0 +1,306 @@
+// Terminal preview of a planned mosaic, so you can see it before committing to a huge
+// PNG. Four ways to show it, best first: kitty graphics, sixel, truecolor half-blocks
+// and plain Unicode die faces (⚀-⚅). `Auto` picks the best one the terminal admits to.
+// Everything works from the placements; the image modes paint a thumbnail-sized render.
+
+use std::io::IsTerminal;
+
+use base64::Engine;
+use image::RgbaImage;
+
+use crate::dicelib::DiceSides;
+use crate::layout::Cell;
+use crate::pipeline::Mosaic;
+use crate::render::{render_mosaic, Placement, TileSet};
+use crate::text::cell_grid;
+
+/// Pixels per terminal column we assume for the graphics protocols.
+const CELL_PIXELS: u32 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PreviewMode {
+    /// Whatever the terminal supports.
+    Auto,
+    /// ⚀-⚅, works anywhere with Unicode.
+    Faces,
+    /// ▀ with truecolor foreground/background, two pixels per character.
+    Blocks,
+    Sixel,
+    Kitty,
+}
+
+impl PreviewMode {
+    pub fn parse(s: &str) -> Option<PreviewMode> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "auto" => Some(PreviewMode::Auto),
+            "faces" | "unicode" => Some(PreviewMode::Faces),
+            "blocks" | "ansi" => Some(PreviewMode::Blocks),
+            "sixel" => Some(PreviewMode::Sixel),
+            "kitty" => Some(PreviewMode::Kitty),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PreviewMode::Auto => "auto",
+            PreviewMode::Faces => "faces",
+            PreviewMode::Blocks => "blocks",
+            PreviewMode::Sixel => "sixel",
+            PreviewMode::Kitty => "kitty",
+        }
+    }
+
+    /// Resolves `Auto` from the environment. Graphics only go to a real terminal;
+    /// piped output gets the faces.
+    pub fn resolve(self) -> PreviewMode {
+        if self != PreviewMode::Auto {
+            return self;
+        }
+        if !std::io::stdout().is_terminal() {
+            return PreviewMode::Faces;
+        }
+        let env = |key: &str| std::env::var(key).unwrap_or_default().to_ascii_lowercase();
+        let (term, program) = (env("TERM"), env("TERM_PROGRAM"));
+        if std::env::var_os("KITTY_WINDOW_ID").is_some()
+            || term.contains("kitty")
+            || ["wezterm", "ghostty"].contains(&program.as_str())
+        {
+            PreviewMode::Kitty
+        } else if term.contains("sixel") || term.starts_with("foot") || term.starts_with("mlterm") {
+            PreviewMode::Sixel
+        } else if ["truecolor", "24bit"].contains(&env("COLORTERM").as_str()) {
+            PreviewMode::Blocks
+        } else {
+            PreviewMode::Faces
+        }
+    }
+}
+
+/// Width of the terminal in columns, 80 if it can't be told.
+pub fn terminal_columns() -> u32 {
+    terminal_size::terminal_size()
+        .map(|(w, _)| w.0 as u32)
+        .or_else(|| std::env::var("COLUMNS").ok().and_then(|c| c.parse().ok()))
+        .unwrap_or(80)
+        .max(8)
+}
+
+/// The preview as text ready to print, `columns` wide at most.
+pub fn preview(mosaic: &Mosaic, tiles: &mut TileSet, mode: PreviewMode, columns: u32) -> String {
+    match mode.resolve() {
+        PreviewMode::Auto | PreviewMode::Faces => faces(mosaic, columns),
+        PreviewMode::Blocks => half_blocks(&thumbnail(mosaic, tiles, columns)),
+        PreviewMode::Sixel => sixel(&thumbnail(mosaic, tiles, columns * CELL_PIXELS)),
+        PreviewMode::Kitty => kitty(&thumbnail(mosaic, tiles, columns * CELL_PIXELS), columns)
+            .unwrap_or_else(|_| half_blocks(&thumbnail(mosaic, tiles, columns))),
+    }
+}
+
+/// The mosaic painted at `width` pixels wide (never scaled up). Every die is drawn from
+/// the tile set at its shrunken size, so this is cheap even for huge mosaics.
+pub fn thumbnail(mosaic: &Mosaic, tiles: &mut TileSet, width: u32) -> RgbaImage {
+    let (w, h) = mosaic.size;
+    let s = (width as f64 / w.max(1) as f64).min(1.0);
+    let at = |v: u32| (v as f64 * s).floor() as u32;
+
+    // Edges are scaled, not sizes, so neighbours still touch
+    let placements: Vec<Placement> = mosaic
+        .placements
+        .iter()
+        .map(|p| {
+            let c = p.cell;
+            let cell = Cell {
+                x: at(c.x),
+                y: at(c.y),
+                w: (at(c.x + c.w) - at(c.x)).max(1),
+                h: (at(c.y + c.h) - at(c.y)).max(1),
+                ..c
+            };
+            Placement { cell, side: p.side }
+        })
+        .collect();
+    tiles.prepare_cells(&placements.iter().map(|p| p.cell).collect::<Vec<_>>());
+    render_mosaic(&placements, tiles, (at(w).max(1), at(h).max(1)))
+}
+
+/// Faces on a character grid, a die and a space per position so it comes out roughly
+/// square. Grids wider than the terminal are merged k x k, averaging the pips.
+pub fn faces(mosaic: &Mosaic, columns: u32) -> String {
+    let grid = face_grid(mosaic);
+    let grid_cols = grid.iter().map(Vec::len).max().unwrap_or(0) as u32;
+    let k = grid_cols.div_ceil((columns / 2).max(1)).max(1) as usize;
+
+    let mut out = String::new();
+    for rows in grid.chunks(k) {
+        let width = rows.iter().map(Vec::len).max().unwrap_or(0);
+        for x in (0..width).step_by(k) {
+            let pips: Vec<u32> = rows
+                .iter()
+                .flat_map(|row| row.iter().skip(x).take(k))
+                .flatten()
+                .map(|side| side.pips() as u32)
+                .collect();
+            if pips.is_empty() {
+                out.push_str("  ");
+                continue;
+            }
+            let mean = (pips.iter().sum::<u32>() as f64 / pips.len() as f64).round() as usize;
+            out.push(die_char(DiceSides::ALL[mean.clamp(1, 6) - 1]));
+            out.push(' ');
+        }
+        out.push('\n');
+    }
+    out
+}
+
+pub fn die_char(side: DiceSides) -> char {
+    crate::text::FACES.chars().nth(side.index()).expect("six faces")
+}
+
+fn face_grid(mosaic: &Mosaic) -> Vec<Vec<Option<DiceSides>>> {
+    let cells: Vec<Cell> = mosaic.placements.iter().map(|p| p.cell).collect();
+    let sides: Vec<DiceSides> = mosaic.placements.iter().map(|p| p.side).collect();
+    cell_grid(&cells, &sides)
+}
+
+/// Upper pixel as foreground, lower as background of a ▀, so each character is
+/// two square pixels. Transparent pixels fall back to the terminal's own colors.
+pub fn half_blocks(image: &RgbaImage) -> String {
+    let (w, h) = image.dimensions();
+    let mut out = String::new();
+    for y in (0..h).step_by(2) {
+        for x in 0..w {
+            let top = image.get_pixel(x, y);
+            let bottom = (y + 1 < h).then(|| image.get_pixel(x, y + 1));
+            match (top[3] >= 128, bottom.filter(|b| b[3] >= 128)) {
+                (true, Some(b)) => out.push_str(&format!(
+                    "\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m▀",
+                    top[0], top[1], top[2], b[0], b[1], b[2]
+                )),
+                (true, None) => out.push_str(&format!("\x1b[38;2;{};{};{}m\x1b[49m▀", top[0], top[1], top[2])),
+                (false, Some(b)) => out.push_str(&format!("\x1b[38;2;{};{};{}m\x1b[49m▄", b[0], b[1], b[2])),
+                (false, None) => out.push_str("\x1b[0m "),
+            }
+        }
+        out.push_str("\x1b[0m\n");
+    }
+    out
+}
+
+/// Palette index for a pixel: 40 grays for anything near neutral (dice mostly are),
+/// a 6x6x6 cube for the rest. 256 entries, the most sixel terminals agree on.
+fn sixel_index(r: u8, g: u8, b: u8) -> u8 {
+    let (max, min) = (r.max(g).max(b), r.min(g).min(b));
+    if max - min < 12 {
+        216 + ((r as u32 + g as u32 + b as u32) / 3 * 39 / 255) as u8
+    } else {
+        let q = |v: u8| (v as u32 * 5 + 127) / 255;
+        (q(r) * 36 + q(g) * 6 + q(b)) as u8
+    }
+}
+
+fn sixel_color(index: u8) -> (u32, u32, u32) {
+    // Sixel colors are percentages
+    if index >= 216 {
+        let v = (index - 216) as u32 * 100 / 39;
+        (v, v, v)
+    } else {
+        let i = index as u32;
+        (i / 36 * 20, i / 6 % 6 * 20, i % 6 * 20)
+    }
+}
+
+/// DEC sixel: six pixel rows per band, one pass per color used in the band,
+/// runs compressed with `!n`. Transparent pixels are left unpainted.
+pub fn sixel(image: &RgbaImage) -> String {
+    let (w, h) = image.dimensions();
+    let index: Vec<Option<u8>> = image
+        .pixels()
+        .map(|p| (p[3] >= 128).then(|| sixel_index(p[0], p[1], p[2])))
+        .collect();
+
+    let mut out = format!("\x1bP0;1;0q\"1;1;{};{}", w, h);
+    let mut used = [false; 256];
+    index.iter().flatten().for_each(|&i| used[i as usize] = true);
+    for (i, _) in used.iter().enumerate().filter(|(_, u)| **u) {
+        let (r, g, b) = sixel_color(i as u8);
+        out.push_str(&format!("#{};2;{};{};{}", i, r, g, b));
+    }
+
+    for band in (0..h).step_by(6) {
+        let rows = (h - band).min(6);
+        let mut colors: Vec<u8> = (0..rows)
+            .flat_map(|dy| {
+                let row = ((band + dy) * w) as usize;
+                index[row..row + w as usize].iter().flatten().copied()
+            })
+            .collect();
+        colors.sort_unstable();
+        colors.dedup();
+
+        for (n, &color) in colors.iter().enumerate() {
+            if n > 0 {
+                out.push('$');
+            }
+            out.push_str(&format!("#{}", color));
+            let mut run: Option<(char, u32)> = None;
+            for x in 0..w {
+                let bits = (0..rows)
+                    .filter(|&dy| index[((band + dy) * w + x) as usize] == Some(color))
+                    .fold(0u8, |bits, dy| bits | (1 << dy));
+                let c = (63 + bits) as char;
+                run = match run {
+                    Some((prev, count)) if prev == c => Some((c, count + 1)),
+                    Some((prev, count)) => {
+                        push_run(&mut out, prev, count);
+                        Some((c, 1))
+                    }
+                    None => Some((c, 1)),
+                };
+            }
+            if let Some((c, count)) = run {
+                push_run(&mut out, c, count);
+            }
+        }
+        out.push('-');
+    }
+    out.push_str("\x1b\\\n");
+    out
+}
+
+fn push_run(out: &mut String, c: char, count: u32) {
+    if count > 3 {
+        out.push_str(&format!("!{}{}", count, c));
+    } else {
+        (0..count).for_each(|_| out.push(c));
+    }
+}
+
+/// Kitty graphics protocol: the thumbnail as a base64 PNG in 4K chunks, scaled by the
+/// terminal to `columns` cells wide.
+pub fn kitty(image: &RgbaImage, columns: u32) -> Result<String, String> {
+    let mut png = Vec::new();
+    image
+        .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+        .map_err(|e| e.to_string())?;
+    let data = base64::engine::general_purpose::STANDARD.encode(png);
+
+    let chunks: Vec<&str> = data
+        .as_bytes()
+        .chunks(4096)
+        .map(|c| std::str::from_utf8(c).expect("base64 is ASCII"))
+        .collect();
+    let mut out = String::new();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let more = (i + 1 < chunks.len()) as u8;
+        if i == 0 {
+            out.push_str(&format!("\x1b_Gf=100,a=T,c={},m={};{}\x1b\\", columns, more, chunk));
+        } else {
+            out.push_str(&format!("\x1b_Gm={};{}\x1b\\", more, chunk));
+        }
+    }
+    out.push('\n');
+    Ok(out)
+}
+
@@ -0,0 +1,189 @@
+// Adaptive tiles. Big dice where the image is flat, small dice where there's detail.
+// We start from max-size squares and keep splitting into four while the block is
+// busy (variance above the threshold) and the children would still be >= min size.
+
+use image::GrayImage;
+use serde::{Deserialize, Serialize};
+
+use crate::integral::IntegralImage;
+use crate::layout::Cell;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuadtreeOptions {
+    /// Smallest die, in pixels.
+    pub min_size: u32,
+    /// Largest die, in pixels. Sizes go max, max/2, max/4... down to min.
+    pub max_size: u32,
+    /// Split a block when its luma variance is above this.
+    pub split_variance: f64,
+}
+
+impl QuadtreeOptions {
+    /// Halving has to land on the smallest die exactly, or blocks would leave strips
+    /// uncovered and cells would fall off the smallest die's grid. A variance that
+    /// isn't a number never splits, so that's caught too.
+    pub fn check(&self) -> Result<(), String> {
+        if !(self.split_variance.is_finite() && self.split_variance >= 0.0) {
+            return Err(format!("The quadtree split variance has to be 0 or more, got {}", self.split_variance));
+        }
+        let (min, max) = (self.min_size, self.max_size);
+        if min == 0 {
+            return Err("The smallest quadtree die has to be at least 1 pixel".to_string());
+        }
+        if max < min || max % min != 0 || !(max / min).is_power_of_two() {
+            return Err(format!(
+                "The largest quadtree die has to be the smallest doubled some number of times ({}, {}, {}...), got {}",
+                min,
+                min * 2,
+                min * 4,
+                max
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Splits the image into square cells of varying size. `col`/`row` of each cell
+/// are in units of the smallest die, so they still tell you where it sits.
+pub fn quadtree_cells(input: &GrayImage, opts: &QuadtreeOptions) -> Vec<Cell> {
+    let min = opts.min_size.max(1);
+    let max = opts.max_size.max(min);
+    let (w, h) = input.dimensions();
+    let stats = BlockStats {
+        sums: IntegralImage::new(input),
+        squares: IntegralImage::of_squares(input),
+    };
+
+    let mut cells = Vec::new();
+    for y in (0..h).step_by(max as usize) {
+        for x in (0..w).step_by(max as usize) {
+            split(&stats, x, y, max, min, opts.split_variance, &mut cells);
+        }
+    }
+
+    // Reading order, same as the regular layouts
+    cells.sort_by_key(|c| (c.y, c.x));
+    cells
+}
+
+struct BlockStats {
+    sums: IntegralImage,
+    squares: IntegralImage,
+}
+
+impl BlockStats {
+    fn variance(&self, x: u32, y: u32, size: u32) -> f64 {
+        let n = (size * size) as f64;
+        let mean = self.sums.sum(x, y, size, size) as f64 / n;
+        self.squares.sum(x, y, size, size) as f64 / n - mean * mean
+    }
+}
+
+fn split(stats: &BlockStats, x: u32, y: u32, size: u32, min: u32, threshold: f64, cells: &mut Vec<Cell>) {
+    let (w, h) = stats.sums.dimensions();
+    let fits = x + size <= w && y + size <= h;
+    let can_split = size / 2 >= min;
+
+    // Blocks hanging over the edge get split until they fit. If even the
+    // smallest die doesn't fit, that bit of the image is left out like the grid does.
+    if !fits && !can_split {
+        return;
+    }
+
+    if !fits || (can_split && stats.variance(x, y, size) > threshold) {
+        let half = size / 2;
+        for (dx, dy) in [(0, 0), (half, 0), (0, half), (half, half)] {
+            split(stats, x + dx, y + dy, half, min, threshold, cells);
+        }
+        return;
+    }
+
+    cells.push(Cell {
+        col: x / min,
+        row: y / min,
+        x,
+        y,
+        w: size,
+        h: size,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dicelib::DiceSides;
+    use crate::export::csv;
+    use crate::render::Placement;
+
+    fn options(min_size: u32, max_size: u32) -> QuadtreeOptions {
+        QuadtreeOptions {
+            min_size,
+            max_size,
+            split_variance: 400.0,
+        }
+    }
+
+    /// Flat gray with a checkerboard in the top-left 8x8 corner.
+    fn busy_corner() -> GrayImage {
+        GrayImage::from_fn(32, 32, |x, y| image::Luma([if x < 8 && y < 8 && (x + y) % 2 == 0 { 255 } else { 128 }]))
+    }
+
+    #[test]
+    fn check_wants_the_smallest_die_doubled() {
+        assert!(options(8, 8).check().is_ok());
+        assert!(options(8, 64).check().is_ok());
+        assert!(options(8, 48).check().is_err());
+        assert!(options(8, 4).check().is_err());
+        assert!(options(0, 8).check().is_err());
+        for variance in [-1.0, f64::NAN, f64::INFINITY] {
+            let q = QuadtreeOptions { split_variance: variance, ..options(8, 32) };
+            assert!(q.check().is_err(), "{}", variance);
+        }
+    }
+
+    #[test]
+    fn flat_images_keep_the_biggest_dice() {
+        let cells = quadtree_cells(&GrayImage::from_pixel(64, 32, image::Luma([90])), &options(8, 32));
+        let blocks: Vec<(u32, u32, u32)> = cells.iter().map(|c| (c.x, c.y, c.w)).collect();
+        assert_eq!(blocks, vec![(0, 0, 32), (32, 0, 32)]);
+    }
+
+    #[test]
+    fn detail_splits_down_to_the_smallest_die() {
+        let cells = quadtree_cells(&busy_corner(), &options(8, 32));
+        // The busy 32 block splits into four 16s, the busy 16 into four 8s
+        let sizes: Vec<u32> = cells.iter().map(|c| c.w).collect();
+        assert_eq!(sizes.iter().filter(|&&s| s == 8).count(), 4);
+        assert_eq!(sizes.iter().filter(|&&s| s == 16).count(), 3);
+        assert_eq!(cells.iter().map(|c| c.w * c.h).sum::<u32>(), 32 * 32);
+        let first = cells[0];
+        assert_eq!((first.col, first.row, first.x, first.y, first.w), (0, 0, 0, 0, 8));
+        let last = cells.last().unwrap();
+        assert_eq!((last.col, last.row, last.x, last.y, last.w), (2, 2, 16, 16, 16));
+        assert!(cells.windows(2).all(|w| (w[0].y, w[0].x) < (w[1].y, w[1].x)));
+    }
+
+    #[test]
+    fn edges_split_until_they_fit() {
+        // 40 wide: one 32 block, then the overhang splits to 8s; the last 4 rows are left out
+        let cells = quadtree_cells(&GrayImage::from_pixel(40, 36, image::Luma([90])), &options(8, 32));
+        assert!(cells.iter().all(|c| c.x + c.w <= 40 && c.y + c.h <= 36));
+        assert_eq!(cells.iter().filter(|c| c.w == 32).count(), 1);
+        assert_eq!(cells.iter().filter(|c| c.w == 8).count(), 4);
+    }
+
+    #[test]
+    fn tile_list_has_every_die_with_its_size() {
+        let placements: Vec<Placement> = quadtree_cells(&busy_corner(), &options(8, 32))
+            .into_iter()
+            .map(|cell| Placement { cell, side: DiceSides::Three })
+            .collect();
+        let list = csv(&placements);
+        let lines: Vec<&str> = list.lines().collect();
+        assert_eq!(lines[0], "x,y,width,height,col,row,face");
+        assert_eq!(lines[1], "0,0,8,8,0,0,3");
+        assert_eq!(lines.last(), Some(&"16,16,16,16,2,2,3"));
+        assert_eq!(lines.len(), 1 + placements.len());
+    }
+}
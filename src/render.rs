@@ -0,0 +1,190 @@
+// The heavy lifting: pick a face for every cell, then paint the dice onto the canvas.
+// Both steps go row by row, so with the `parallel` feature rows are handed out to rayon.
+
+use std::collections::HashMap;
+
+use image::{imageops, Pixel, Rgba, RgbaImage};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use crate::dicelib::{Dice, DiceSides, Thresholds};
+use crate::integral::IntegralImage;
+use crate::layout::Cell;
+
+/// A cell with the face we picked for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Placement {
+    pub cell: Cell,
+    pub side: DiceSides,
+}
+
+/// The six faces, already converted to RGBA, at every size the render needs.
+/// Converting once up front is the whole point: no per-cell `to_rgba8()`.
+pub struct TileSet {
+    dice: Vec<Dice>,
+    sized: HashMap<(u32, u32), [RgbaImage; 6]>,
+}
+
+impl TileSet {
+    pub fn new(dice: &[Dice; 6]) -> TileSet {
+        let mut tiles = TileSet {
+            dice: dice.to_vec(),
+            sized: HashMap::new(),
+        };
+        let (w, h) = (dice[0].image.width(), dice[0].image.height());
+        tiles.prepare(w, h);
+        tiles
+    }
+
+    /// Makes sure the faces exist at `w`x`h`. Anything that isn't the loaded size
+    /// is scaled from the loaded dice, so load them at the biggest size you need.
+    pub fn prepare(&mut self, w: u32, h: u32) {
+        if self.sized.contains_key(&(w, h)) {
+            return;
+        }
+        let faces = core::array::from_fn(|i| {
+            let d = self
+                .dice
+                .iter()
+                .find(|d| d.side == DiceSides::ALL[i])
+                .expect("Tile set is missing a face.");
+            if d.image.width() == w && d.image.height() == h {
+                d.image.to_rgba8()
+            } else {
+                d.image.resize_exact(w, h, imageops::FilterType::Lanczos3).to_rgba8()
+            }
+        });
+        self.sized.insert((w, h), faces);
+    }
+
+    /// Prepares every size used by `cells`.
+    pub fn prepare_cells(&mut self, cells: &[Cell]) {
+        for cell in cells {
+            self.prepare(cell.w, cell.h);
+        }
+    }
+
+    pub fn get(&self, side: DiceSides, w: u32, h: u32) -> Option<&RgbaImage> {
+        self.sized.get(&(w, h)).map(|faces| &faces[side.index()])
+    }
+
+    /// Sizes that have been prepared, biggest first.
+    pub fn sizes(&self) -> Vec<(u32, u32)> {
+        let mut sizes: Vec<_> = self.sized.keys().copied().collect();
+        sizes.sort_unstable_by(|a, b| b.cmp(a));
+        sizes
+    }
+}
+
+/// Picks a face for every cell from the block averages. Cells come back in the same order.
+pub fn assign_faces(integral: &IntegralImage, cells: &[Cell], thresholds: &Thresholds) -> Vec<Placement> {
+    let place = |cell: &Cell| Placement {
+        cell: *cell,
+        side: thresholds.side_for(integral.mean(cell.x, cell.y, cell.w, cell.h)),
+    };
+
+    #[cfg(feature = "parallel")]
+    let placements = cells.par_iter().map(place).collect();
+    #[cfg(not(feature = "parallel"))]
+    let placements = cells.iter().map(place).collect();
+
+    placements
+}
+
+/// Size of the canvas that holds every placement.
+pub fn canvas_size(placements: &[Placement]) -> (u32, u32) {
+    placements.iter().fold((0, 0), |(w, h), p| {
+        (w.max(p.cell.x + p.cell.w), h.max(p.cell.y + p.cell.h))
+    })
+}
+
+/// Paints the placements onto a fresh `width`x`height` canvas.
+pub fn render_mosaic(placements: &[Placement], tiles: &TileSet, (width, height): (u32, u32)) -> RgbaImage {
+    render_region(placements, tiles, width, 0, height)
+}
+
+/// Paints canvas rows `y0..y0 + rows` only. Dice outside the region are skipped
+/// and dice straddling its edges are clipped, so a mosaic can be produced strip by strip.
+///
+/// The region is cut into horizontal bands and each band draws every die that
+/// overlaps it, so bands never touch each other's pixels and can run in parallel.
+/// Overlapping layouts (hex) and mixed sizes (quadtree) work the same way.
+pub fn render_region(placements: &[Placement], tiles: &TileSet, width: u32, y0: u32, rows: u32) -> RgbaImage {
+    let mut canvas = RgbaImage::new(width, rows);
+    if width == 0 || rows == 0 {
+        return canvas;
+    }
+    let y_end = y0 + rows;
+
+    let band_h = placements
+        .iter()
+        .map(|p| p.cell.h)
+        .min()
+        .unwrap_or(rows)
+        .clamp(1, rows);
+    let bands = rows.div_ceil(band_h) as usize;
+
+    // Bucket dice by the bands they touch so each band only looks at its own
+    let mut per_band: Vec<Vec<&Placement>> = vec![Vec::new(); bands];
+    for p in placements {
+        let top = p.cell.y.max(y0);
+        let bottom = (p.cell.y + p.cell.h).min(y_end);
+        if top >= bottom {
+            continue;
+        }
+        let first = ((top - y0) / band_h) as usize;
+        let last = ((bottom - 1 - y0) / band_h) as usize;
+        for band in per_band.iter_mut().take(last + 1).skip(first) {
+            band.push(p);
+        }
+    }
+
+    let band_bytes = band_h as usize * width as usize * 4;
+    let paint = |(i, band): (usize, &mut [u8])| {
+        let band_y = y0 + i as u32 * band_h;
+        for p in &per_band[i] {
+            if let Some(tile) = tiles.get(p.side, p.cell.w, p.cell.h) {
+                draw_tile_in_band(band, width, band_y, tile, p.cell.x, p.cell.y);
+            }
+        }
+    };
+
+    #[cfg(feature = "parallel")]
+    canvas.par_chunks_mut(band_bytes).enumerate().for_each(paint);
+    #[cfg(not(feature = "parallel"))]
+    canvas.chunks_mut(band_bytes).enumerate().for_each(paint);
+
+    canvas
+}
+
+/// Blends `tile` at (`x`, `y`) into a band of canvas rows starting at `band_y`,
+/// clipped to the band. Same blending as `imageops::overlay`.
+pub(crate) fn draw_tile_in_band(band: &mut [u8], width: u32, band_y: u32, tile: &RgbaImage, x: u32, y: u32) {
+    let band_rows = (band.len() / (width as usize * 4)) as u32;
+    let y0 = y.max(band_y);
+    let y1 = (y + tile.height()).min(band_y + band_rows);
+    let x1 = (x + tile.width()).min(width);
+    if y0 >= y1 || x >= x1 {
+        return;
+    }
+
+    for cy in y0..y1 {
+        let ty = cy - y;
+        for cx in x..x1 {
+            let src = tile.get_pixel(cx - x, ty);
+            let alpha = src[3];
+            if alpha == 0 {
+                continue;
+            }
+            let idx = ((cy - band_y) as usize * width as usize + cx as usize) * 4;
+            let dst = &mut band[idx..idx + 4];
+            if alpha == 255 {
+                dst.copy_from_slice(&src.0);
+            } else {
+                let mut px = Rgba([dst[0], dst[1], dst[2], dst[3]]);
+                px.blend(src);
+                dst.copy_from_slice(&px.0);
+            }
+        }
+    }
+}
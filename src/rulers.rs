@@ -0,0 +1,260 @@
+// Build aids for working from a preview: column and row numbers in a margin around the
+// mosaic, and a heavier section line every N dice so you can count off blocks. Works
+// for every layout; quadtree mosaics are numbered in units of their smallest die.
+
+use ab_glyph::{FontVec, PxScale};
+use image::{Rgba, RgbaImage};
+use imageproc::drawing::{draw_filled_rect_mut, draw_text_mut};
+use imageproc::rect::Rect;
+use serde::{Deserialize, Serialize};
+
+use crate::dicelib::font;
+use crate::overlay::{line_height, text_width};
+use crate::layout::Layout;
+use crate::pipeline::{self, Mosaic, Settings};
+use crate::render::Placement;
+
+const MARGIN: Rgba<u8> = Rgba([255, 255, 255, 255]);
+const LABEL: Rgba<u8> = Rgba([0, 0, 0, 255]);
+const SECTION: Rgba<u8> = Rgba([220, 30, 30, 255]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RulerOptions {
+    /// Section line every this many dice, 0 for none.
+    pub every: u32,
+    /// Label size in pixels; follows the dice size when unset.
+    pub font_size: Option<f32>,
+}
+
+impl Default for RulerOptions {
+    fn default() -> RulerOptions {
+        RulerOptions {
+            every: 10,
+            font_size: None,
+        }
+    }
+}
+
+/// One column or row of dice along an edge: its number (0-based) and pixel span.
+#[derive(Debug, Clone, Copy)]
+struct Span {
+    index: u32,
+    start: u32,
+    len: u32,
+}
+
+/// Numbered margins plus section lines for one mosaic. The art moves right and down by
+/// `offset()`; `draw` paints the margins and lines on top of the rendered dice.
+pub struct Rulers {
+    cols: Vec<Span>,
+    rows: Vec<Span>,
+    /// The mosaic itself, before the margin.
+    art: (u32, u32),
+    /// What the margin goes around: the art plus anything attached to it (a footer).
+    inner: (u32, u32),
+    margin: u32,
+    every: u32,
+    line_width: u32,
+    label_step: u32,
+    font: FontVec,
+    scale: PxScale,
+    line_height: u32,
+}
+
+impl Rulers {
+    /// `art` is the mosaic size, `inner` the size of the canvas the margin wraps.
+    fn new((cols, rows): (Vec<Span>, Vec<Span>), art: (u32, u32), inner: (u32, u32), opts: &RulerOptions) -> Rulers {
+        let die = cols.iter().map(|s| s.len).min().unwrap_or(16).max(1);
+
+        let font = font();
+        let size = opts.font_size.unwrap_or((die as f32 * 0.6).clamp(10.0, 48.0));
+        let scale = PxScale::from(size);
+        let line_height = line_height(&font, scale).ceil() as u32;
+
+        // Widest label decides the margin and how many columns fit a label each
+        let count = cols.len().max(rows.len()) as u32;
+        let widest = text_width(&font, scale, &count.to_string()).ceil() as u32;
+        let padding = (size * 0.4).ceil() as u32;
+        let margin = (widest + 2 * padding).max(line_height + 2 * padding);
+        let label_step = [1, 2, 5, 10, 20, 50, 100, 200, 500, 1000]
+            .into_iter()
+            .find(|step| step * die >= widest + padding)
+            .unwrap_or(1000);
+
+        Rulers {
+            cols,
+            rows,
+            art,
+            inner,
+            margin,
+            every: opts.every,
+            line_width: (die / 10).max(1),
+            label_step,
+            font,
+            scale,
+            line_height,
+        }
+    }
+
+    /// The rulers a mosaic gets from its settings, if any. `inner` is the canvas the
+    /// margin goes around, see `pipeline::output_canvas`.
+    pub fn for_mosaic(mosaic: &Mosaic, settings: &Settings, inner: (u32, u32)) -> Option<Rulers> {
+        let opts = settings.rulers.as_ref()?;
+        let axes = match &settings.quadtree {
+            Some(q) => unit_axes(q.min_size, mosaic.size),
+            None => layout_axes(&pipeline::layout(mosaic.input_size, settings)),
+        };
+        Some(Rulers::new(axes, mosaic.size, inner, opts))
+    }
+
+    /// Where the art's top-left corner lands on the final canvas.
+    pub fn offset(&self) -> (u32, u32) {
+        (self.margin, self.margin)
+    }
+
+    pub fn canvas_size(&self) -> (u32, u32) {
+        (self.inner.0 + 2 * self.margin, self.inner.1 + 2 * self.margin)
+    }
+
+    /// Moves placements to where they go inside the margin.
+    pub fn shift(&self, placements: &[Placement]) -> Vec<Placement> {
+        placements
+            .iter()
+            .map(|p| {
+                let mut p = *p;
+                p.cell.x += self.margin;
+                p.cell.y += self.margin;
+                p
+            })
+            .collect()
+    }
+
+    /// Draws onto `image`, which holds canvas rows starting at `y0` (0 for a whole canvas).
+    pub fn draw(&self, image: &mut RgbaImage, y0: u32) {
+        let (cw, _) = self.canvas_size();
+        let m = self.margin;
+        let top = |y: u32| y as i32 - y0 as i32;
+
+        // Margin frame around the inner canvas
+        let (iw, ih) = self.inner;
+        for (x, y, w, h) in [(0, 0, cw, m), (0, m + ih, cw, m), (0, m, m, ih), (m + iw, m, m, ih)] {
+            if h > 0 && w > 0 {
+                draw_filled_rect_mut(image, Rect::at(x as i32, top(y)).of_size(w, h), MARGIN);
+            }
+        }
+
+        // Column numbers above and below the art, row numbers left and right of it
+        let (aw, ah) = self.art;
+        for span in self.labeled(&self.cols) {
+            let label = (span.index + 1).to_string();
+            let w = text_width(&self.font, self.scale, &label);
+            let x = (m + span.start) as f32 + (span.len as f32 - w) / 2.0;
+            let label_top = (m - self.line_height) / 2;
+            for y in [label_top, m + ih + label_top] {
+                draw_text_mut(image, LABEL, x.round() as i32, top(y), self.scale, &self.font, &label);
+            }
+        }
+        for span in self.labeled(&self.rows) {
+            let label = (span.index + 1).to_string();
+            let w = text_width(&self.font, self.scale, &label).ceil() as u32;
+            let y = (m + span.start + span.len / 2).saturating_sub(self.line_height / 2);
+            for x in [(m - w) / 2, m + iw + (m - w) / 2] {
+                draw_text_mut(image, LABEL, x as i32, top(y), self.scale, &self.font, &label);
+            }
+        }
+
+        // Section lines over the art: every N dice, plus the outer edges
+        if self.every == 0 {
+            return;
+        }
+        let lw = self.line_width;
+        for x in section_edges(&self.cols, self.every, aw) {
+            let x = (m + x).saturating_sub(lw / 2);
+            draw_filled_rect_mut(image, Rect::at(x as i32, top(m)).of_size(lw, ah), SECTION);
+        }
+        for y in section_edges(&self.rows, self.every, ah) {
+            let y = (m + y).saturating_sub(lw / 2);
+            draw_filled_rect_mut(image, Rect::at(m as i32, top(y)).of_size(aw, lw), SECTION);
+        }
+    }
+
+    /// Spans that get a number: the first one and every `label_step`-th after.
+    fn labeled<'a>(&self, spans: &'a [Span]) -> impl Iterator<Item = &'a Span> {
+        let step = self.label_step;
+        spans.iter().filter(move |s| s.index == 0 || (s.index + 1) % step == 0)
+    }
+}
+
+/// Pixel positions of the section lines along one axis.
+fn section_edges(spans: &[Span], every: u32, end: u32) -> Vec<u32> {
+    let mut edges: Vec<u32> = spans.iter().filter(|s| s.index % every == 0).map(|s| s.start).collect();
+    edges.push(end);
+    edges
+}
+
+/// Columns and rows of a regular layout, from its geometry rather than the dice that
+/// ended up placed, so masked-out cells still get their numbers. Columns follow the
+/// first row, since brick and hex shift every other row.
+fn layout_axes(layout: &Layout) -> (Vec<Span>, Vec<Span>) {
+    let cols = (0..layout.cols_in_row(0))
+        .map(|col| Span {
+            index: col,
+            start: layout.row_offset(0) + col * layout.cell_w,
+            len: layout.cell_w,
+        })
+        .collect();
+    let rows = (0..layout.rows)
+        .map(|row| Span {
+            index: row,
+            start: row * layout.row_pitch(),
+            len: layout.cell_h,
+        })
+        .collect();
+    (cols, rows)
+}
+
+/// Quadtree cells come in mixed sizes, so those are numbered on a grid of the smallest die.
+fn unit_axes(unit: u32, (width, height): (u32, u32)) -> (Vec<Span>, Vec<Span>) {
+    let unit = unit.max(1);
+    let grid = |extent: u32| -> Vec<Span> {
+        (0..extent / unit)
+            .map(|i| Span {
+                index: i,
+                start: i * unit,
+                len: unit,
+            })
+            .collect()
+    };
+    (grid(width), grid(height))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::LayoutKind;
+
+    #[test]
+    fn columns_come_from_the_layout_not_the_dice_left() {
+        let settings = Settings {
+            dice_size: 8,
+            layout: LayoutKind::Brick,
+            rulers: Some(RulerOptions::default()),
+            ..Settings::default()
+        };
+        let mut mosaic = pipeline::plan(&image::GrayImage::new(40, 24), &settings);
+        // A mask took the whole first row
+        mosaic.placements.retain(|p| p.cell.row > 0);
+        let rulers = Rulers::for_mosaic(&mosaic, &settings, mosaic.size).unwrap();
+        let starts = |spans: &[Span]| spans.iter().map(|s| (s.index, s.start)).collect::<Vec<_>>();
+        assert_eq!(starts(&rulers.cols), vec![(0, 0), (1, 8), (2, 16), (3, 24), (4, 32)]);
+        assert_eq!(starts(&rulers.rows), vec![(0, 0), (1, 8), (2, 16)]);
+    }
+
+    #[test]
+    fn quadtree_columns_are_in_smallest_dice() {
+        let (cols, rows) = unit_axes(8, (64, 32));
+        assert_eq!((cols.len(), rows.len()), (8, 4));
+        assert_eq!((cols[3].start, cols[3].len), (24, 8));
+    }
+}
@@ -0,0 +1,408 @@
+// `dice serve`: the renderer over plain HTTP, for tools that can't load the WASM build.
+//
+//     GET  /health                      {"status":"ok",...}
+//     POST /render?format=svg&dice_size=16&preset=dark
+//                                       body: the image bytes, any format image reads
+//
+// Options are query parameters named like the `[render]` fields of a job file, plus
+// `format` (png, tif, svg, pdf, json, bom, csv, txt, html) and `die_mm`. One thread
+// accepts requests and hands renders to a fixed pool of workers through a bounded queue;
+// when the queue is full the answer is 503 rather than an ever-growing backlog.
+
+use std::io::{Cursor, Read};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::mpsc::{sync_channel, Receiver, TrySendError};
+use std::sync::{Arc, Mutex};
+
+use image::ImageReader;
+use serde::Serialize;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::alpha::Alpha;
+use crate::anneal::Anneal;
+use crate::cache::TileCache;
+use crate::dicelib::{decode_input, split_input, IntensityPreset, Thresholds};
+use crate::dither::Dither;
+use crate::export::{self, Bom, Grid, OutputFormat, Physical};
+use crate::grayscale::Grayscale;
+use crate::inventory::{Inventory, Solver};
+use crate::layout::LayoutKind;
+use crate::mask::{Key, Subject};
+use crate::overlay::OverlayPosition;
+use crate::palette::Palette;
+use crate::pipeline::{self, Decorations, Fit, Settings};
+use crate::preprocess::Op;
+use crate::rulers::RulerOptions;
+use crate::text::{Ramp, TextArt};
+
+pub struct ServeOptions {
+    pub addr: String,
+    pub dice_dir: PathBuf,
+    /// Renders running at once.
+    pub workers: usize,
+    /// Renders waiting for a worker before new ones get a 503.
+    pub queue: usize,
+    /// Biggest request body, in bytes.
+    pub max_body: u64,
+    /// Biggest input image, in pixels.
+    pub max_input_pixels: u64,
+    /// Biggest raster output, in pixels.
+    pub max_output_pixels: u64,
+    /// Most dice in a mosaic, for every format. Text and vector outputs grow with it.
+    pub max_dice: u64,
+    pub cache: Option<TileCache>,
+}
+
+/// What a request turned into: a status, a content type and the body.
+struct Reply {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+    total_dice: Option<u32>,
+}
+
+impl Reply {
+    fn json(status: u16, value: &impl Serialize) -> Reply {
+        Reply {
+            status,
+            content_type: "application/json",
+            body: serde_json::to_vec_pretty(value).unwrap_or_default(),
+            total_dice: None,
+        }
+    }
+
+    fn error(status: u16, message: impl Into<String>) -> Reply {
+        #[derive(Serialize)]
+        struct Error {
+            error: String,
+        }
+        Reply::json(status, &Error { error: message.into() })
+    }
+
+    fn send(self, request: Request) {
+        let mut response = Response::from_data(self.body)
+            .with_status_code(self.status)
+            .with_header(header("Content-Type", self.content_type));
+        if let Some(total) = self.total_dice {
+            response.add_header(header("X-Dice-Total", &total.to_string()));
+        }
+        // Nothing to be done if the client has gone
+        let _ = request.respond(response);
+    }
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("header names and values are ASCII")
+}
+
+/// Binds `opts.addr` and serves until the process is stopped.
+pub fn serve(opts: ServeOptions) -> Result<(), String> {
+    let server = Server::http(&opts.addr).map_err(|e| format!("Couldn't listen on {}: {}", opts.addr, e))?;
+    let (queue, jobs) = sync_channel::<Request>(opts.queue);
+    let jobs = Arc::new(Mutex::new(jobs));
+    let opts = Arc::new(opts);
+
+    let workers: Vec<_> = (0..opts.workers.max(1))
+        .map(|_| {
+            let (jobs, opts) = (Arc::clone(&jobs), Arc::clone(&opts));
+            std::thread::spawn(move || work(&jobs, &opts))
+        })
+        .collect();
+
+    println!("Listening on http://{} with {} workers", opts.addr, workers.len());
+    for request in server.incoming_requests() {
+        let path = request.url().split('?').next().unwrap_or("");
+        match (request.method(), path) {
+            // Answered right here, so it stays quick while the workers are busy
+            (Method::Get, "/health") => Reply::json(200, &health(&opts)).send(request),
+            (Method::Post, "/render") => match queue.try_send(request) {
+                Ok(()) => {}
+                Err(TrySendError::Full(request)) => Reply::error(503, "Too many renders queued, try again shortly").send(request),
+                Err(TrySendError::Disconnected(request)) => Reply::error(500, "No workers left").send(request),
+            },
+            (_, "/render") | (_, "/health") => Reply::error(405, "Method not allowed").send(request),
+            _ => Reply::error(404, "Not found. Try POST /render or GET /health").send(request),
+        }
+    }
+    Ok(())
+}
+
+fn work(jobs: &Mutex<Receiver<Request>>, opts: &ServeOptions) {
+    loop {
+        // Hold the lock only while waiting, not while rendering
+        let request = match jobs.lock() {
+            Ok(jobs) => jobs.recv(),
+            Err(_) => return,
+        };
+        let Ok(mut request) = request else {
+            return;
+        };
+        // A panic in one render shouldn't take the worker down with it
+        let reply = match panic::catch_unwind(AssertUnwindSafe(|| render_request(&mut request, opts))) {
+            Ok(Ok(reply)) => reply,
+            Ok(Err((status, message))) => Reply::error(status, message),
+            Err(_) => Reply::error(500, "The render failed unexpectedly"),
+        };
+        reply.send(request);
+    }
+}
+
+#[derive(Serialize)]
+struct Health {
+    status: &'static str,
+    version: &'static str,
+    workers: usize,
+    max_body: u64,
+    max_input_pixels: u64,
+    max_output_pixels: u64,
+    max_dice: u64,
+}
+
+fn health(opts: &ServeOptions) -> Health {
+    Health {
+        status: "ok",
+        version: env!("CARGO_PKG_VERSION"),
+        workers: opts.workers,
+        max_body: opts.max_body,
+        max_input_pixels: opts.max_input_pixels,
+        max_output_pixels: opts.max_output_pixels,
+        max_dice: opts.max_dice,
+    }
+}
+
+type Failure = (u16, String);
+
+/// Longest a request may spend annealing.
+const MAX_ANNEAL_SECONDS: f64 = 5.0;
+
+fn render_request(request: &mut Request, opts: &ServeOptions) -> Result<Reply, Failure> {
+    let query = request.url().split_once('?').map(|(_, q)| q.to_string()).unwrap_or_default();
+    let (mut settings, mut format, mut physical) = (Settings::default(), OutputFormat::Png, None);
+    for (key, value) in parse_query(&query) {
+        apply_param(&mut settings, &mut format, &mut physical, &key, &value).map_err(|e| (400, e))?;
+    }
+    settings.check().map_err(|e| (400, e))?;
+    // Annealing runs as long as it's told to, so it gets a time budget here
+    if let Some(anneal) = &mut settings.anneal {
+        anneal.seconds = Some(anneal.seconds.unwrap_or(MAX_ANNEAL_SECONDS).min(MAX_ANNEAL_SECONDS));
+    }
+
+    // Size limits first: the declared length, then what actually arrives
+    if request.body_length().is_some_and(|len| len as u64 > opts.max_body) {
+        return Err((413, format!("Body is bigger than the {} byte limit", opts.max_body)));
+    }
+    let mut body = Vec::new();
+    request
+        .as_reader()
+        .take(opts.max_body + 1)
+        .read_to_end(&mut body)
+        .map_err(|e| (400, format!("Couldn't read the body: {}", e)))?;
+    if body.len() as u64 > opts.max_body {
+        return Err((413, format!("Body is bigger than the {} byte limit", opts.max_body)));
+    }
+    if body.is_empty() {
+        return Err((400, "Send the image as the request body".to_string()));
+    }
+
+    let reader = || ImageReader::new(Cursor::new(&body)).with_guessed_format();
+    let (w, h) = reader()
+        .and_then(|r| r.into_dimensions().map_err(std::io::Error::other))
+        .map_err(|e| (415, format!("Not an image we can read: {}", e)))?;
+    if w as u64 * h as u64 > opts.max_input_pixels {
+        return Err((413, format!("Image is {}x{}, over the {} pixel limit", w, h, opts.max_input_pixels)));
+    }
+    let image = reader()
+        .map_err(|e| e.to_string())
+        .and_then(|r| decode_input(r, settings.auto_orient))
+        .map_err(|e| (415, format!("Couldn't decode the image: {}", e)))?;
+
+    let (input, alpha) = split_input(&image, &settings).map_err(|e| (422, e))?;
+    let input = pipeline::prepare_input(input, &settings);
+    let alpha = alpha.map(|a| pipeline::prepare_alpha(a, &settings));
+    // Too many dice for sure, before the cells are even laid out
+    let (iw, ih) = input.dimensions();
+    let die = settings.tile_size().max(1);
+    let fewest = (iw / die).saturating_sub(1) as u64 * (ih / die) as u64;
+    if fewest > opts.max_dice {
+        return Err((413, too_many_dice(fewest, opts)));
+    }
+    let mosaic = pipeline::plan_masked(&input, alpha.as_ref(), &settings);
+    if mosaic.placements.is_empty() {
+        return Err((422, format!("Image ({}x{}) is smaller than a single die", w, h)));
+    }
+    if mosaic.total_dice() as u64 > opts.max_dice {
+        return Err((413, too_many_dice(mosaic.total_dice() as u64, opts)));
+    }
+    mosaic.check().map_err(|e| (422, e))?;
+
+    let (content_type, body) = match format {
+        OutputFormat::Png | OutputFormat::Tif => {
+            let (cw, ch) = Decorations::new(&mosaic, &settings).canvas_size();
+            if cw as u64 * ch as u64 > opts.max_output_pixels {
+                return Err((
+                    413,
+                    format!("Output would be {}x{}, over the {} pixel limit. Use smaller dice.", cw, ch, opts.max_output_pixels),
+                ));
+            }
+            let tiles = pipeline::load_tiles(&opts.dice_dir.to_string_lossy(), &settings, opts.cache.as_ref())
+                .map_err(|e| (500, e))?;
+            let image = pipeline::render(&mosaic, &tiles, &settings);
+            let (image_format, content_type) = match format {
+                OutputFormat::Png => (image::ImageFormat::Png, "image/png"),
+                _ => (image::ImageFormat::Tiff, "image/tiff"),
+            };
+            let mut bytes = Vec::new();
+            image
+                .write_to(&mut Cursor::new(&mut bytes), image_format)
+                .map_err(|e| (500, format!("Couldn't encode the image: {}", e)))?;
+            (content_type, bytes)
+        }
+        OutputFormat::Svg => ("image/svg+xml", export::svg(&mosaic, &settings, physical.as_ref()).into_bytes()),
+        OutputFormat::Pdf => ("application/pdf", export::pdf(&mosaic, &settings, physical.as_ref())),
+        OutputFormat::Csv => ("text/csv", export::csv(&mosaic.placements).into_bytes()),
+        OutputFormat::Json => return Ok(with_total(Reply::json(200, &Grid::new(&mosaic)), &mosaic)),
+        OutputFormat::Bom => {
+            let bom = Bom::new(&mosaic, &settings, physical.as_ref());
+            return Ok(with_total(Reply::json(200, &bom), &mosaic));
+        }
+        OutputFormat::Txt => ("text/plain; charset=utf-8", TextArt::new(&input, alpha.as_ref(), &settings).to_text().into_bytes()),
+        OutputFormat::Html => {
+            let art = TextArt::new(&input, alpha.as_ref(), &settings);
+            ("text/html; charset=utf-8", art.to_html(&settings, "dice").into_bytes())
+        }
+    };
+    Ok(with_total(
+        Reply {
+            status: 200,
+            content_type,
+            body,
+            total_dice: None,
+        },
+        &mosaic,
+    ))
+}
+
+fn too_many_dice(dice: u64, opts: &ServeOptions) -> String {
+    format!("The mosaic would have {} dice, over the {} dice limit. Use bigger dice.", dice, opts.max_dice)
+}
+
+fn with_total(reply: Reply, mosaic: &pipeline::Mosaic) -> Reply {
+    Reply {
+        total_dice: Some(mosaic.total_dice()),
+        ..reply
+    }
+}
+
+/// One query parameter onto the settings. Names follow the job file's `[render]` table.
+fn apply_param(
+    settings: &mut Settings,
+    format: &mut OutputFormat,
+    physical: &mut Option<Physical>,
+    key: &str,
+    value: &str,
+) -> Result<(), String> {
+    let bad = || format!("Bad value for {}: {:?}", key, value);
+    let flag = || match value.to_ascii_lowercase().as_str() {
+        "" | "1" | "true" | "yes" | "on" => Ok(true),
+        "0" | "false" | "no" | "off" => Ok(false),
+        _ => Err(bad()),
+    };
+    match key {
+        "format" => *format = OutputFormat::parse(value).ok_or_else(bad)?,
+        "dice_size" => settings.dice_size = value.parse().ok().filter(|&s| s > 0).ok_or_else(bad)?,
+        "layout" => settings.layout = LayoutKind::parse(value).ok_or_else(bad)?,
+        "preset" => settings.preset = IntensityPreset::parse(value).ok_or_else(bad)?,
+        "thresholds" => settings.thresholds = Some(Thresholds::parse(value)?),
+        "dither" => settings.dither = Dither::parse(value).ok_or_else(bad)?,
+        "fit" => settings.fit = Fit::parse(value).ok_or_else(bad)?,
+        "palette" => settings.palette = Some(Palette::parse(value)?),
+        "grayscale" => settings.grayscale = Grayscale::parse(value)?,
+        "alpha" => settings.alpha = Alpha::parse(value)?,
+        "inventory" => {
+            let solver = settings.inventory.as_ref().map(|i| i.solver).unwrap_or_default();
+            settings.inventory = Some(Inventory { solver, ..Inventory::parse(value)? });
+        }
+        "solver" => {
+            let solver = Solver::parse(value).ok_or_else(bad)?;
+            settings.inventory.get_or_insert_with(Inventory::default).solver = solver;
+        }
+        "mask" => match Key::parse(value)? {
+            // Paths would be on the server's disk
+            Key::Image { .. } => return Err("Mask images can't be used here, key the backdrop out instead".to_string()),
+            key => settings.mask = Some(Subject::new(key)),
+        },
+        "mask_fill" | "mask_coverage" => {
+            let mask = settings.mask.as_mut().ok_or_else(|| format!("{} goes after mask", key))?;
+            match key {
+                "mask_fill" => mask.fill = Some(value.parse().ok().filter(|f| (1..=6).contains(f)).ok_or_else(bad)?),
+                _ => mask.coverage = value.parse().ok().filter(|c| (0.0..=1.0).contains(c)).ok_or_else(bad)?,
+            }
+        }
+        "auto_orient" => settings.auto_orient = flag()?,
+        "linear_light" => settings.linear_light = flag()?,
+        "invert_input" => settings.invert_input = flag()?,
+        "invert_dice" => settings.invert_dice = flag()?,
+        "preprocess" => settings.preprocess.extend(Op::parse_list(value)?),
+        "add_debug" | "debug" => settings.add_debug = flag()?,
+        "anneal" => settings.anneal = flag()?.then(|| settings.anneal.clone().unwrap_or_default()),
+        "anneal_iterations" | "anneal_seed" | "anneal_seconds" | "anneal_blur" => {
+            let anneal = settings.anneal.get_or_insert_with(Anneal::default);
+            match key {
+                "anneal_iterations" => anneal.iterations = Some(value.parse().map_err(|_| bad())?),
+                "anneal_seed" => anneal.seed = value.parse().map_err(|_| bad())?,
+                "anneal_seconds" => anneal.seconds = Some(value.parse().ok().filter(|s: &f64| *s >= 0.0).ok_or_else(bad)?),
+                _ => anneal.blur = value.parse().ok().filter(|b: &f32| *b > 0.0).ok_or_else(bad)?,
+            }
+        }
+        "legend" => settings.overlay.legend = flag()?,
+        "overlay" => settings.overlay.position = OverlayPosition::parse(value).ok_or_else(bad)?,
+        "rulers" => {
+            settings.rulers = Some(RulerOptions {
+                every: value.parse().map_err(|_| bad())?,
+                ..RulerOptions::default()
+            })
+        }
+        "ramp" => settings.ramp = Some(Ramp::parse(value)?),
+        "die_mm" => {
+            let die_mm = value.parse().ok().filter(|&mm: &f64| mm > 0.0).ok_or_else(bad)?;
+            *physical = Some(Physical { die_mm });
+        }
+        _ => return Err(format!("Unknown option {:?}", key)),
+    }
+    Ok(())
+}
+
+/// `a=1&b=two%20words` into pairs, percent-decoded, `+` as space.
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode(key), decode(value))
+        })
+        .collect()
+}
+
+fn decode(s: &str) -> String {
+    let hex = |b: u8| (b as char).to_digit(16).map(|d| d as u8);
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => match (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                (Some(hi), Some(lo)) => {
+                    out.push(hi << 4 | lo);
+                    i += 2;
+                }
+                _ => out.push(b'%'),
+            },
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
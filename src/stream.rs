@@ -0,0 +1,200 @@
+// Streaming output for mosaics too big to hold in memory. 200x200 dice at 64px is a
+// 12800x12800 RGBA canvas (650MB), so instead we render a strip of rows at a time
+// and push it straight into a PNG or TIFF encoder. Only one strip is ever alive.
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use image::RgbaImage;
+
+use crate::render::{render_region, Placement, TileSet};
+
+/// Default budget for the strip buffer when none is given.
+pub const DEFAULT_STRIP_BYTES: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamFormat {
+    Png,
+    Tiff,
+}
+
+impl StreamFormat {
+    /// Picks the encoder from the file extension.
+    pub fn from_path(path: &Path) -> Option<StreamFormat> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "png" => Some(StreamFormat::Png),
+            "tif" | "tiff" => Some(StreamFormat::Tiff),
+            _ => None,
+        }
+    }
+}
+
+/// Bytes needed to hold the whole canvas at once.
+pub fn canvas_bytes((width, height): (u32, u32)) -> u64 {
+    width as u64 * height as u64 * 4
+}
+
+/// How many rows fit in `max_bytes`. Always at least one row.
+pub fn strip_rows_for_budget(width: u32, height: u32, max_bytes: u64) -> u32 {
+    let row_bytes = (width as u64 * 4).max(1);
+    (max_bytes / row_bytes).clamp(1, height.max(1) as u64) as u32
+}
+
+/// Renders the mosaic strip by strip into `path`, never holding more than
+/// `strip_rows` rows of canvas. `decorate` gets every strip (with its top row)
+/// before it's written, for drawing things like the debug text.
+pub fn write_streaming(
+    path: &Path,
+    placements: &[Placement],
+    tiles: &TileSet,
+    (width, height): (u32, u32),
+    strip_rows: u32,
+    mut decorate: impl FnMut(u32, &mut RgbaImage),
+) -> Result<(), String> {
+    let format = StreamFormat::from_path(path)
+        .ok_or_else(|| format!("Can't stream to {:?}, use a .png or .tif output", path))?;
+    if width == 0 || height == 0 {
+        return Err("Nothing to render.".to_string());
+    }
+    let strip_rows = strip_rows.clamp(1, height);
+
+    if let Some(parent_dir) = path.parent() {
+        std::fs::create_dir_all(parent_dir).map_err(|e| e.to_string())?;
+    }
+    let file = BufWriter::new(File::create(path).map_err(|e| e.to_string())?);
+
+    // Dice sorted by top edge, so each strip can find its dice with a binary search
+    let mut by_y: Vec<Placement> = placements.to_vec();
+    by_y.sort_by_key(|p| p.cell.y);
+    let tallest = by_y.iter().map(|p| p.cell.h).max().unwrap_or(0);
+
+    let mut next_strip = |y0: u32| -> RgbaImage {
+        let rows = strip_rows.min(height - y0);
+        let from = by_y.partition_point(|p| p.cell.y + tallest <= y0);
+        let to = by_y.partition_point(|p| p.cell.y < y0 + rows);
+        let mut strip = render_region(&by_y[from..to], tiles, width, y0, rows);
+        decorate(y0, &mut strip);
+        strip
+    };
+
+    match format {
+        StreamFormat::Png => {
+            let mut encoder = png::Encoder::new(file, width, height);
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.set_depth(png::BitDepth::Eight);
+            let mut writer = encoder
+                .write_header()
+                .and_then(|w| w.into_stream_writer())
+                .map_err(|e| e.to_string())?;
+            for y0 in (0..height).step_by(strip_rows as usize) {
+                let strip = next_strip(y0);
+                std::io::Write::write_all(&mut writer, strip.as_raw()).map_err(|e| e.to_string())?;
+            }
+            writer.finish().map_err(|e| e.to_string())
+        }
+        StreamFormat::Tiff => {
+            use tiff::encoder::TiffEncoder;
+
+            // Classic TIFF tops out at 4GB of offsets, go BigTIFF past that
+            let mut file = file;
+            if canvas_bytes((width, height)) > u32::MAX as u64 / 2 {
+                let encoder = TiffEncoder::new_big(&mut file).map_err(|e| e.to_string())?;
+                write_tiff(encoder, (width, height), strip_rows, &mut next_strip)
+            } else {
+                let encoder = TiffEncoder::new(&mut file).map_err(|e| e.to_string())?;
+                write_tiff(encoder, (width, height), strip_rows, &mut next_strip)
+            }
+        }
+    }
+}
+
+fn write_tiff<W: std::io::Write + std::io::Seek, K: tiff::encoder::TiffKind>(
+    mut encoder: tiff::encoder::TiffEncoder<W, K>,
+    (width, height): (u32, u32),
+    strip_rows: u32,
+    next_strip: &mut dyn FnMut(u32) -> RgbaImage,
+) -> Result<(), String> {
+    let mut image = encoder
+        .new_image::<tiff::encoder::colortype::RGBA8>(width, height)
+        .map_err(|e| e.to_string())?;
+    image.rows_per_strip(strip_rows).map_err(|e| e.to_string())?;
+    for y0 in (0..height).step_by(strip_rows as usize) {
+        let strip = next_strip(y0);
+        image.write_strip(strip.as_raw()).map_err(|e| e.to_string())?;
+    }
+    image.finish().map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dicelib::{Dice, DiceSides};
+    use crate::layout::LayoutKind;
+    use crate::pipeline::{plan, Settings};
+    use image::{DynamicImage, GrayImage, Rgba};
+
+    /// Six 8x8 faces, each its own color with see-through corners so blending shows.
+    fn tiles() -> TileSet {
+        let dice = DiceSides::ALL.map(|side| {
+            let i = side.index() as u8;
+            let face = RgbaImage::from_fn(8, 8, |x, y| {
+                let corner = (x == 0 || x == 7) && (y == 0 || y == 7);
+                Rgba([40 * i, 255 - 40 * i, (x * 30) as u8, if corner { 100 } else { 255 }])
+            });
+            Dice {
+                side,
+                image: DynamicImage::ImageRgba8(face),
+            }
+        });
+        TileSet::new(&dice)
+    }
+
+    #[test]
+    fn strips_match_the_whole_canvas() {
+        // Hex dice overlap, and 5-row strips cut through every one of them
+        let input = GrayImage::from_fn(61, 45, |x, y| image::Luma([((x * 37 + y * 91) % 256) as u8]));
+        let settings = Settings {
+            dice_size: 8,
+            layout: LayoutKind::Hex,
+            ..Settings::default()
+        };
+        let mosaic = plan(&input, &settings);
+        assert_eq!(mosaic.placements.len(), 6 * 7);
+        let tiles = tiles();
+        let whole = render_region(&mosaic.placements, &tiles, mosaic.size.0, 0, mosaic.size.1);
+
+        let dir = std::env::temp_dir().join(format!("dice-stream-test-{}", std::process::id()));
+        for name in ["strips.png", "strips.tif"] {
+            let path = dir.join(name);
+            write_streaming(&path, &mosaic.placements, &tiles, mosaic.size, 5, |_, _| {}).unwrap();
+            let streamed = image::open(&path).unwrap().to_rgba8();
+            let _ = std::fs::remove_file(&path);
+            assert_eq!(streamed.dimensions(), whole.dimensions(), "{}", name);
+            assert!(streamed.pixels().eq(whole.pixels()), "{}", name);
+        }
+    }
+
+    #[test]
+    fn decorate_sees_every_strip_once() {
+        let settings = Settings {
+            dice_size: 8,
+            ..Settings::default()
+        };
+        let mosaic = plan(&GrayImage::new(32, 24), &settings);
+        let path = std::env::temp_dir().join(format!("dice-stream-test-{}-tops.png", std::process::id()));
+        let mut tops = Vec::new();
+        write_streaming(&path, &mosaic.placements, &tiles(), mosaic.size, 10, |y0, strip| tops.push((y0, strip.height())))
+            .unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(tops, vec![(0, 10), (10, 10), (20, 4)]);
+    }
+
+    #[test]
+    fn strip_rows_stay_within_budget() {
+        assert_eq!(strip_rows_for_budget(100, 1000, 400 * 10), 10);
+        assert_eq!(strip_rows_for_budget(100, 1000, 1), 1);
+        assert_eq!(strip_rows_for_budget(100, 5, u64::MAX), 5);
+    }
+}
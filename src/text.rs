@@ -0,0 +1,304 @@
+// Dice art without the dice: the same cells, thresholds and dithering, but every cell
+// becomes a character. Die faces (⚀-⚅) by default, or any ramp of characters from
+// darkest to brightest. Written as plain text, or as an HTML page in a <pre>.
+
+use std::path::Path;
+
+use image::GrayImage;
+use serde::{Deserialize, Serialize};
+
+use crate::dicelib::DiceSides;
+use crate::dither::{cell_levels, diffuse_by, Dither};
+use crate::export::{create_parent, vector_colors};
+use crate::layout::Cell;
+use crate::mask::Subject;
+use crate::palette::Color;
+use crate::pipeline::{self, Settings};
+use crate::preprocess;
+
+/// Die faces, one to six pips. Six characters, so they use the face thresholds as is.
+pub const FACES: &str = "⚀⚁⚂⚃⚄⚅";
+
+/// Characters from darkest to brightest. A six-character ramp uses the face thresholds,
+/// so it lines up one-for-one with the dice; any other length splits 0-255 evenly.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Ramp(Vec<char>);
+
+impl Default for Ramp {
+    fn default() -> Ramp {
+        Ramp(FACES.chars().collect())
+    }
+}
+
+impl Ramp {
+    pub fn parse(s: &str) -> Result<Ramp, String> {
+        let chars: Vec<char> = s.chars().collect();
+        if chars.len() < 2 {
+            return Err(format!("A ramp needs at least 2 characters, got {:?}", s));
+        }
+        // One band per gray level at most
+        if chars.len() > 256 {
+            return Err(format!("A ramp can have at most 256 characters, got {}", chars.len()));
+        }
+        if chars.iter().any(|c| c.is_control()) {
+            return Err(format!("Ramp {:?} has control characters in it", s));
+        }
+        Ok(Ramp(chars))
+    }
+
+    pub fn is_faces(&self) -> bool {
+        self.0.iter().copied().eq(FACES.chars())
+    }
+
+    /// Upper bound of every band but the last, like `Thresholds` for the faces.
+    fn bounds(&self, settings: &Settings) -> Vec<u8> {
+        let n = self.0.len() as u32;
+        if n == 6 {
+            return settings.thresholds().0.to_vec();
+        }
+        (1..n).map(|i| (i * 256 / n - 1) as u8).collect()
+    }
+
+    /// The character standing in for a die face: the face itself for six characters,
+    /// otherwise the one as far along the ramp.
+    fn face(&self, side: DiceSides) -> char {
+        let last = self.0.len() - 1;
+        self.0[(side.index() * last + 2) / 5]
+    }
+
+    /// The character for each cell, dithered if the settings say so.
+    fn pick(&self, levels: Vec<f32>, cells: &[Cell], settings: &Settings) -> Vec<char> {
+        let bounds = self.bounds(settings);
+        let band = |level: f32| {
+            let level = level.round() as u8;
+            bounds.iter().position(|&b| level <= b).unwrap_or(bounds.len())
+        };
+        // Same convention as the faces: the ends stand for black and white
+        let stands_for = |i: usize| -> f32 {
+            match i {
+                0 => 0.0,
+                i if i == bounds.len() => 255.0,
+                i => ((bounds[i - 1] as u16 + 1 + bounds[i] as u16) / 2) as f32,
+            }
+        };
+
+        match settings.dither {
+            Dither::None => levels.into_iter().map(|l| self.0[band(l)]).collect(),
+            Dither::FloydSteinberg => diffuse_by(levels, cells, |l| {
+                let i = band(l);
+                (self.0[i], stands_for(i))
+            }),
+        }
+    }
+}
+
+impl TryFrom<String> for Ramp {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Ramp, String> {
+        Ramp::parse(&s)
+    }
+}
+
+impl From<Ramp> for String {
+    fn from(ramp: Ramp) -> String {
+        ramp.0.into_iter().collect()
+    }
+}
+
+/// A mosaic as lines of text. Every cell is two columns wide so it comes out roughly
+/// square: a face and a space, or a ramp character doubled.
+pub struct TextArt {
+    pub lines: Vec<String>,
+    /// Characters placed, one per die the mosaic would have.
+    pub cells: u32,
+}
+
+impl TextArt {
+    /// Lays out the cells over a prepared input (see `pipeline::prepare_input`) and
+    /// picks a character for each. Cells a prepared mask cuts out are blank, or the
+    /// subject mask's fill face.
+    pub fn new(input: &GrayImage, alpha: Option<&GrayImage>, settings: &Settings) -> TextArt {
+        let ramp = settings.ramp.clone().unwrap_or_default();
+        let (mut cells, cut) = pipeline::masked_cells(input, alpha, settings);
+        let integral = pipeline::integral(input, settings);
+        let levels = preprocess::edge_levels(&settings.preprocess, input, &integral, &cells)
+            .unwrap_or_else(|| cell_levels(&integral, &cells));
+        let mut chars = ramp.pick(levels, &cells, settings);
+        if let Some(side) = settings.mask.as_ref().and_then(Subject::fill_face) {
+            // Merged back in reading order, like the mosaic's placements
+            let mut all: Vec<(Cell, char)> = cells.into_iter().zip(chars).collect();
+            all.extend(cut.into_iter().map(|cell| (cell, ramp.face(side))));
+            all.sort_by_key(|(c, _)| (c.y, c.x));
+            (cells, chars) = all.into_iter().unzip();
+        }
+
+        let faces = ramp.is_faces();
+        let grid = cell_grid(&cells, &chars);
+        let indent = offset_rows(input, settings, grid.len());
+        let lines = grid
+            .iter()
+            .zip(indent)
+            .map(|(row, indent)| {
+                let mut line = String::from(if indent { " " } else { "" });
+                for c in row {
+                    match c {
+                        Some(c) => {
+                            line.push(*c);
+                            line.push(if faces { ' ' } else { *c });
+                        }
+                        None => line.push_str("  "),
+                    }
+                }
+                line.trim_end().to_string()
+            })
+            .collect();
+        TextArt {
+            lines,
+            cells: cells.len() as u32,
+        }
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut out = self.lines.join("\n");
+        out.push('\n');
+        out
+    }
+
+    /// A standalone page: the art in a <pre>, dice body color behind pip color text.
+    pub fn to_html(&self, settings: &Settings, title: &str) -> String {
+        let (body, pip) = vector_colors(settings);
+        format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>\n\
+             body {{ margin: 0; background: {}; color: {}; }}\n\
+             pre {{ margin: 1em; font-family: \"DejaVu Sans Mono\", Menlo, Consolas, monospace; \
+             font-size: 8px; line-height: 1; letter-spacing: 0; }}\n\
+             </style>\n</head>\n<body>\n<pre>\n{}</pre>\n</body>\n</html>\n",
+            escape(title),
+            Color(body).hex(),
+            Color(pip).hex(),
+            escape(&self.to_text())
+        )
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+pub fn write_text(path: &Path, art: &TextArt) -> Result<(), String> {
+    create_parent(path)?;
+    std::fs::write(path, art.to_text()).map_err(|e| format!("Error saving {:?}: {}", path, e))
+}
+
+pub fn write_html(path: &Path, art: &TextArt, settings: &Settings) -> Result<(), String> {
+    create_parent(path)?;
+    let title = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    std::fs::write(path, art.to_html(settings, &title)).map_err(|e| format!("Error saving {:?}: {}", path, e))
+}
+
+/// Values laid out by grid position. Regular layouts use their own rows and columns;
+/// quadtree cells are spread over a grid of the smallest die.
+pub fn cell_grid<T: Copy>(cells: &[Cell], values: &[T]) -> Vec<Vec<Option<T>>> {
+    let unit = cells.iter().map(|c| c.w).min().unwrap_or(1).max(1);
+    let uniform = cells.iter().all(|c| c.w == unit);
+
+    let mut grid: Vec<Vec<Option<T>>> = Vec::new();
+    let mut put = |col: usize, row: usize, value: T| {
+        if grid.len() <= row {
+            grid.resize(row + 1, Vec::new());
+        }
+        if grid[row].len() <= col {
+            grid[row].resize(col + 1, None);
+        }
+        grid[row][col] = Some(value);
+    };
+    for (c, &value) in cells.iter().zip(values) {
+        if uniform {
+            put(c.col as usize, c.row as usize, value);
+        } else {
+            for row in c.y / unit..(c.y + c.h) / unit {
+                for col in c.x / unit..(c.x + c.w) / unit {
+                    put(col as usize, row as usize, value);
+                }
+            }
+        }
+    }
+    grid
+}
+
+/// Rows that start half a die in (brick and hex), so the text can shift them too.
+/// Taken from the layout, not the cells: a mask can cut a row's first dice away.
+fn offset_rows(input: &GrayImage, settings: &Settings, rows: usize) -> Vec<bool> {
+    if settings.quadtree.is_some() {
+        return vec![false; rows];
+    }
+    let layout = pipeline::layout(input.dimensions(), settings);
+    (0..rows as u32).map(|row| layout.row_offset(row) > 0).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dicelib::Thresholds;
+    use crate::layout::LayoutKind;
+    use crate::mask::Key;
+
+    #[test]
+    fn bounds_split_the_range_evenly() {
+        let settings = Settings::default();
+        assert_eq!(Ramp::parse("ab").unwrap().bounds(&settings), vec![127]);
+        assert_eq!(Ramp::parse("abcd").unwrap().bounds(&settings), vec![63, 127, 191]);
+    }
+
+    #[test]
+    fn six_characters_use_the_face_thresholds() {
+        let settings = Settings {
+            thresholds: Some(Thresholds([10, 20, 30, 40, 50])),
+            ..Settings::default()
+        };
+        assert_eq!(Ramp::parse("abcdef").unwrap().bounds(&settings), vec![10, 20, 30, 40, 50]);
+    }
+
+    #[test]
+    fn the_longest_ramp_gets_a_band_per_level() {
+        let chars: String = (0..256).map(|i| char::from_u32(0x4e00 + i).unwrap()).collect();
+        let bounds = Ramp::parse(&chars).unwrap().bounds(&Settings::default());
+        assert_eq!(bounds, (0..=254).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn parse_rejects_unusable_ramps() {
+        assert!(Ramp::parse("a").is_err());
+        assert!(Ramp::parse("a\tb").is_err());
+        assert!(Ramp::parse(&"a".repeat(257)).is_err());
+    }
+
+    /// Leading spaces of each line of a two-row mosaic with the second row masked out
+    /// up to `cut` pixels in.
+    fn indents(layout: LayoutKind, cut: u32) -> Vec<usize> {
+        let input = GrayImage::from_pixel(32, 16, image::Luma([128]));
+        let alpha = GrayImage::from_fn(32, 16, |x, y| image::Luma([if y >= 8 && x < cut { 0 } else { 255 }]));
+        let settings = Settings {
+            dice_size: 8,
+            layout,
+            mask: Some(Subject::new(Key::Flood { tolerance: 24 })),
+            ..Settings::default()
+        };
+        let art = TextArt::new(&input, Some(&alpha), &settings);
+        art.lines.iter().map(|l| l.len() - l.trim_start().len()).collect()
+    }
+
+    #[test]
+    fn masked_grid_rows_stay_in_line() {
+        assert_eq!(indents(LayoutKind::Grid, 8), vec![0, 2]);
+    }
+
+    #[test]
+    fn shifted_rows_are_indented_by_half_a_cell() {
+        assert_eq!(indents(LayoutKind::Brick, 0), vec![0, 1]);
+        // Half a cell, then a blank one where the mask cut the first die
+        assert_eq!(indents(LayoutKind::Brick, 12), vec![0, 3]);
+    }
+}
@@ -0,0 +1,323 @@
+// `dice tui`: the settings on one screen with a live preview, instead of a row of y/n
+// prompts you can't go back on. Pick a setting with the arrow keys, change it with
+// left/right, and the preview redraws from a fresh plan. `s` writes the job file and
+// renders its outputs, so what you tuned here can be rerun with `dice run`.
+
+use std::io;
+use std::path::PathBuf;
+
+use image::{GrayImage, RgbaImage};
+use ratatui::buffer::Buffer;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph, Widget};
+use ratatui::{DefaultTerminal, Frame};
+
+use crate::cache::TileCache;
+use crate::dicelib::{load_dice_dir, try_load_input, Dice, IntensityPreset, Thresholds};
+use crate::dither::Dither;
+use crate::job::{Job, OutputSpec};
+use crate::pipeline::{self, Fit, Mosaic, Settings};
+use crate::preprocess::Op;
+use crate::preview::thumbnail;
+use crate::render::TileSet;
+
+/// Size the dice are loaded at for the preview. Thumbnails only ever shrink them.
+const PREVIEW_TILE: u32 = 64;
+
+/// What `dice tui` starts from: a job (loaded or put together from flags) and where
+/// `s` saves it. Only `job.render` is tuned, the rest is written back as it came.
+pub struct TuiOptions {
+    pub job: Job,
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    DiceSize,
+    Preset,
+    Threshold(usize),
+    InvertInput,
+    InvertDice,
+    Gamma,
+    Crop,
+    Dither,
+}
+
+const FIELDS: [Field; 12] = [
+    Field::DiceSize,
+    Field::Preset,
+    Field::Threshold(0),
+    Field::Threshold(1),
+    Field::Threshold(2),
+    Field::Threshold(3),
+    Field::Threshold(4),
+    Field::InvertInput,
+    Field::InvertDice,
+    Field::Gamma,
+    Field::Crop,
+    Field::Dither,
+];
+
+struct App {
+    opts: TuiOptions,
+    source: GrayImage,
+    /// The input's alpha, when transparent cells are left empty.
+    alpha: Option<GrayImage>,
+    dice: [Dice; 6],
+    tiles: TileSet,
+    cache: Option<TileCache>,
+    mosaic: Mosaic,
+    /// Preview and the area it was drawn for; `None` after any change.
+    thumb: Option<(RgbaImage, Rect)>,
+    selected: usize,
+    status: String,
+}
+
+/// Loads everything, takes over the terminal and runs until `q`.
+pub fn run(opts: TuiOptions, cache: Option<TileCache>) -> Result<(), String> {
+    let job = &opts.job;
+    let (source, alpha) = try_load_input(&job.input.to_string_lossy(), &job.render)?;
+    let dice = load_dice_dir(&job.dice_dir.to_string_lossy(), PREVIEW_TILE, cache.as_ref())?;
+    let tiles = pipeline::tile_set(dice.clone(), &job.render);
+    let mosaic = plan(&source, alpha.as_ref(), &job.render);
+    let mut app = App {
+        opts,
+        source,
+        alpha,
+        dice,
+        tiles,
+        cache,
+        mosaic,
+        thumb: None,
+        selected: 0,
+        status: "Ready".to_string(),
+    };
+
+    let mut terminal = ratatui::init();
+    let result = app.event_loop(&mut terminal);
+    ratatui::restore();
+    result.map_err(|e| format!("Terminal error: {}", e))
+}
+
+fn plan(source: &GrayImage, alpha: Option<&GrayImage>, settings: &Settings) -> Mosaic {
+    let alpha = alpha.map(|a| pipeline::prepare_alpha(a.clone(), settings));
+    pipeline::plan_masked(&pipeline::prepare_input(source.clone(), settings), alpha.as_ref(), settings)
+}
+
+impl App {
+    fn event_loop(&mut self, terminal: &mut DefaultTerminal) -> io::Result<()> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            let big = key.modifiers.contains(KeyModifiers::SHIFT);
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                KeyCode::Up | KeyCode::Char('k') => self.selected = self.selected.saturating_sub(1),
+                KeyCode::Down | KeyCode::Char('j') => self.selected = (self.selected + 1).min(FIELDS.len() - 1),
+                KeyCode::Left | KeyCode::Char('h') => self.adjust(-1, big),
+                KeyCode::Right | KeyCode::Char('l') => self.adjust(1, big),
+                KeyCode::Char('H') => self.adjust(-1, true),
+                KeyCode::Char('L') => self.adjust(1, true),
+                KeyCode::Char('s') => {
+                    self.status = "Saving...".to_string();
+                    terminal.draw(|frame| self.draw(frame))?;
+                    self.status = match self.save() {
+                        Ok(status) => status,
+                        Err(err) => format!("Error: {}", err),
+                    };
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Steps the selected setting; `big` takes bigger steps where that means anything.
+    fn adjust(&mut self, dir: i32, big: bool) {
+        let settings = &mut self.opts.job.render;
+        match FIELDS[self.selected] {
+            Field::DiceSize => {
+                let step = if big { 8 } else { 1 };
+                settings.dice_size = (settings.dice_size as i32 + dir * step).clamp(2, 512) as u32;
+            }
+            Field::Preset => {
+                settings.preset = cycle(&IntensityPreset::ALL, settings.preset, dir);
+                settings.thresholds = None;
+            }
+            Field::Threshold(i) => {
+                // Stays between its neighbours, so the bounds keep going up
+                let mut bounds = settings.thresholds().0;
+                let step = if big { 10 } else { 1 };
+                let low = if i > 0 { bounds[i - 1] as i32 } else { 0 };
+                let high = if i < 4 { bounds[i + 1] as i32 } else { 255 };
+                bounds[i] = (bounds[i] as i32 + dir * step).clamp(low, high) as u8;
+                settings.thresholds = Some(Thresholds(bounds));
+            }
+            Field::InvertInput => settings.invert_input = !settings.invert_input,
+            Field::InvertDice => {
+                settings.invert_dice = !settings.invert_dice;
+                self.tiles = pipeline::tile_set(self.dice.clone(), settings);
+            }
+            Field::Gamma => {
+                let step = if big { 0.25 } else { 0.05 };
+                let gamma = (gamma(settings) + dir as f64 * step).clamp(0.1, 5.0);
+                set_gamma(settings, (gamma * 100.0).round() / 100.0);
+            }
+            Field::Crop => settings.fit = cycle(&Fit::ALL, settings.fit, dir),
+            Field::Dither => settings.dither = cycle(&Dither::ALL, settings.dither, dir),
+        }
+        self.mosaic = plan(&self.source, self.alpha.as_ref(), &self.opts.job.render);
+        self.thumb = None;
+    }
+
+    /// Writes the job file, then renders every output it lists. Paths are written
+    /// absolute, since a job's paths are read relative to wherever the file is.
+    fn save(&self) -> Result<String, String> {
+        let absolute = |p: &PathBuf| std::path::absolute(p).unwrap_or_else(|_| p.clone());
+        let mut job = self.opts.job.clone();
+        job.input = absolute(&job.input);
+        job.dice_dir = absolute(&job.dice_dir);
+        job.outputs = job
+            .outputs()
+            .into_iter()
+            .map(|o| OutputSpec {
+                path: absolute(&o.path),
+                ..o
+            })
+            .collect();
+
+        let path = &self.opts.path;
+        std::fs::write(path, job.to_toml()?).map_err(|e| format!("Couldn't write {:?}: {}", path, e))?;
+        job.run(self.cache.as_ref())?;
+        let outputs: Vec<String> = job.outputs.iter().map(|o| o.path.display().to_string()).collect();
+        Ok(format!("Saved {} and {}", path.display(), outputs.join(", ")))
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, help] = Layout::vertical([Constraint::Min(3), Constraint::Length(1)]).areas(frame.area());
+        let [left, right] = Layout::horizontal([Constraint::Min(10), Constraint::Length(34)]).areas(main);
+
+        let block = Block::bordered().title(" Preview ");
+        let area = block.inner(left);
+        frame.render_widget(block, left);
+        if self.thumb.as_ref().is_none_or(|(_, drawn_for)| *drawn_for != area) {
+            self.thumb = Some((self.thumbnail(area), area));
+        }
+        if let Some((thumb, _)) = &self.thumb {
+            frame.render_widget(HalfBlocks(thumb), area);
+        }
+
+        let mut lines: Vec<Line> = FIELDS
+            .iter()
+            .enumerate()
+            .map(|(i, field)| {
+                let (name, value) = self.label(*field);
+                let line = Line::from(format!(" {:<14}{}", name, value));
+                if i == self.selected { line.reversed() } else { line }
+            })
+            .collect();
+        let (w, h) = self.mosaic.size;
+        lines.push(Line::default());
+        lines.push(match self.mosaic.short {
+            0 => Line::from(format!(" Dice: {}", self.mosaic.total_dice())),
+            short => Line::from(format!(" Dice: {} ({} short)", self.mosaic.total_dice(), short)).fg(Color::Red),
+        });
+        lines.push(Line::from(format!(" Output: {}x{}", w, h)));
+        lines.push(Line::from(format!(" Job: {}", self.opts.path.display())));
+        lines.push(Line::default());
+        lines.push(Line::from(format!(" {}", self.status)).fg(Color::Yellow));
+        frame.render_widget(Paragraph::new(lines).block(Block::bordered().title(" Settings ")), right);
+
+        let keys = " ↑↓ select  ←→ change (shift: faster)  s save job and outputs  q quit";
+        frame.render_widget(Paragraph::new(keys).dim(), help);
+    }
+
+    fn label(&self, field: Field) -> (&'static str, String) {
+        let settings = &self.opts.job.render;
+        let yes_no = |b: bool| if b { "yes" } else { "no" }.to_string();
+        match field {
+            Field::DiceSize => ("Dice size", format!("{} px", settings.dice_size)),
+            Field::Preset => match settings.thresholds {
+                Some(_) => ("Preset", format!("custom ({})", settings.preset.name())),
+                None => ("Preset", settings.preset.name().to_string()),
+            },
+            Field::Threshold(i) => {
+                const NAMES: [&str; 5] = ["  face 1 to", "  face 2 to", "  face 3 to", "  face 4 to", "  face 5 to"];
+                (NAMES[i], settings.thresholds().0[i].to_string())
+            }
+            Field::InvertInput => ("Invert input", yes_no(settings.invert_input)),
+            Field::InvertDice => ("Invert dice", yes_no(settings.invert_dice)),
+            Field::Gamma => ("Gamma", format!("{:.2}", gamma(settings))),
+            Field::Crop => ("Crop", settings.fit.name().to_string()),
+            Field::Dither => ("Dither", settings.dither.name().to_string()),
+        }
+    }
+
+    /// The mosaic shrunk to fit `area`, two pixels per character cell.
+    fn thumbnail(&mut self, area: Rect) -> RgbaImage {
+        let (w, h) = self.mosaic.size;
+        let by_height = (area.height as u64 * 2 * w as u64 / h.max(1) as u64) as u32;
+        thumbnail(&self.mosaic, &mut self.tiles, (area.width as u32).min(by_height).max(1))
+    }
+}
+
+/// The gamma the TUI edits: the first gamma step in the preprocessing list, 1 if none.
+fn gamma(settings: &Settings) -> f64 {
+    settings
+        .preprocess
+        .iter()
+        .find_map(|op| match op {
+            Op::Gamma { gamma } => Some(*gamma),
+            _ => None,
+        })
+        .unwrap_or(1.0)
+}
+
+/// Changes that step, adding it up front if there isn't one and dropping it at 1.
+fn set_gamma(settings: &mut Settings, gamma: f64) {
+    let steps = &mut settings.preprocess;
+    let at = steps.iter().position(|op| matches!(op, Op::Gamma { .. }));
+    match at {
+        Some(i) if gamma == 1.0 => {
+            steps.remove(i);
+        }
+        Some(i) => steps[i] = Op::Gamma { gamma },
+        None if gamma != 1.0 => steps.insert(0, Op::Gamma { gamma }),
+        None => {}
+    }
+}
+
+/// Next (or previous) entry of `all` after `current`, wrapping around.
+fn cycle<T: Copy + PartialEq>(all: &[T], current: T, dir: i32) -> T {
+    let i = all.iter().position(|v| *v == current).unwrap_or(0) as i32;
+    all[(i + dir).rem_euclid(all.len() as i32) as usize]
+}
+
+/// An image as ▀ cells: top pixel in the foreground color, bottom one in the background.
+struct HalfBlocks<'a>(&'a RgbaImage);
+
+impl Widget for HalfBlocks<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let image = self.0;
+        let rgb = |x: u32, y: u32| {
+            let p = image.get_pixel(x, y);
+            Color::Rgb(p[0], p[1], p[2])
+        };
+        for row in 0..area.height.min(image.height().div_ceil(2) as u16) {
+            for col in 0..area.width.min(image.width() as u16) {
+                let (x, y) = (col as u32, row as u32 * 2);
+                let bottom = if y + 1 < image.height() { rgb(x, y + 1) } else { Color::Reset };
+                if let Some(cell) = buf.cell_mut((area.x + col, area.y + row)) {
+                    cell.set_char('▀').set_style(Style::new().fg(rgb(x, y)).bg(bottom));
+                }
+            }
+        }
+    }
+}